@@ -0,0 +1,35 @@
+use std::net::{Ipv4Addr, SocketAddr};
+
+use chrono::Duration;
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+
+use udptun::IdAlloc;
+use udptun::cache::Cache;
+
+const CONNECTIONS: u16 = 10_000;
+
+fn addr(i: u16) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, i))
+}
+
+// Opens 10k connections and closes them again, every other one first, so the
+// freed ids come back out of order.
+fn insert_remove(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ids");
+    group.throughput(Throughput::Elements(u64::from(CONNECTIONS)));
+    for &(name, alloc) in &[("lowest", IdAlloc::Lowest), ("round-robin", IdAlloc::RoundRobin), ("random", IdAlloc::Random), ("shuffled", IdAlloc::Shuffled)] {
+        let mut cache = Cache::new(Duration::seconds(60), alloc, None, SmallRng::seed_from_u64(0));
+        group.bench_function(BenchmarkId::new("insert_remove", name), |b| b.iter(|| {
+            let ids: Vec<u16> = (0..CONNECTIONS).map(|i| cache.insert(None, 0, addr(i)).unwrap().0.id.id).collect();
+            for &id in ids.iter().step_by(2).chain(ids.iter().skip(1).step_by(2)) {
+                cache.remove(id);
+            }
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, insert_remove);
+criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,31 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+
+use udptun::common::FairOrder;
+
+const CONNECTIONS: u16 = 500;
+
+// What picking the next socket costs per packet with 500 connections, with the
+// order kept across packets as the server does until the cache changes, and
+// rebuilt for every packet for comparison.
+fn next_socket(c: &mut Criterion) {
+    let slots = || (0..CONNECTIONS).map(|k| (k, 1));
+    let mut group = c.benchmark_group("poll_order");
+    let mut order = FairOrder::default();
+    order.set(slots());
+    group.bench_function("reused", |b| b.iter(|| {
+        order.sort();
+        let next = order.keys().next().unwrap();
+        order.serviced(next);
+    }));
+    let mut order = FairOrder::default();
+    group.bench_function("rebuilt", |b| b.iter(|| {
+        order.set(slots());
+        order.sort();
+        let next = order.keys().next().unwrap();
+        order.serviced(next);
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, next_socket);
+criterion_main!(benches);
\ No newline at end of file
@@ -0,0 +1,172 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main, Throughput};
+use tokio::sync::oneshot;
+
+use udptun::{HashKey, IpMode, ServerParams, TargetSelect};
+use udptun::proto::*;
+use udptun::sockopt::SocketOptions;
+use udptun::sourcefmt::SourceAlloc;
+use udptun::stats::Stats;
+
+const PACKETS: usize = 256;
+// packets on the way at once, shared by all connections, so the peer's receive
+// buffer doesn't overflow and the numbers aren't skewed by loss
+const IN_FLIGHT: usize = 64;
+const PAYLOAD: [u8; 512] = [0x5a; 512];
+
+// A server end with the other end of the tunnel played by `peer` and every
+// connection going to `target`.
+struct Setup {
+    peer: UdpSocket,
+    target: UdpSocket,
+    // the server's socket for each connection, indexed by connection ID
+    conn_addrs: Vec<SocketAddr>,
+    stop: Option<oneshot::Sender<()>>,
+    server: Option<thread::JoinHandle<()>>,
+}
+
+impl Setup {
+    fn new(connections: u16, task_per_connection: bool, batch: usize) -> Self {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        target.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let (peer_addr, target_addr) = (peer.local_addr().unwrap(), target.local_addr().unwrap());
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = thread::spawn(move || {
+            let mut rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async move {
+                let params = ServerParams {
+                    targets: vec![target_addr],
+                    remote: Some(peer_addr),
+                    bufsize: 2048,
+                    timeout: chrono::Duration::seconds(60),
+                    tunnel_addr: None::<SocketAddr>,
+                    source_format: None,
+                    source_alloc: SourceAlloc::Random,
+                    hash_key: HashKey::Connection,
+                    target_select: TargetSelect::Hash,
+                    mode: IpMode::V4Only,
+                    format: None,
+                    print_data_buffer: false,
+                    middleware: None,
+                    filter: None,
+                    stats: Arc::new(Stats::default()),
+                    events: None,
+                    control: None,
+                    drain_timeout: Duration::from_secs(1),
+                    cleanup_interval: Duration::from_secs(60),
+                    reload: None,
+                    jitter: false,
+                    max_conns_per_source: None,
+                    verbosity: 0,
+                    target_batch: None,
+                    log_connections: false,
+                    log_expiry: false,
+                    socket_options: SocketOptions::default(),
+                    listen_interface: None,
+                    grow_buffer: None,
+                    strict_mtu: false,
+                    bind_retries: 0,
+                    tunnel_weight: 1,
+                    batch,
+                    rng_seed: None,
+                    psk: None,
+                    keepalive: None,
+                    connect_retries: 0,
+                    connect_timeout: Duration::from_secs(1),
+                    coalesce: false,
+                    task_per_connection,
+                };
+                udptun::start_server_until(params, async { let _ = stopped.await; }).await.unwrap();
+            });
+        });
+
+        let mut buf = [0; 2048];
+        let (_, server_addr) = peer.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[0], PACKET_CONNECT);
+        peer.send_to(&[PACKET_CONN_ACK, TYPE_CLIENT, PROTO_VERSION, 0], server_addr).unwrap();
+        peer.connect(server_addr).unwrap();
+        // the first packet of every connection tells which socket the server
+        // opened for it
+        let mut conn_addrs = Vec::new();
+        for cid in 0..connections {
+            let [hi, lo] = cid.to_be_bytes();
+            peer.send(&[PACKET_DATA, hi, lo, 0]).unwrap();
+            conn_addrs.push(target.recv_from(&mut buf).unwrap().1);
+        }
+        Setup { peer, target, conn_addrs, stop: Some(stop), server: Some(server) }
+    }
+
+    // Sends `PACKETS` from the target on every connection and waits until all
+    // of them came out of the tunnel.
+    fn forward(&self) {
+        let window = (IN_FLIGHT / self.conn_addrs.len()).max(1);
+        let mut credits = Vec::new();
+        let senders: Vec<_> = self.conn_addrs.iter().map(|&addr| {
+            let (credit, wait) = mpsc::channel();
+            credits.push(credit);
+            let target = self.target.try_clone().unwrap();
+            thread::spawn(move || {
+                for i in 0..PACKETS {
+                    if i >= window {
+                        wait.recv().unwrap();
+                    }
+                    target.send_to(&PAYLOAD, addr).unwrap();
+                }
+            })
+        }).collect();
+        let mut buf = [0; 2048];
+        for received in 0..PACKETS * self.conn_addrs.len() {
+            let len = self.peer.recv(&mut buf).unwrap_or_else(|e| panic!("only {} packets came out of the tunnel: {}", received, e));
+            assert!(len > DATA_HEADER_LEN && buf[0] == PACKET_DATA);
+            // the sender may be done already
+            let _ = credits[u16::from_be_bytes([buf[1], buf[2]]) as usize].send(());
+        }
+        for sender in senders {
+            sender.join().unwrap();
+        }
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        let _ = self.stop.take().unwrap().send(());
+        self.server.take().unwrap().join().unwrap();
+    }
+}
+
+fn task_per_connection(c: &mut Criterion) {
+    udptun::log::init(0, true);
+    let mut group = c.benchmark_group("into_tunnel");
+    for &connections in &[1, 4, 16] {
+        group.throughput(Throughput::Elements(PACKETS as u64 * u64::from(connections)));
+        for &tasks in &[false, true] {
+            let setup = Setup::new(connections, tasks, 1);
+            let name = if tasks { "task_per_connection" } else { "main_loop" };
+            group.bench_with_input(BenchmarkId::new(name, connections), &setup, |b, setup| b.iter(|| setup.forward()));
+        }
+    }
+    group.finish();
+}
+
+// --batch reads whatever is waiting on a socket with one syscall
+fn batch(c: &mut Criterion) {
+    udptun::log::init(0, true);
+    let mut group = c.benchmark_group("batch");
+    let connections = 4;
+    group.throughput(Throughput::Elements(PACKETS as u64 * u64::from(connections)));
+    for &batch in &[1, 8, 32] {
+        let setup = Setup::new(connections, false, batch);
+        group.bench_with_input(BenchmarkId::from_parameter(batch), &setup, |b, setup| b.iter(|| setup.forward()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, task_per_connection, batch);
+criterion_main!(benches);
\ No newline at end of file
@@ -1,96 +1,393 @@
 use std::cell::{Cell, RefCell};
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::{fs, io};
 use std::net::SocketAddr;
-use std::rc::Rc;
+use std::path::Path;
+use std::str::FromStr;
 
 use chrono::{DateTime, Duration, Local};
-use num_traits::cast::ToPrimitive;
+use rand::Rng;
+use rand::prelude::{IteratorRandom, SliceRandom};
+use rand::rngs::SmallRng;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::stats::{ConnectionInfo, ConnStats, Jitter, Rate};
+
 pub struct Cache {
     timeout: Duration,
-    ids: Vec<u8>,
-    by_id: HashMap<u8, Rc<CacheEntry>>,
-    by_addr: HashMap<SocketAddr, Rc<CacheEntry>>,
+    alloc: IdAlloc,
+    next_id: u16,
+    pool: VecDeque<u16>,
+    ids: IdSet,
+    // for IdAlloc::Lowest: every free id below the watermark, lowest on top,
+    // possibly with some that were taken again since
+    freed: BinaryHeap<Reverse<u16>>,
+    watermark: usize,
+    by_id: HashMap<u16, CacheEntryOuter>,
+    // a client is identified by the entry socket it talks to and its address
+    by_addr: HashMap<(usize, SocketAddr), u16>,
     expired: RefCell<HashSet<SocketId>>,
+    // connections beyond this evict the least recently used one
+    max: Option<usize>,
+    rng: SmallRng,
 }
 
-struct CacheEntry {
+const ID_SPACE: usize = 1 << 16;
+
+struct CacheEntryOuter {
+    created: DateTime<Local>,
     last_access: Cell<DateTime<Local>>,
-    data: SocketId,
+    data: CacheEntry,
+}
+
+impl CacheEntryOuter {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            cid: self.data.id.id,
+            peer: self.data.id.addr,
+            local: None,
+            target: None,
+            created: self.created,
+            last_access: self.last_access.get(),
+            stats: self.data.stats,
+            jitter: self.data.jitter.map(|j| j.millis()),
+        }
+    }
+}
+
+pub struct CacheEntry {
+    pub id: SocketId,
+    pub stats: ConnStats,
+    pub jitter: Option<Jitter>,
+    pub rate: Rate,
 }
 
-#[derive(Copy, Clone, Hash, Eq, PartialEq)]
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SocketId {
-    pub id: u8,
+    pub id: u16,
+    // index of the entry socket the client sends to
+    pub entry: usize,
     pub addr: SocketAddr,
 }
 
 impl Cache {
-    pub fn new(timeout: Duration) -> Self {
+    pub fn new(timeout: Duration, alloc: IdAlloc, max: Option<usize>, mut rng: SmallRng) -> Self {
         Cache {
             timeout,
-            ids: Vec::new(),
+            alloc,
+            next_id: 0,
+            pool: if alloc == IdAlloc::Shuffled { shuffled_ids(&mut rng) } else { VecDeque::new() },
+            ids: IdSet::new(),
+            freed: BinaryHeap::new(),
+            watermark: 0,
             by_id: Default::default(),
             by_addr: Default::default(),
             expired: Default::default(),
+            max,
+            rng,
         }
     }
 
-    pub fn insert(&mut self, id: Option<u8>, addr: SocketAddr) -> Result<SocketId, Error> {
+    // Starts out with the connections of an earlier run, which count as just
+    // used. Entries beyond the maximum and duplicate ids or addresses from a
+    // hand edited file are left out.
+    pub fn from_snapshot(timeout: Duration, alloc: IdAlloc, max: Option<usize>, rng: SmallRng, snapshot: CacheSnapshot) -> Self {
+        let mut cache = Cache::new(timeout, alloc, max, rng);
+        let cap = max.map_or(ID_SPACE, |max| max.min(ID_SPACE));
+        for id in snapshot.connections {
+            if cache.ids.len() >= cap {
+                break;
+            }
+            if cache.by_id.contains_key(&id.id) || cache.by_addr.contains_key(&(id.entry, id.addr)) {
+                continue;
+            }
+            let _ = cache.insert(Some(id.id), id.entry, id.addr);
+        }
+        cache
+    }
+
+    // The connections that haven't timed out, for restoring with from_snapshot
+    pub fn to_snapshot(&self) -> CacheSnapshot {
+        let now = Local::now();
+        let expired = self.expired.borrow();
+        let mut connections: Vec<_> = self.by_id.values()
+            .filter(|e| now.signed_duration_since(e.last_access.get()) <= self.timeout && !expired.contains(&e.data.id))
+            .map(|e| e.data.id)
+            .collect();
+        connections.sort_by_key(|c| c.id);
+        CacheSnapshot { connections }
+    }
+
+    // When the cache is full, the least recently used connection makes room
+    // and is returned alongside the new entry.
+    pub fn insert(&mut self, id: Option<u16>, entry: usize, addr: SocketAddr) -> Result<(&mut CacheEntry, Option<ConnectionInfo>), Error> {
         self.cleanup();
         let now = Local::now();
+        let cap = self.max.map_or(ID_SPACE, |max| max.min(ID_SPACE));
+        let evicted = if id.is_none() && self.ids.len() >= cap { self.evict_lru() } else { None };
         let id = id.or_else(|| self.get_next_free_id()).ok_or(Error::NoFreeSlots)?;
-        if let Err(pos) = self.ids.binary_search(&id) {
-            self.ids.insert(pos, id)
+        self.ids.insert(id);
+        let data = CacheEntry { id: SocketId { id, entry, addr }, stats: Default::default(), jitter: None, rate: Rate::default() };
+        let outer = CacheEntryOuter { created: now, last_access: Cell::new(now), data };
+        if let Some(old) = self.by_id.insert(id, outer) {
+            if (old.data.id.entry, old.data.id.addr) != (entry, addr) {
+                self.by_addr.remove(&(old.data.id.entry, old.data.id.addr));
+            }
         }
-        let data = SocketId { id, addr };
-        let entry = Rc::new(CacheEntry { last_access: Cell::new(now), data });
-        self.by_addr.insert(data.addr, entry.clone());
-        self.by_id.insert(data.id, entry);
-        Ok(data)
+        self.by_addr.insert((entry, addr), id);
+        Ok((&mut self.by_id.get_mut(&id).unwrap().data, evicted))
     }
 
-    pub fn get_or_insert_by_addr(&mut self, addr: SocketAddr) -> Result<SocketId, Error> {
-        match self.get_by_addr(addr) {
-            None => self.insert(None, addr),
-            Some(r) => Ok(r),
-        }
+    pub fn evict_lru(&mut self) -> Option<ConnectionInfo> {
+        let id = self.by_id.values().min_by_key(|e| e.last_access.get())?.data.id.id;
+        self.remove(id)
     }
 
-    pub fn get_by_id(&self, id: u8) -> Option<SocketId> {
-        self.prepare_entry(self.by_id.get(&id)?)
+    pub fn get_by_id_mut(&mut self, id: u16) -> Option<&mut CacheEntry> {
+        Cache::prepare_entry_mut(self.by_id.get_mut(&id)?, self.timeout, &self.expired)
     }
 
-    pub fn get_by_addr(&self, addr: SocketAddr) -> Option<SocketId> {
-        self.prepare_entry(self.by_addr.get(&addr)?)
+    pub fn get_by_addr_mut(&mut self, entry: usize, addr: SocketAddr) -> Option<&mut CacheEntry> {
+        let id = *self.by_addr.get(&(entry, addr))?;
+        self.get_by_id_mut(id)
     }
 
-    fn prepare_entry(&self, e: &Rc<CacheEntry>) -> Option<SocketId> {
+    fn prepare_entry_mut<'a>(e: &'a mut CacheEntryOuter, timeout: Duration, expired: &RefCell<HashSet<SocketId>>) -> Option<&'a mut CacheEntry> {
         let now = Local::now();
-        if now.signed_duration_since(e.last_access.get()) > self.timeout {
-            self.expired.borrow_mut().insert(e.data);
+        if now.signed_duration_since(e.last_access.get()) > timeout {
+            expired.borrow_mut().insert(e.data.id);
             return None;
         }
         e.last_access.set(now);
-        Some(e.data)
+        Some(&mut e.data)
+    }
+
+    // Moves a connection to a new client address on the same entry socket and
+    // keeps its id, for clients that roamed to another network. An address
+    // that already belongs to another connection is never taken over, that
+    // one has to be removed first. Returns the old address.
+    pub fn update_addr(&mut self, id: u16, addr: SocketAddr) -> Result<SocketAddr, Error> {
+        let e = self.by_id.get_mut(&id).ok_or(Error::NoConnection(id))?;
+        let old = e.data.id;
+        match self.by_addr.get(&(old.entry, addr)) {
+            Some(&other) if other != id => return Err(Error::AddrInUse(other)),
+            _ => {}
+        }
+        e.data.id.addr = addr;
+        self.by_addr.remove(&(old.entry, old.addr));
+        self.by_addr.insert((old.entry, addr), id);
+        let expired = self.expired.get_mut();
+        if expired.remove(&old) {
+            expired.insert(e.data.id);
+        }
+        Ok(old.addr)
+    }
+
+    pub fn remove(&mut self, id: u16) -> Option<ConnectionInfo> {
+        let entry = self.by_id.remove(&id)?;
+        self.by_addr.remove(&(entry.data.id.entry, entry.data.id.addr));
+        // or a later cleanup would remove whoever gets the id next
+        self.expired.get_mut().remove(&entry.data.id);
+        self.free_id(id);
+        Some(entry.info())
     }
 
-    fn get_next_free_id(&self) -> Option<u8> {
-        self.ids.iter()
-            .enumerate()
-            .find_map(|(exp, &v)| if v != exp as u8 { Some(exp as u8) } else { None })
-            .or_else(|| self.ids.len().to_u8())
+    pub fn active_count(&self) -> usize {
+        let now = Local::now();
+        self.by_id.values()
+            .filter(|e| now.signed_duration_since(e.last_access.get()) <= self.timeout)
+            .count()
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnectionInfo> {
+        let now = Local::now();
+        self.by_id.values()
+            .filter(|e| now.signed_duration_since(e.last_access.get()) <= self.timeout)
+            .map(CacheEntryOuter::info)
+            .collect()
     }
 
-    pub fn cleanup(&mut self) {
-        let vec = self.expired.get_mut();
-        for x in vec.drain() {
-            if let Ok(pos) = self.ids.binary_search(&x.id) {
-                self.ids.remove(pos);
+    fn get_next_free_id(&mut self) -> Option<u16> {
+        match self.alloc {
+            IdAlloc::Lowest => {
+                while let Some(Reverse(id)) = self.freed.pop() {
+                    if !self.ids.contains(id) {
+                        return Some(id);
+                    }
+                }
+                while self.watermark < ID_SPACE {
+                    let id = self.watermark as u16;
+                    self.watermark += 1;
+                    if !self.ids.contains(id) {
+                        return Some(id);
+                    }
+                }
+                None
+            }
+            IdAlloc::RoundRobin => {
+                let ids = &self.ids;
+                let id = (0..=u16::MAX)
+                    .map(|off| self.next_id.wrapping_add(off))
+                    .find(|&id| !ids.contains(id))?;
+                self.next_id = id.wrapping_add(1);
+                Some(id)
+            }
+            // guessing takes a few tries at most while the space is mostly
+            // free, only a nearly full one has to be searched
+            IdAlloc::Random if self.ids.len() < ID_SPACE / 16 * 15 => loop {
+                let id = self.rng.gen::<u16>();
+                if !self.ids.contains(id) {
+                    return Some(id);
+                }
+            },
+            IdAlloc::Random => {
+                let ids = &self.ids;
+                (0..=u16::MAX)
+                    .filter(|&id| !ids.contains(id))
+                    .choose(&mut self.rng)
+            }
+            IdAlloc::Shuffled => {
+                while let Some(id) = self.pool.pop_front() {
+                    if !self.ids.contains(id) {
+                        return Some(id);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    fn free_id(&mut self, id: u16) {
+        if self.ids.remove(id) {
+            match self.alloc {
+                IdAlloc::Shuffled => self.pool.push_back(id),
+                IdAlloc::Lowest if (id as usize) < self.watermark => self.freed.push(Reverse(id)),
+                _ => {}
             }
-            self.by_id.remove(&x.id);
-            self.by_addr.remove(&x.addr);
+        }
+    }
+
+    // marks every connection that timed out, so the next cleanup removes it
+    // even if nothing touched it since
+    pub fn expire_idle(&mut self) {
+        let now = Local::now();
+        let timeout = self.timeout;
+        let expired = self.expired.get_mut();
+        expired.extend(self.by_id.values()
+            .filter(|e| now.signed_duration_since(e.last_access.get()) > timeout)
+            .map(|e| e.data.id));
+    }
+
+    // connections waiting to be removed by the next cleanup
+    pub fn expired_count(&self) -> usize {
+        self.expired.borrow().len()
+    }
+
+    pub fn cleanup(&mut self) -> Vec<ConnectionInfo> {
+        let expired: Vec<_> = self.expired.get_mut().drain().collect();
+        let mut removed = Vec::new();
+        for x in expired {
+            self.free_id(x.id);
+            let entry = self.by_id.remove(&x.id);
+            if self.by_addr.remove(&(x.entry, x.addr)).is_some() {
+                removed.extend(entry.map(|e| e.info()));
+            }
+        }
+        removed
+    }
+}
+
+// What --state-file keeps across restarts: which client had which id, not
+// the sockets or counters.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    pub connections: Vec<SocketId>,
+}
+
+// None if there is no state file yet
+pub fn load_state(path: &Path) -> io::Result<Option<CacheSnapshot>> {
+    match fs::read_to_string(path) {
+        Ok(s) => serde_json::from_str(&s).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// goes through a temporary file so a crash while writing keeps the old state
+pub fn save_state(path: &Path, snapshot: &CacheSnapshot) -> io::Result<()> {
+    let s = serde_json::to_string(snapshot).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, s)?;
+    fs::rename(&tmp, path)
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IdAlloc {
+    Lowest,
+    // don't hand out a just freed id again right away
+    RoundRobin,
+    Random,
+    // walks a random permutation of the id space so ids look random on the
+    // wire while staying unique; this is obfuscation, not encryption
+    Shuffled,
+}
+
+// One bit per id, so taking and freeing one is constant time
+struct IdSet {
+    bits: Vec<u64>,
+    len: usize,
+}
+
+impl IdSet {
+    fn new() -> Self {
+        IdSet { bits: vec![0; ID_SPACE / 64], len: 0 }
+    }
+
+    fn contains(&self, id: u16) -> bool {
+        self.bits[id as usize / 64] & (1 << (id % 64)) != 0
+    }
+
+    fn insert(&mut self, id: u16) {
+        if !self.contains(id) {
+            self.bits[id as usize / 64] |= 1 << (id % 64);
+            self.len += 1;
+        }
+    }
+
+    // whether it was there
+    fn remove(&mut self, id: u16) -> bool {
+        let present = self.contains(id);
+        if present {
+            self.bits[id as usize / 64] &= !(1 << (id % 64));
+            self.len -= 1;
+        }
+        present
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+fn shuffled_ids(rng: &mut SmallRng) -> VecDeque<u16> {
+    let mut ids: Vec<u16> = (0..=u16::MAX).collect();
+    ids.shuffle(rng);
+    ids.into()
+}
+
+impl FromStr for IdAlloc {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowest" => Ok(IdAlloc::Lowest),
+            "round-robin" => Ok(IdAlloc::RoundRobin),
+            "random" => Ok(IdAlloc::Random),
+            "shuffled" => Ok(IdAlloc::Shuffled),
+            _ => Err(()),
         }
     }
 }
@@ -98,5 +395,126 @@ impl Cache {
 #[derive(Error, Debug, Copy, Clone)]
 pub enum Error {
     #[error("no free ID slots available")]
-    NoFreeSlots
+    NoFreeSlots,
+    #[error("no connection {0}")]
+    NoConnection(u16),
+    #[error("address is in use by connection {0}")]
+    AddrInUse(u16),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::seeded_rng;
+
+    use super::*;
+
+    fn cache(alloc: IdAlloc, max: Option<usize>) -> Cache {
+        Cache::new(Duration::seconds(60), alloc, max, seeded_rng(Some(0)))
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn insert(cache: &mut Cache, port: u16) -> u16 {
+        cache.insert(None, 0, addr(port)).unwrap().0.id.id
+    }
+
+    #[test]
+    fn close_frees_id() {
+        let mut cache = cache(IdAlloc::Lowest, None);
+        let id = insert(&mut cache, 1000);
+        let info = cache.remove(id).unwrap();
+        assert_eq!((info.cid, info.peer), (id, addr(1000)));
+        assert!(cache.get_by_addr_mut(0, addr(1000)).is_none());
+        assert_eq!(insert(&mut cache, 1001), id);
+        assert_eq!(cache.get_by_addr_mut(0, addr(1001)).unwrap().id.id, id);
+    }
+
+    #[test]
+    fn close_unknown_id() {
+        let mut cache = cache(IdAlloc::Lowest, None);
+        insert(&mut cache, 1000);
+        assert!(cache.remove(1).is_none());
+        assert_eq!(cache.active_count(), 1);
+    }
+
+    #[test]
+    fn full_cache_evicts_lru() {
+        let mut cache = cache(IdAlloc::Lowest, Some(3));
+        let ids: Vec<_> = (1..=3).map(|port| insert(&mut cache, port)).collect();
+        // inserted within the same millisecond otherwise
+        cache.by_id[&ids[1]].last_access.set(Local::now() - Duration::seconds(10));
+        let (entry, evicted) = cache.insert(None, 0, addr(4)).unwrap();
+        assert_eq!(entry.id.id, ids[1]);
+        let evicted = evicted.unwrap();
+        assert_eq!((evicted.cid, evicted.peer), (ids[1], addr(2)));
+        assert!(cache.get_by_addr_mut(0, addr(2)).is_none());
+        assert_eq!(cache.active_count(), 3);
+    }
+
+    #[test]
+    fn cache_below_max_evicts_nothing() {
+        let mut cache = cache(IdAlloc::Lowest, Some(3));
+        insert(&mut cache, 1);
+        assert!(cache.insert(None, 0, addr(2)).unwrap().1.is_none());
+    }
+
+    fn insert_all(cache: &mut Cache, ports: std::ops::Range<u16>) -> Vec<u16> {
+        ports.map(|port| insert(cache, port)).collect()
+    }
+
+    #[test]
+    fn lowest_takes_freed_id_first() {
+        let mut cache = cache(IdAlloc::Lowest, None);
+        assert_eq!(insert_all(&mut cache, 0..3), [0, 1, 2]);
+        cache.remove(1);
+        assert_eq!(insert_all(&mut cache, 3..5), [1, 3]);
+    }
+
+    #[test]
+    fn round_robin_skips_freed_id() {
+        let mut cache = cache(IdAlloc::RoundRobin, None);
+        assert_eq!(insert_all(&mut cache, 0..3), [0, 1, 2]);
+        cache.remove(1);
+        assert_eq!(insert_all(&mut cache, 3..5), [3, 4]);
+    }
+
+    #[test]
+    fn round_robin_wraps() {
+        let mut cache = cache(IdAlloc::RoundRobin, None);
+        cache.next_id = u16::MAX;
+        let first = insert(&mut cache, 0);
+        assert_eq!((first, insert(&mut cache, 1)), (u16::MAX, 0));
+    }
+
+    #[test]
+    fn random_ids_unique() {
+        for &alloc in &[IdAlloc::Random, IdAlloc::Shuffled] {
+            let mut cache = cache(alloc, None);
+            let ids = insert_all(&mut cache, 0..1000);
+            assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 1000);
+            // not just counting up
+            assert_ne!(ids[..3], [0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn random_ids_reproducible() {
+        for &alloc in &[IdAlloc::Random, IdAlloc::Shuffled] {
+            assert_eq!(insert_all(&mut cache(alloc, None), 0..10), insert_all(&mut cache(alloc, None), 0..10));
+        }
+    }
+
+    // the last free ID is found, also by guessing ones
+    #[test]
+    fn every_id_used() {
+        for &alloc in &[IdAlloc::Lowest, IdAlloc::RoundRobin, IdAlloc::Random, IdAlloc::Shuffled] {
+            let mut cache = cache(alloc, None);
+            let mut ids = insert_all(&mut cache, 0..u16::MAX);
+            ids.push(cache.insert(None, 1, addr(0)).unwrap().0.id.id);
+            ids.sort_unstable();
+            assert!(ids.iter().copied().eq(0..=u16::MAX), "{:?}", alloc);
+        }
+    }
 }
\ No newline at end of file
@@ -1,147 +1,714 @@
 use std::{fmt, io};
 use std::borrow::Cow;
 use std::fmt::{Display, Formatter};
+use std::future::Future;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
-use chrono::Duration;
-use rand::prelude::{SliceRandom, ThreadRng};
+use chrono::{DateTime, Duration, Local, SecondsFormat};
 use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc::Sender;
+use tokio::time;
 
-use crate::{common, output};
-use crate::cache::{Cache, SocketId};
-use crate::common::{Format, IpMode, respond_connect, setup_tunnel_socket};
-use crate::output::Alignment;
+use crate::{common, control, filter, middleware, output, sockopt, stats};
+use crate::cache::{Cache, IdAlloc, SocketId};
+use crate::cache;
+use crate::config::Reload;
+use crate::common::{Batch, Coalesce, DataDirection, Drain, FairOrder, Handshake, Health, HealthCheck, Keepalive, Format, IpMode, log_closed, log_expired, log_opened, read_cid, Reconnect, reject, respond_connect, setup_tunnel_socket};
+use crate::control::{Command, ControlAddr};
+use crate::event::{CloseReason, EventSink, TunnelEvent};
+use crate::filter::{Filter, Packet};
+use crate::middleware::Middleware;
+use crate::output::{Alignment, Color, DataLog, DataRecord};
 use crate::proto::*;
+use crate::signal::SignalListener;
+use crate::sockopt::{ListenInterface, SocketOptions};
+use crate::stats::{Jitter, Rejection, Stats};
+
+const DEFAULT_FORMAT: &str = "[tunnel %D] client: %C cid: %i dbuf: %l";
 
 pub struct ClientParams<'a, T, U, V>
     where T: ToSocketAddrs,
           U: ToSocketAddrs,
           V: ToSocketAddrs {
-    pub entry: T,
+    // ids come from one pool shared by all entry sockets, each connection
+    // remembers which socket its client talks to
+    pub entry: Vec<T>,
     pub remote: Option<U>,
     pub timeout: Duration,
     pub bufsize: usize,
+    pub id_alloc: IdAlloc,
     pub tunnel_addr: Option<V>,
     pub mode: IpMode,
     pub format: Option<Format<'a>>,
     pub print_data_buffer: bool,
+    pub middleware: Option<Box<dyn Middleware>>,
+    pub filter: Option<Box<dyn Filter>>,
+    pub stats: Arc<Stats>,
+    pub events: Option<Sender<TunnelEvent>>,
+    pub control: Option<ControlAddr>,
+    pub drain_timeout: time::Duration,
+    pub max_connections: Option<usize>,
+    // how often timed out connections are looked for and removed
+    pub cleanup_interval: time::Duration,
+    pub reload: Option<Reload<'a>>,
+    pub jitter: bool,
+    pub verbosity: u64,
+    pub log_connections: bool,
+    // a line for every connection removed for being idle
+    pub log_expiry: bool,
+    pub socket_options: SocketOptions,
+    // only for the tunnel socket, unlike socket_options.device
+    pub listen_interface: Option<ListenInterface>,
+    pub grow_buffer: Option<usize>,
+    // drop packets that filled the whole buffer instead of forwarding them
+    pub strict_mtu: bool,
+    pub tunnel_weight: u32,
+    // datagrams read per syscall once a socket is readable
+    pub batch: usize,
+    // for reproducible connection IDs
+    pub rng_seed: Option<u64>,
+    // connection ids are kept here across restarts
+    pub state_file: Option<PathBuf>,
+    pub psk: Option<Vec<u8>>,
+    pub keepalive: Option<time::Duration>,
+    // resends of an unanswered connect, each waiting twice as long
+    pub connect_retries: u32,
+    pub connect_timeout: time::Duration,
+    // pack data packets that are read together into one tunnel datagram
+    pub coalesce: bool,
+    // tried in order after `remote` once it stops answering; only when this
+    // end connects
+    pub fallback_remotes: Vec<U>,
+    pub failover_timeout: Option<time::Duration>,
+}
+
+impl<T, U, V> ClientParams<'_, T, U, V>
+    where T: ToSocketAddrs,
+          U: ToSocketAddrs,
+          V: ToSocketAddrs {
+    // Sets up everything start_client would short of talking to the remote and
+    // returns the first problem found.
+    pub async fn validate(&self) -> Result<(), common::Error> {
+        self.format.as_ref().map(|f| DataLog::<OutputColumn>::new(f, DEFAULT_FORMAT)).transpose().map_err(common::Error::DataFormat)?;
+        // held until the end, so two entries on the same port are caught too
+        let mut external_sockets = Vec::with_capacity(self.entry.len());
+        for entry in &self.entry {
+            external_sockets.push(sockopt::bind(entry, &self.socket_options).await.map_err(common::Error::EntryBind)?);
+        }
+        common::open_tunnel(self.tunnel_addr.as_ref(), self.remote.as_ref(), self.mode, &self.socket_options, self.listen_interface.as_ref(), self.psk.as_deref()).await?;
+        Ok(())
+    }
+}
+
+pub fn list_columns() -> String {
+    output::list_columns::<OutputColumn>()
+}
+
+// Runs until the tunnel is drained or SIGINT/SIGTERM arrives.
+pub async fn start_client<T, U, V>(params: ClientParams<'_, T, U, V>) -> Result<(), common::Error>
+    where T: ToSocketAddrs,
+          U: ToSocketAddrs,
+          V: ToSocketAddrs {
+    let mut signal = SignalListener::shutdown();
+    start_client_until(params, signal.recv()).await
 }
 
-pub async fn start_client<T, U, V>(params: ClientParams<'_, T, U, V>)
+// Like start_client, but stops when `shutdown` completes instead of on a signal.
+pub async fn start_client_until<T, U, V>(mut params: ClientParams<'_, T, U, V>, shutdown: impl Future<Output=()>) -> Result<(), common::Error>
     where T: ToSocketAddrs,
           U: ToSocketAddrs,
           V: ToSocketAddrs {
+    tokio::pin!(shutdown);
+    // before anything is set up, a typo shouldn't have to wait for the handshake
+    let mut data_output = params.format.map(|f| DataLog::<OutputColumn>::new(&f, DEFAULT_FORMAT)).transpose().map_err(common::Error::DataFormat)?;
     let mut buffer = vec![0; params.bufsize];
-    let mut external_socket = UdpSocket::bind(params.entry).await.expect("failed to open entry socket");
-    let mut tunnel_socket = setup_tunnel_socket(params.tunnel_addr, params.remote, params.mode, &mut buffer, TYPE_SERVER).await.expect("failed to setup tunnel");
-    let mut cache = Cache::new(params.timeout);
-    let data_output = params.format.map(|f| output::TableFormat::<OutputColumn>::parse_spec(f.with_default("[tunnel %D] client: %C cid: %i dbuf: %l")).expect("failed to parse data log format"));
+    let mut external_sockets = Vec::with_capacity(params.entry.len());
+    for entry in params.entry {
+        external_sockets.push(sockopt::bind(entry, &params.socket_options).await.map_err(common::Error::EntryBind)?);
+    }
+    let mut events = EventSink::new(params.events);
+    // only the end that sent the connect can send it again
+    let connecting = params.tunnel_addr.is_none();
+    let features = middleware::features(&params.middleware) | if params.coalesce { FEATURE_COALESCE } else { 0 };
+    let mut remotes: Vec<U> = params.remote.into_iter().collect();
+    remotes.append(&mut params.fallback_remotes);
+    // once up front, a failover later on runs in its own task and can't
+    // borrow them
+    let remotes = common::resolve_remotes(&remotes, params.mode).await?;
+    let handshake = Handshake {
+        mode: params.mode,
+        opts: params.socket_options.clone(),
+        interface: params.listen_interface.clone(),
+        remote_type: TYPE_SERVER,
+        features,
+        psk: params.psk.clone(),
+        retries: params.connect_retries,
+        timeout: params.connect_timeout,
+    };
+    let (mut tunnel_socket, mut current_remote) = if remotes.len() > 1 {
+        common::setup_failover(&remotes, 0, &handshake, &mut buffer, &mut events).await?
+    } else {
+        (setup_tunnel_socket(params.tunnel_addr, remotes.first(), &handshake, &mut buffer, &mut events).await?, 0)
+    };
+    let mut reconnect = Reconnect::default();
+    // only when this end connected, a listening end counts the connects it answers
+    if tunnel_socket.version.is_some() {
+        params.stats.handshake_completed();
+    }
+    if let Some(rtt) = tunnel_socket.rtt {
+        params.stats.set_rtt(rtt);
+    }
+    let mut own_addr = tunnel_socket.local_addr().ok();
+    let rng = common::seeded_rng(params.rng_seed);
+    let mut cache = match params.state_file.as_deref().map(cache::load_state) {
+        Some(Ok(Some(mut snapshot))) => {
+            // the entry sockets may have changed since
+            snapshot.connections.retain(|c| c.entry < external_sockets.len());
+            let cache = Cache::from_snapshot(params.timeout, params.id_alloc, params.max_connections, rng, snapshot);
+            info!("restored {} connections from the state file", cache.active_count());
+            params.stats.set_connections(cache.active_count());
+            cache
+        }
+        Some(Err(e)) => {
+            warn!("warning: ignoring the state file: {}", e);
+            Cache::new(params.timeout, params.id_alloc, params.max_connections, rng)
+        }
+        _ => Cache::new(params.timeout, params.id_alloc, params.max_connections, rng),
+    };
+
+    let mut control_rx = control::start(params.control).await.map_err(common::Error::ControlSocket)?;
+    let mut drain = Drain::new(params.drain_timeout);
+    let mut cleanup_timer = time::interval(params.cleanup_interval);
+    let mut keepalive = Keepalive::new(params.keepalive);
+    let mut health = Health::new(params.failover_timeout.filter(|_| remotes.len() > 1));
+    let mut drain_signal = SignalListener::drain();
+    let mut reload_signal = SignalListener::reload();
+    let mut poll_order = FairOrder::default();
+    let mut batched = Batch::new(params.batch);
+    let mut coalesce = Coalesce::new(params.coalesce, params.bufsize - DATA_HEADER_LEN);
+    let external = external_sockets.iter().enumerate().map(|(i, _)| (Direction::IntoTunnel(i), 1));
+    poll_order.set(std::iter::once((Direction::FromTunnel, params.tunnel_weight)).chain(external));
 
     loop {
-        match poll_sockets(&tunnel_socket, &external_socket, &mut buffer[2..]).await {
+        // whatever was held back for coalescing goes out before waiting
+        if batched.is_empty() {
+            if let Err(e) = coalesce.flush(&mut tunnel_socket).await {
+                error!("failed to send packet: {}", e);
+            }
+        }
+        let queued = batched.pop_into(&mut buffer[DATA_HEADER_LEN..]);
+        let from_batch = queued.is_some();
+        let packet = match queued {
+            Some(packet) => packet,
+            None => tokio::select! {
+                packet = poll_sockets(&tunnel_socket, &external_sockets, &mut poll_order, &mut buffer[DATA_HEADER_LEN..]) => packet,
+                Some(req) = control::recv(&mut control_rx) => {
+                    let reply = match req.command {
+                        Command::List => control::format_list(&cache.snapshot()),
+                        Command::Stats => control::format_stats(&params.stats),
+                        Command::Drain => {
+                            drain.start(&params.stats);
+                            format!("draining, {} connections left\n", cache.active_count())
+                        }
+                        Command::Kill(id) => {
+                            let removed = id.parse().ok().and_then(|id| cache.remove(id));
+                            params.stats.set_connections(cache.active_count());
+                            if let Some(closed) = &removed {
+                                events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Killed });
+                                if params.log_connections {
+                                    log_closed(closed, CloseReason::Killed);
+                                }
+                                // or it keeps sending on the connection, which
+                                // gets mixed up with the next one using the ID
+                                let close = common::close_packet(&mut params.middleware, closed.cid, params.bufsize);
+                                if let Err(e) = common::send_close(&mut tunnel_socket, close).await {
+                                    error!("failed to send close: {}", e);
+                                }
+                            }
+                            control::format_kill(&id, removed)
+                        }
+                        // all connections share the entry sockets here
+                        Command::Weight(..) => "error: connection weights are only supported at the target end\n".to_string(),
+                        Command::Rebind(id, addr) => match (id.parse(), addr.parse()) {
+                            (Ok(cid), Ok(new)) => {
+                                let result = cache.update_addr(cid, new);
+                                if let Ok(old) = result {
+                                    info!("connection {} moved from {} to {}", cid, old, new);
+                                }
+                                control::format_rebind(&id, &addr, result)
+                            }
+                            _ => "error: expected rebind ID ADDRESS\n".to_string(),
+                        },
+                    };
+                    let _ = req.reply.send(reply);
+                    continue;
+                }
+                _ = drain_signal.recv() => {
+                    drain.start(&params.stats);
+                    info!("draining, {} connections left", cache.active_count());
+                    continue;
+                }
+                _ = reload_signal.recv() => {
+                    // only the log format applies to this end of the tunnel
+                    let config = match params.reload.as_ref().map(|r| r()) {
+                        Some(Ok(config)) => config,
+                        Some(Err(e)) => {
+                            warn!("failed to reload config, keeping the current one: {}", e);
+                            continue;
+                        }
+                        None => {
+                            warn!("no config file given, nothing to reload");
+                            continue;
+                        }
+                    };
+                    // a format from the config doesn't replace JSON output
+                    if let Some(f) = config.format.as_ref().filter(|_| matches!(data_output, Some(DataLog::Table(_)))) {
+                        match output::TableFormat::<OutputColumn>::parse_spec(f) {
+                            Ok(t) => data_output = Some(DataLog::Table(t)),
+                            Err(e) => {
+                                warn!("failed to reload config, keeping the current one: failed to parse data log format: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    info!("reloaded config");
+                    continue;
+                }
+                _ = keepalive.due() => {
+                    // nobody to send to before the handshake
+                    if tunnel_socket.version.is_some() {
+                        if let Err(e) = tunnel_socket.send(&[PACKET_KEEPALIVE]).await {
+                            error!("failed to send keepalive: {}", e);
+                        }
+                    }
+                    keepalive.sent();
+                    continue;
+                }
+                check = health.check() => {
+                    match check {
+                        HealthCheck::Probe => {
+                            if let Err(e) = tunnel_socket.send(&[PACKET_KEEPALIVE, KEEPALIVE_PROBE]).await {
+                                error!("failed to send keepalive: {}", e);
+                            }
+                            health.probed();
+                            keepalive.sent();
+                        }
+                        HealthCheck::Lost => {
+                            if !reconnect.is_running() {
+                                warn!("remote {} of {} stopped answering, failing over", current_remote + 1, remotes.len());
+                                // the one that just failed comes last, it may be back by then
+                                let next = (current_remote + 1) % remotes.len();
+                                reconnect.start(remotes.clone(), next, &handshake, buffer.len(), events.clone());
+                            }
+                            // not lost again until the failover had its time
+                            health.heard();
+                        }
+                    }
+                    continue;
+                }
+                result = reconnect.done() => {
+                    match result {
+                        Ok((session, i)) => {
+                            tunnel_socket = session;
+                            own_addr = tunnel_socket.local_addr().ok();
+                            params.stats.handshake_completed();
+                            if let Some(rtt) = tunnel_socket.rtt {
+                                params.stats.set_rtt(rtt);
+                            }
+                            if i != current_remote {
+                                info!("failed over to remote {} of {}, {} connections may have to be established again", i + 1, remotes.len(), cache.active_count());
+                            }
+                            current_remote = i;
+                        }
+                        Err(e) => error!("no remote answered, trying again later: {}", e),
+                    }
+                    health.heard();
+                    continue;
+                }
+                timed_out = drain.tick() => {
+                    if timed_out || cache.active_count() == 0 {
+                        info!("drain finished, {} connections left, peak was {}", cache.active_count(), params.stats.peak_connections.load(Ordering::Relaxed));
+                        save_state(params.state_file.as_deref(), &cache);
+                        return Ok(());
+                    }
+                    continue;
+                }
+                _ = cleanup_timer.tick() => {
+                    cache.expire_idle();
+                    if cache.expired_count() > 0 {
+                        if params.verbosity >= 1 {
+                            debug!("cleaning up {} timed out connections", cache.expired_count());
+                        }
+                        for closed in cache.cleanup() {
+                            events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Timeout });
+                            if params.log_connections {
+                                log_closed(&closed, CloseReason::Timeout);
+                            }
+                            if params.log_expiry {
+                                log_expired(&closed);
+                            }
+                        }
+                        params.stats.set_connections(cache.active_count());
+                    }
+                    continue;
+                }
+                _ = &mut shutdown => {
+                    cache.cleanup();
+                    info!("shutting down, {} connections were active, peak was {}", cache.active_count(), params.stats.peak_connections.load(Ordering::Relaxed));
+                    save_state(params.state_file.as_deref(), &cache);
+                    return Ok(());
+                }
+            },
+        };
+        poll_order.serviced(packet.0);
+        if let (_, Ok((size, sender_addr))) = &packet {
+            if common::check_truncated(&mut buffer, DATA_HEADER_LEN, *size, *sender_addr, &mut params.grow_buffer, params.strict_mtu) {
+                params.stats.reject(Rejection::Truncated);
+                continue;
+            }
+        }
+        if !from_batch && packet.1.is_ok() {
+            let socket = match packet.0 {
+                Direction::FromTunnel => &*tunnel_socket,
+                Direction::IntoTunnel(i) => &external_sockets[i],
+            };
+            batched.fill(packet.0, socket, buffer.len() - DATA_HEADER_LEN);
+        }
+        match packet {
             (dir, Ok((size, sender_addr))) => {
                 match dir {
                     Direction::FromTunnel => {
-                        let buffer = &mut buffer[2..];
+                        let buffer = &mut buffer[DATA_HEADER_LEN..];
                         if size == 0 { continue; }
+                        if own_addr.is_some_and(|own| common::is_own_addr(own, sender_addr)) {
+                            reject(&params.stats, params.verbosity, sender_addr, Rejection::Loopback, "packet from the tunnel socket itself");
+                            continue;
+                        }
+                        health.heard();
                         match buffer[0] {
+                            PACKET_CONNECT if drain.is_draining() => {
+                                warn!("draining, ignoring connect from {}", sender_addr);
+                            }
                             PACKET_CONNECT => {
-                                respond_connect(&mut tunnel_socket, sender_addr, buffer, TYPE_CLIENT).await;
+                                match respond_connect(&mut tunnel_socket, sender_addr, buffer, size, TYPE_CLIENT, features, &mut events).await {
+                                    // not the peer's fault, keep serving and wait for it to retry
+                                    Err(common::Error::RemoteConnect(e)) | Err(common::Error::ConnectRespond(e)) => {
+                                        error!("failed to answer connect from {}: {}", sender_addr, e);
+                                    }
+                                    Err(e) => reject(&params.stats, params.verbosity, sender_addr, Rejection::from(&e), e),
+                                    Ok(()) => params.stats.handshake_completed(),
+                                }
+                            }
+                            // only there to keep the path open, it doesn't
+                            // refresh any connection: keepalives go out exactly
+                            // when the tunnel is idle, so if they refreshed
+                            // connections, two ends with --keepalive would keep
+                            // each other's connections from ever timing out
+                            PACKET_KEEPALIVE => {
+                                // the other end is watching whether this one is still there
+                                if size >= 2 && buffer[1] == KEEPALIVE_PROBE {
+                                    if let Err(e) = tunnel_socket.send(&[PACKET_KEEPALIVE]).await {
+                                        error!("failed to answer keepalive: {}", e);
+                                    }
+                                }
+                            }
+                            PACKET_COALESCED if tunnel_socket.features & FEATURE_COALESCE != 0 => {
+                                if !batched.split(Direction::FromTunnel, &buffer[1..size], sender_addr) {
+                                    reject(&params.stats, params.verbosity, sender_addr, Rejection::Malformed, "coalesced packet lengths don't add up");
+                                }
+                            }
+                            PACKET_CLOSE => {
+                                if size < DATA_HEADER_LEN {
+                                    reject(&params.stats, params.verbosity, sender_addr, Rejection::Malformed, "too small for close");
+                                    continue;
+                                }
+                                let id = read_cid(buffer);
+                                if let Err(e) = middleware::inbound(&mut params.middleware, id, &mut buffer[DATA_HEADER_LEN..], size - DATA_HEADER_LEN) {
+                                    reject(&params.stats, params.verbosity, sender_addr, Rejection::from(&e), format_args!("id {}: {}", id, e));
+                                    continue;
+                                }
+                                if let Some(closed) = cache.remove(id) {
+                                    params.stats.set_connections(cache.active_count());
+                                    events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Remote });
+                                    if params.log_connections {
+                                        log_closed(&closed, CloseReason::Remote);
+                                    }
+                                }
                             }
                             PACKET_DATA => {
-                                let id = buffer[1];
-                                let buffer = &mut buffer[2..size];
-                                if let Some(SocketId { addr, .. }) = cache.get_by_id(id) {
-                                    if let Some(data_table) = &data_output {
+                                if size < DATA_HEADER_LEN {
+                                    reject(&params.stats, params.verbosity, sender_addr, Rejection::Malformed, "too small for data");
+                                    continue;
+                                }
+                                let id = read_cid(buffer);
+                                let len = match middleware::inbound(&mut params.middleware, id, &mut buffer[DATA_HEADER_LEN..], size - DATA_HEADER_LEN) {
+                                    Ok(len) => len,
+                                    Err(e) => {
+                                        reject(&params.stats, params.verbosity, sender_addr, Rejection::from(&e), format_args!("id {}: {}", id, e));
+                                        continue;
+                                    }
+                                };
+                                let buffer = &mut buffer[DATA_HEADER_LEN..DATA_HEADER_LEN + len];
+                                if !filter::accepts(&params.filter, &Packet { direction: DataDirection::FromTunnel, cid: id, payload: buffer }) {
+                                    reject(&params.stats, params.verbosity, sender_addr, Rejection::Filtered, format_args!("id {}: payload did not pass the filters", id));
+                                    continue;
+                                }
+                                if let Some(entry) = cache.get_by_id_mut(id) {
+                                    entry.stats.record(DataDirection::FromTunnel, buffer.len());
+                                    params.stats.record(DataDirection::FromTunnel, buffer.len());
+                                    let now = Instant::now();
+                                    entry.rate.update(now, buffer.len());
+                                    if params.jitter {
+                                        entry.jitter.get_or_insert_with(Jitter::default).update(now);
+                                    }
+                                    let SocketId { entry: entry_idx, addr, .. } = entry.id;
+                                    if let Some(data_log) = &data_output {
                                         let data = DataPacketInfo {
+                                            time: Local::now(),
                                             to_tunnel: false,
                                             client: addr,
                                             cid: id,
                                             tunnel: tunnel_socket.local_addr().ok(),
                                             data_len: buffer.len(),
+                                            jitter: entry.jitter.map(|j| j.millis()),
+                                            rate: entry.rate.bytes_per_sec(now),
+                                            packet: Some(entry.stats.packets()),
+                                            bytes: Some(entry.stats.bytes()),
                                         };
-                                        println!("{}", data_table.bind(&data));
+                                        data_log.write(&data);
+                                    }
+                                    if params.print_data_buffer {
+                                        common::dump_payload(DataDirection::FromTunnel, id, buffer);
                                     }
-                                    if let Err(e) = external_socket.send_to(&buffer, addr).await {
-                                        eprintln!("failed to send packet: {}", e);
+                                    match external_sockets[entry_idx].send_to(buffer, addr).await {
+                                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                                            if let Some(closed) = cache.remove(id) {
+                                                params.stats.set_connections(cache.active_count());
+                                                events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Refused });
+                                                if params.log_connections {
+                                                    log_closed(&closed, CloseReason::Refused);
+                                                }
+                                            }
+                                            let close = common::close_packet(&mut params.middleware, id, params.bufsize);
+                                            if let Err(e) = common::send_close(&mut tunnel_socket, close).await {
+                                                error!("failed to send close: {}", e);
+                                            }
+                                        }
+                                        Err(e) => {
+                                            error!("failed to send packet: {}", e);
+                                            events.emit(TunnelEvent::SendError { cid: id, error: e.to_string() });
+                                        }
+                                        Ok(_) => {}
                                     }
                                 } else {
-                                    eprintln!("received packet for id {}, but it doesn't exist!", id);
+                                    warn!("received packet for id {}, but it doesn't exist!", id);
                                 }
                             }
-                            _ => eprintln!("ignoring invalid packet type ${:02X}", buffer[0])
+                            _ => reject(&params.stats, params.verbosity, sender_addr, Rejection::UnknownPacket, format!("${:02X}, first bytes: {}", buffer[0], common::head_bytes(&buffer[..size]))),
                         }
                     }
-                    Direction::IntoTunnel => {
-                        let cache_entry = match cache.get_or_insert_by_addr(sender_addr) {
-                            Ok(socket_id) => socket_id,
+                    Direction::IntoTunnel(entry_idx) => {
+                        let cache_entry = match cache.get_by_addr_mut(entry_idx, sender_addr) {
+                            Some(entry) => entry,
+                            None => {
+                                if drain.is_draining() {
+                                    warn!("draining, dropping packet from new client {}", sender_addr);
+                                    continue;
+                                }
+                                for closed in cache.cleanup() {
+                                    events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Timeout });
+                                    if params.log_connections {
+                                        log_closed(&closed, CloseReason::Timeout);
+                                    }
+                                    if params.log_expiry {
+                                        log_expired(&closed);
+                                    }
+                                }
+                                let active = cache.active_count();
+                                params.stats.set_connections(active);
+                                match cache.insert(None, entry_idx, sender_addr) {
+                                    Ok((entry, evicted)) => {
+                                        // an evicted connection made room for this one
+                                        params.stats.set_connections(active + usize::from(evicted.is_none()));
+                                        if let Some(closed) = evicted {
+                                            events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Evicted });
+                                            if params.log_connections {
+                                                log_closed(&closed, CloseReason::Evicted);
+                                            }
+                                            // the id is handed out again right away
+                                            let close = common::close_packet(&mut params.middleware, closed.cid, params.bufsize);
+                                            if let Err(e) = common::send_close(&mut tunnel_socket, close).await {
+                                                error!("failed to send close: {}", e);
+                                            }
+                                        }
+                                        events.emit(TunnelEvent::ConnectionOpened { cid: entry.id.id, peer: sender_addr });
+                                        if params.log_connections {
+                                            log_opened(entry.id.id, sender_addr, None, None);
+                                        }
+                                        entry
+                                    }
+                                    Err(e) => {
+                                        warn!("failed to get ID for client, ignoring: {}", e);
+                                        continue;
+                                    }
+                                }
+                            }
+                        };
+                        let id = cache_entry.id.id;
+                        if !filter::accepts(&params.filter, &Packet { direction: DataDirection::IntoTunnel, cid: id, payload: &buffer[DATA_HEADER_LEN..size + DATA_HEADER_LEN] }) {
+                            reject(&params.stats, params.verbosity, sender_addr, Rejection::Filtered, format_args!("id {}: payload did not pass the filters", id));
+                            continue;
+                        }
+                        cache_entry.stats.record(DataDirection::IntoTunnel, size);
+                        let now = Instant::now();
+                        cache_entry.rate.update(now, size);
+                        let jitter = cache_entry.jitter.map(|j| j.millis());
+                        let rate = cache_entry.rate.bytes_per_sec(now);
+                        let packet = cache_entry.stats.packets();
+                        let bytes = cache_entry.stats.bytes();
+                        params.stats.record(DataDirection::IntoTunnel, size);
+                        // %l is the payload length in both directions
+                        let payload_len = size;
+                        // before the middleware turns it into something unreadable
+                        if params.print_data_buffer {
+                            common::dump_payload(DataDirection::IntoTunnel, id, &buffer[DATA_HEADER_LEN..size + DATA_HEADER_LEN]);
+                        }
+                        let size = match middleware::outbound(&mut params.middleware, id, &mut buffer[DATA_HEADER_LEN..], size) {
+                            Ok(size) => size,
                             Err(e) => {
-                                eprintln!("failed to get ID for client, ignoring: {}", e);
+                                params.stats.reject(Rejection::from(&e));
+                                warn!("middleware dropped packet from {}: {}", sender_addr, e);
                                 continue;
                             }
                         };
-                        let id = cache_entry.id;
                         buffer[0] = PACKET_DATA;
-                        buffer[1] = id;
-                        if let Some(data_table) = &data_output {
+                        buffer[1..DATA_HEADER_LEN].copy_from_slice(&id.to_be_bytes());
+                        if let Some(data_log) = &data_output {
                             let data = DataPacketInfo {
+                                time: Local::now(),
                                 to_tunnel: true,
                                 client: sender_addr,
                                 cid: id,
                                 tunnel: tunnel_socket.local_addr().ok(),
-                                data_len: size,
+                                data_len: payload_len,
+                                jitter,
+                                rate,
+                                packet: Some(packet),
+                                bytes: Some(bytes),
                             };
-                            println!("{}", data_table.bind(&data));
+                            data_log.write(&data);
                         }
-                        if let Err(e) = tunnel_socket.send(&buffer[..size + 2]).await {
-                            eprintln!("failed to send packet: {}", e);
+                        keepalive.sent();
+                        if let Err(e) = coalesce.send(&mut tunnel_socket, &buffer[..size + DATA_HEADER_LEN]).await {
+                            error!("failed to send packet: {}", e);
+                            events.emit(TunnelEvent::SendError { cid: id, error: e.to_string() });
                         }
                     }
                 }
             }
+            // the other end went away, most likely restarted
+            (Direction::FromTunnel, Err(e)) if connecting && common::is_unreachable(&e) => {
+                if reconnect.is_running() {
+                    continue;
+                }
+                warn!("tunnel peer unreachable ({}), connecting again", e);
+                // the current remote first, then the fallbacks if there are any
+                reconnect.start(remotes.clone(), current_remote, &handshake, buffer.len(), events.clone());
+            }
             (dir, Err(e)) => {
-                eprintln!("recv error from {}, ignoring: {}", dir, e);
+                error!("recv error from {}, ignoring: {}", dir, e);
             }
         }
     }
 }
 
-async fn poll_sockets(tunnel_socket: &UdpSocket, external_socket: &UdpSocket, buf: &mut [u8]) -> (Direction, io::Result<(usize, SocketAddr)>) {
-    let mut all = [
-        (Direction::FromTunnel, tunnel_socket),
-        (Direction::IntoTunnel, external_socket),
-    ];
-    all.shuffle(&mut ThreadRng::default());
+// "HOST:FIRST-LAST" stands for one entry address per port in the range, both
+// ends included. Anything else is taken as a single address.
+pub fn expand_entry(entry: &str) -> Result<Vec<String>, String> {
+    let (host, ports) = match entry.rfind(':') {
+        Some(i) if entry[i + 1..].contains('-') => (&entry[..i], &entry[i + 1..]),
+        _ => return Ok(vec![entry.to_string()]),
+    };
+    let mut parts = ports.splitn(2, '-');
+    let first: u16 = parts.next().unwrap().parse().map_err(|_| format!("invalid port range {}", ports))?;
+    let last: u16 = parts.next().unwrap().parse().map_err(|_| format!("invalid port range {}", ports))?;
+    if first > last {
+        return Err(format!("port range {} is reversed", ports));
+    }
+    Ok((first..=last).map(|port| format!("{}:{}", host, port)).collect())
+}
 
-    let (d, r) = common::poll_sockets(&all, buf).await;
-    (*d, r)
+// a failed write only costs the returning clients their ids
+fn save_state(path: Option<&Path>, cache: &Cache) {
+    if let Some(path) = path {
+        if let Err(e) = cache::save_state(path, &cache.to_snapshot()) {
+            warn!("warning: failed to write the state file: {}", e);
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
+// Sockets take turns by weight.
+async fn poll_sockets(tunnel_socket: &UdpSocket, external_sockets: &[UdpSocket], order: &mut FairOrder<Direction>, buf: &mut [u8]) -> (Direction, io::Result<(usize, SocketAddr)>) {
+    order.sort();
+    let order = &*order;
+    common::poll_sockets(|| order.keys().map(|d| match d {
+        Direction::FromTunnel => (d, tunnel_socket),
+        Direction::IntoTunnel(i) => (d, &external_sockets[i]),
+    }), buf).await
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
 enum Direction {
     FromTunnel,
-    IntoTunnel,
+    IntoTunnel(usize),
 }
 
 impl Display for Direction {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Direction::FromTunnel => write!(f, "tunnel"),
-            Direction::IntoTunnel => write!(f, "client"),
+            Direction::IntoTunnel(_) => write!(f, "client"),
         }
     }
 }
 
 struct DataPacketInfo {
+    time: DateTime<Local>,
     to_tunnel: bool,
     client: SocketAddr,
-    cid: u8,
+    cid: u16,
     tunnel: Option<SocketAddr>,
     data_len: usize,
+    jitter: Option<f64>,
+    rate: Option<f64>,
+    packet: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl output::Record for DataPacketInfo {
+    fn record(&self) -> DataRecord {
+        DataRecord {
+            timestamp: self.time.to_rfc3339_opts(SecondsFormat::Millis, false),
+            direction: if self.to_tunnel { "into_tunnel" } else { "from_tunnel" },
+            cid: self.cid,
+            peer: self.client,
+            local: self.tunnel,
+            len: self.data_len,
+            jitter_ms: self.jitter,
+            rate: self.rate,
+            packet: self.packet,
+            bytes: self.bytes,
+        }
+    }
 }
 
 #[derive(Hash, Eq, PartialEq, Copy, Clone)]
 enum OutputColumn {
+    Timestamp,
     Direction,
     RevDirection,
     Client,
@@ -149,13 +716,20 @@ enum OutputColumn {
     ClientAddr,
     TunnelAddr,
     DataLen,
+    Jitter,
+    Rate,
+    PacketCount,
+    Bytes,
 }
 
 impl output::Column for OutputColumn {
     type Data = DataPacketInfo;
 
+    const CHARS: &'static str = "TdDciCtljrnB";
+
     fn by_char(ch: char) -> Option<Self> {
         match ch {
+            'T' => Some(OutputColumn::Timestamp),
             'd' => Some(OutputColumn::Direction),
             'D' => Some(OutputColumn::RevDirection),
             'c' => Some(OutputColumn::Client),
@@ -163,12 +737,35 @@ impl output::Column for OutputColumn {
             'C' => Some(OutputColumn::ClientAddr),
             't' => Some(OutputColumn::TunnelAddr),
             'l' => Some(OutputColumn::DataLen),
+            'j' => Some(OutputColumn::Jitter),
+            'r' => Some(OutputColumn::Rate),
+            'n' => Some(OutputColumn::PacketCount),
+            'B' => Some(OutputColumn::Bytes),
+            _ => None,
+        }
+    }
+
+    fn describe(ch: char) -> Option<&'static str> {
+        match ch {
+            'T' => Some("time the packet was handled, see --timestamp-format"),
+            'd' => Some("direction, => into the tunnel and <= out of it"),
+            'D' => Some("direction seen from the other end, the reverse of %d"),
+            'c' => Some("connection, as ID@local tunnel socket address"),
+            'i' => Some("connection ID"),
+            'C' => Some("address of the client"),
+            't' => Some("local address of the tunnel socket"),
+            'l' => Some("payload length in bytes"),
+            'j' => Some("jitter of the connection in milliseconds, needs --jitter"),
+            'r' => Some("data rate of the connection"),
+            'n' => Some("packets transferred on the connection so far"),
+            'B' => Some("bytes transferred on the connection so far"),
             _ => None,
         }
     }
 
     fn to_string<'a>(&'a self, data: &'a Self::Data) -> Cow<'a, str> {
         match self {
+            OutputColumn::Timestamp => data.time.format(output::time_format()).to_string().into(),
             OutputColumn::Direction => if data.to_tunnel { "=>" } else { "<=" }.into(),
             OutputColumn::RevDirection => if data.to_tunnel { "<=" } else { "=>" }.into(),
             OutputColumn::Client => format!("{}@{}", data.cid, OutputColumn::TunnelAddr.to_string(data)).into(),
@@ -176,16 +773,28 @@ impl output::Column for OutputColumn {
             OutputColumn::ClientAddr => format!("{}", data.client).into(),
             OutputColumn::TunnelAddr => if let Some(tunnel) = data.tunnel { format!("{}", tunnel).into() } else { "???".into() },
             OutputColumn::DataLen => format!("{}", data.data_len).into(),
+            OutputColumn::Jitter => if let Some(j) = data.jitter { format!("{:.2}ms", j).into() } else { "-".into() },
+            OutputColumn::Rate => if let Some(r) = data.rate { stats::format_rate(r).into() } else { "-".into() },
+            OutputColumn::PacketCount => if let Some(n) = data.packet { format!("{}", n).into() } else { "-".into() },
+            OutputColumn::Bytes => if let Some(n) = data.bytes { stats::format_bytes(n).into() } else { "-".into() },
         }
     }
 
     fn constant_size(&self) -> bool {
-        *self == OutputColumn::Direction
+        matches!(self, OutputColumn::Timestamp | OutputColumn::Direction)
+    }
+
+    fn color(&self, data: &Self::Data) -> Option<Color> {
+        match self {
+            OutputColumn::Timestamp => Some(Color::Dim),
+            OutputColumn::Direction | OutputColumn::RevDirection => Some(if data.to_tunnel { Color::Green } else { Color::Cyan }),
+            _ => None,
+        }
     }
 
     fn alignment(&self) -> Alignment {
         match self {
-            OutputColumn::ClientId | OutputColumn::DataLen => Alignment::Right,
+            OutputColumn::ClientId | OutputColumn::DataLen | OutputColumn::Jitter | OutputColumn::Rate | OutputColumn::PacketCount | OutputColumn::Bytes => Alignment::Right,
             _ => Alignment::Left
         }
     }
@@ -1,42 +1,370 @@
+use std::cmp::Reverse;
+use std::collections::{HashSet, VecDeque};
 use std::fmt::{Display, Formatter};
 use std::fmt;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::ops::Deref;
+use std::future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::Ordering;
 use std::task::Poll;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use chrono::Local;
+use hmac::{Hmac, Mac, NewMac};
+use rand::{Rng, SeedableRng};
+use rand::prelude::ThreadRng;
+use rand::rngs::SmallRng;
+use serde::Deserialize;
+use sha2::Sha256;
 use thiserror::Error;
 use tokio::future::poll_fn;
 use tokio::io;
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time;
 
+use crate::{log, middleware, output};
+use crate::middleware::Middleware;
+use crate::sockopt;
+use crate::sockopt::{ListenInterface, SocketOptions};
+use crate::event::{CloseReason, EventSink, TunnelEvent};
 use crate::proto::*;
+use crate::stats::{ConnectionInfo, Rejection, Stats};
 
-pub async fn setup_tunnel_socket(tunnel_addr: Option<impl ToSocketAddrs>, remote: Option<impl ToSocketAddrs>, mode: IpMode, buffer: &mut [u8], remote_type: u8) -> Result<UdpSocket, Error> {
-    let mut tunnel_socket = if let Some(tunnel_addr) = &tunnel_addr {
-        UdpSocket::bind(tunnel_addr).await
-    } else {
-        UdpSocket::bind(default_listen_ip(mode)).await
+// The tunnel socket together with what was negotiated with the other end. It
+// derefs to the socket, so it can be used for sending and receiving directly.
+pub struct TunnelSession {
+    socket: UdpSocket,
+    // None until a handshake with the other end completed
+    pub version: Option<u8>,
+    pub peer_type: Option<u8>,
+    pub features: u8,
+    // round trip of the connect this end sent, if it sent one
+    pub rtt: Option<time::Duration>,
+    psk: Option<Vec<u8>>,
+    // nonces of connects accepted within CONNECT_MAX_AGE with their
+    // timestamps, so a captured one can't be replayed; older ones are
+    // rejected for their timestamp
+    seen_nonces: HashSet<[u8; NONCE_LEN]>,
+    nonce_order: VecDeque<(u64, [u8; NONCE_LEN])>,
+    // nonces of the connects this end sent, the answer has to be signed for one
+    sent_nonces: Vec<[u8; NONCE_LEN]>,
+}
+
+const TIMESTAMP_LEN: usize = 8;
+const NONCE_LEN: usize = 16;
+const CONNECT_TAG_LEN: usize = 32;
+const SIGNED_CONNECT_LEN: usize = 2 + TIMESTAMP_LEN + NONCE_LEN + CONNECT_TAG_LEN;
+// seconds a signed connect is valid for, also how far apart the clocks of
+// the two ends may be
+const CONNECT_MAX_AGE: u64 = 30;
+
+impl TunnelSession {
+    fn new(socket: UdpSocket, psk: Option<Vec<u8>>) -> Self {
+        TunnelSession {
+            socket,
+            version: None,
+            peer_type: None,
+            features: 0,
+            rtt: None,
+            psk,
+            seen_nonces: HashSet::new(),
+            nonce_order: VecDeque::new(),
+            sent_nonces: Vec::new(),
+        }
+    }
+
+    // Writes timestamp, nonce and tag after the connect header in `packet`
+    // if a key is set, returns the length of the connect.
+    fn sign_connect(&mut self, packet: &mut [u8], now: u64) -> usize {
+        let psk = match &self.psk {
+            Some(psk) => psk,
+            None => return 2,
+        };
+        let mut nonce = [0; NONCE_LEN];
+        ThreadRng::default().fill(&mut nonce);
+        packet[2..2 + TIMESTAMP_LEN].copy_from_slice(&now.to_be_bytes());
+        packet[2 + TIMESTAMP_LEN..2 + TIMESTAMP_LEN + NONCE_LEN].copy_from_slice(&nonce);
+        let tag = handshake_mac(psk, b"udptun connect", &[&packet[1..2 + TIMESTAMP_LEN + NONCE_LEN]]).finalize().into_bytes();
+        packet[2 + TIMESTAMP_LEN + NONCE_LEN..SIGNED_CONNECT_LEN].copy_from_slice(&tag);
+        self.sent_nonces.push(nonce);
+        SIGNED_CONNECT_LEN
+    }
+
+    // Returns the nonce to sign the answer with if a key is set.
+    fn verify_connect(&mut self, packet: &[u8], now: u64) -> Result<Option<[u8; NONCE_LEN]>, Error> {
+        let psk = match &self.psk {
+            Some(psk) => psk,
+            None => return Ok(None),
+        };
+        if packet.len() != SIGNED_CONNECT_LEN {
+            return Err(Error::AuthFailed);
+        }
+        let (signed, tag) = packet[1..].split_at(1 + TIMESTAMP_LEN + NONCE_LEN);
+        handshake_mac(psk, b"udptun connect", &[signed]).verify(tag).map_err(|_| Error::AuthFailed)?;
+        let mut timestamp = [0; TIMESTAMP_LEN];
+        timestamp.copy_from_slice(&signed[1..1 + TIMESTAMP_LEN]);
+        let timestamp = u64::from_be_bytes(timestamp);
+        while let Some(&(t, old)) = self.nonce_order.front() {
+            if t.saturating_add(CONNECT_MAX_AGE) >= now {
+                break;
+            }
+            self.nonce_order.pop_front();
+            self.seen_nonces.remove(&old);
+        }
+        if timestamp.abs_diff(now) > CONNECT_MAX_AGE {
+            return Err(Error::AuthFailed);
+        }
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(&signed[1 + TIMESTAMP_LEN..]);
+        if !self.seen_nonces.insert(nonce) {
+            return Err(Error::AuthFailed);
+        }
+        // kept until the newest timestamp seen with it is too old
+        self.nonce_order.push_back((timestamp.max(now), nonce));
+        Ok(Some(nonce))
+    }
+
+    // Appends the tag for the answer `packet[..len]` to the connect with
+    // `nonce`, returns the new length.
+    fn sign_ack(&self, packet: &mut [u8], len: usize, nonce: Option<[u8; NONCE_LEN]>) -> usize {
+        let (psk, nonce) = match (&self.psk, nonce) {
+            (Some(psk), Some(nonce)) => (psk, nonce),
+            _ => return len,
+        };
+        let tag = handshake_mac(psk, b"udptun conn ack", &[&packet[..len], &nonce]).finalize().into_bytes();
+        packet[len..len + CONNECT_TAG_LEN].copy_from_slice(&tag);
+        len + CONNECT_TAG_LEN
+    }
+
+    // Checks that an answer is signed for one of the connects this end sent,
+    // returns its length without the tag.
+    fn verify_ack(&self, packet: &[u8]) -> Result<usize, Error> {
+        let psk = match &self.psk {
+            Some(psk) => psk,
+            None => return Ok(packet.len()),
+        };
+        let len = match packet.len().checked_sub(CONNECT_TAG_LEN) {
+            Some(len) if len >= 3 => len,
+            _ => return Err(Error::AckAuthFailed),
+        };
+        let (ack, tag) = packet.split_at(len);
+        if !self.sent_nonces.iter().any(|nonce| handshake_mac(psk, b"udptun conn ack", &[ack, nonce]).verify(tag).is_ok()) {
+            return Err(Error::AckAuthFailed);
+        }
+        Ok(len)
+    }
+}
+
+// The label keeps the handshake signatures apart from each other and from
+// the data packet tags made with the same key.
+fn handshake_mac(psk: &[u8], label: &[u8], data: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(psk).expect("HMAC accepts any key length");
+    mac.update(label);
+    mac.update(&[PROTO_VERSION]);
+    for d in data {
+        mac.update(d);
+    }
+    mac
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl Deref for TunnelSession {
+    type Target = UdpSocket;
+
+    fn deref(&self) -> &Self::Target { &self.socket }
+}
+
+impl DerefMut for TunnelSession {
+    fn deref_mut(&mut self) -> &mut Self::Target { &mut self.socket }
+}
+
+// Everything the end that connects needs to open a tunnel socket and do the
+// handshake on it. Owned, so a handshake can run in a task of its own.
+#[derive(Clone)]
+pub struct Handshake {
+    pub mode: IpMode,
+    pub opts: SocketOptions,
+    pub interface: Option<ListenInterface>,
+    pub remote_type: u8,
+    pub features: u8,
+    pub psk: Option<Vec<u8>>,
+    pub retries: u32,
+    pub timeout: time::Duration,
+}
+
+pub async fn setup_tunnel_socket(tunnel_addr: Option<impl ToSocketAddrs>, remote: Option<impl ToSocketAddrs>, handshake: &Handshake, buffer: &mut [u8], events: &mut EventSink) -> Result<TunnelSession, Error> {
+    let connecting = tunnel_addr.is_none();
+    let mut session = open_tunnel(tunnel_addr, remote, handshake.mode, &handshake.opts, handshake.interface.as_ref(), handshake.psk.as_deref()).await?;
+    if connecting {
+        send_connect(&mut session, buffer, handshake.remote_type, handshake.features, handshake.retries, handshake.timeout, events).await?;
+    }
+    Ok(session)
+}
+
+// Tries the remotes in turn, starting at `first`, until one answers the
+// connect, and tells which one it was.
+pub async fn setup_failover<U: ToSocketAddrs>(remotes: &[U], first: usize, handshake: &Handshake, buffer: &mut [u8], events: &mut EventSink) -> Result<(TunnelSession, usize), Error> {
+    let mut last_error = None;
+    for i in (0..remotes.len()).map(|i| (first + i) % remotes.len()) {
+        match setup_tunnel_socket(None::<SocketAddr>, Some(&remotes[i]), handshake, buffer, events).await {
+            Ok(session) => return Ok((session, i)),
+            Err(e) => {
+                warn!("remote {} of {} failed: {}", i + 1, remotes.len(), e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("no remotes to fail over between"))
+}
+
+// A handshake running in its own task on a fresh socket, so the forwarding
+// loop doesn't stall for the retries and no data packet on the old socket is
+// taken for the answer. The new session is swapped in once it's done.
+#[derive(Default)]
+pub struct Reconnect {
+    task: Option<JoinHandle<Result<(TunnelSession, usize), Error>>>,
+}
+
+impl Reconnect {
+    pub fn is_running(&self) -> bool {
+        self.task.is_some()
+    }
+
+    // Like setup_failover, with a single remote it's just connecting again.
+    pub fn start(&mut self, remotes: Vec<SocketAddr>, first: usize, handshake: &Handshake, bufsize: usize, mut events: EventSink) {
+        let handshake = handshake.clone();
+        self.task = Some(tokio::spawn(async move {
+            let mut buffer = vec![0; bufsize];
+            if remotes.len() == 1 {
+                setup_tunnel_socket(None::<SocketAddr>, Some(remotes[0]), &handshake, &mut buffer, &mut events).await.map(|s| (s, 0))
+            } else {
+                setup_failover(&remotes, first, &handshake, &mut buffer, &mut events).await
+            }
+        }));
+    }
+
+    // Never completes while no handshake is running.
+    pub async fn done(&mut self) -> Result<(TunnelSession, usize), Error> {
+        let result = match &mut self.task {
+            Some(task) => task.await,
+            None => future::pending().await,
+        };
+        self.task = None;
+        result.expect("handshake task panicked")
+    }
+}
+
+pub async fn resolve_remotes<U: ToSocketAddrs>(remotes: &[U], mode: IpMode) -> Result<Vec<SocketAddr>, Error> {
+    let mut resolved = Vec::with_capacity(remotes.len());
+    for remote in remotes {
+        let addr = lookup_host(remote).await.map_err(Error::RemoteConnect)?
+            .find(|a| mode.allows(a))
+            .ok_or_else(|| Error::RemoteConnect(io::Error::new(io::ErrorKind::AddrNotAvailable, "no remote address for the selected IP mode")))?;
+        resolved.push(addr);
+    }
+    Ok(resolved)
+}
+
+// Binds the tunnel socket and points it at the remote without sending
+// anything over it yet.
+pub async fn open_tunnel(tunnel_addr: Option<impl ToSocketAddrs>, remote: Option<impl ToSocketAddrs>, mode: IpMode, opts: &SocketOptions, interface: Option<&ListenInterface>, psk: Option<&[u8]>) -> Result<TunnelSession, Error> {
+    let tunnel_socket = match (&tunnel_addr, interface) {
+        (Some(addr), Some(interface)) => sockopt::bind_on(addr, interface, opts).await,
+        (Some(addr), None) => sockopt::bind(addr, opts).await,
+        (None, Some(interface)) => sockopt::bind_on(default_listen_ip(mode), interface, opts).await,
+        (None, None) => sockopt::bind(default_listen_ip(mode), opts).await,
     }.map_err(Error::TunnelSocketBind)?;
-    if let Some(remote) = remote {
-        tunnel_socket.connect(remote).await.map_err(Error::RemoteConnect)?;
+    // some systems force IPV6_V6ONLY on and don't let it be cleared, which
+    // silently locks out IPv4 peers
+    if mode == IpMode::Both && sockopt::is_v6_only(&tunnel_socket).unwrap_or(false) {
+        warn!("warning: the tunnel socket only accepts IPv6 even though both IP versions are enabled, IPv4 peers will not be able to connect; run a separate instance with -4 for them");
     }
-    if tunnel_addr.is_none() {
-        send_connect(&mut tunnel_socket, buffer, remote_type).await?;
+    let session = TunnelSession::new(tunnel_socket, psk.map(|k| k.to_vec()));
+    if let Some(remote) = remote {
+        session.connect(remote).await.map_err(Error::RemoteConnect)?;
+        // would forward everything to itself forever
+        if let (Ok(local), Ok(peer)) = (session.local_addr(), sockopt::peer_addr(&session)) {
+            if is_own_addr(local, peer) {
+                return Err(Error::RemoteLoopback(peer));
+            }
+        }
     }
-    Ok(tunnel_socket)
+    Ok(session)
 }
 
-pub async fn send_connect(tunnel_socket: &mut UdpSocket, buffer: &mut [u8], remote_type: u8) -> Result<(), Error> {
-    buffer[0] = PACKET_CONNECT;
-    tunnel_socket.send(&buffer[..1]).await.map_err(Error::ConnectSend)?;
-    let len = tunnel_socket.recv(buffer).await.map_err(Error::ConnectRecv)?;
-    let expected = [PACKET_CONN_ACK, remote_type, 0x01];
-    if buffer[..len] != expected {
-        return Err(Error::ConnectResponse {
+// The framing features byte is optional on both packets so that peers
+// without it can still connect as long as no feature is enabled. An
+// unanswered or refused connect is sent again up to `retries` times, waiting
+// twice as long each time.
+pub async fn send_connect(tunnel_socket: &mut TunnelSession, buffer: &mut [u8], remote_type: u8, features: u8, retries: u32, timeout: time::Duration, events: &mut EventSink) -> Result<(), Error> {
+    let mut wait = timeout;
+    let mut response = None;
+    tunnel_socket.sent_nonces.clear();
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            warn!("no answer to connect, retrying ({}/{})", attempt, retries);
+        }
+        // built anew every time, the buffer may hold a stray datagram by now
+        // and a signed connect needs a fresh nonce or it's taken for a replay
+        buffer[0] = PACKET_CONNECT;
+        buffer[1] = features;
+        let len = tunnel_socket.sign_connect(buffer, unix_time());
+        // monotonic, a clock change during the handshake can't skew it; after
+        // a retry it may be the answer to an earlier try, which reads short
+        let sent = time::Instant::now();
+        tunnel_socket.send(&buffer[..len]).await.map_err(Error::ConnectSend)?;
+        match time::timeout(wait, tunnel_socket.recv_from(buffer)).await {
+            Ok(Ok(r)) => {
+                response = Some((r.0, r.1, sent.elapsed()));
+                break;
+            }
+            // nothing listens there yet, the other end may still be starting
+            Ok(Err(e)) if is_unreachable(&e) => time::delay_until(sent + wait).await,
+            Ok(Err(e)) => return Err(Error::ConnectRecv(e)),
+            Err(_) => {}
+        }
+        wait *= 2;
+    }
+    let (len, remote, rtt) = response.ok_or(Error::ConnectTimeout(retries + 1))?;
+    // anyone could answer in the other end's name otherwise
+    let len = match tunnel_socket.verify_ack(&buffer[..len]) {
+        Ok(len) => len,
+        Err(e) => {
+            events.emit(TunnelEvent::HandshakeRejected { remote, reason: e.to_string() });
+            return Err(e);
+        }
+    };
+    let expected = [PACKET_CONN_ACK, remote_type, PROTO_VERSION];
+    // a well-formed answer from an older or newer release
+    if (3..=4).contains(&len) && buffer[..2] == expected[..2] && buffer[2] != PROTO_VERSION {
+        let e = Error::VersionMismatch { ours: PROTO_VERSION, theirs: buffer[2] };
+        events.emit(TunnelEvent::HandshakeRejected { remote, reason: e.to_string() });
+        return Err(e);
+    }
+    if !(3..=4).contains(&len) || buffer[..3] != expected {
+        let e = Error::ConnectResponse {
             response: HexFormat(buffer[..len].into()),
             expected: HexFormat(expected),
-        });
+        };
+        events.emit(TunnelEvent::HandshakeRejected { remote, reason: e.to_string() });
+        return Err(e);
     }
+    let remote_features = if len > 3 { buffer[3] } else { 0 };
+    if remote_features != features {
+        let e = Error::FeatureMismatch { local: features, remote: remote_features };
+        events.emit(TunnelEvent::HandshakeRejected { remote, reason: e.to_string() });
+        return Err(e);
+    }
+    tunnel_socket.version = Some(buffer[2]);
+    tunnel_socket.peer_type = Some(remote_type);
+    tunnel_socket.features = features;
+    tunnel_socket.rtt = Some(rtt);
+    info!("[connect]\tremote: {}\trtt: {:.1}ms", remote, rtt.as_secs_f64() * 1000.0);
+    events.emit(TunnelEvent::HandshakeCompleted { remote });
     Ok(())
 }
 
@@ -44,14 +372,40 @@ pub async fn send_connect(tunnel_socket: &mut UdpSocket, buffer: &mut [u8], remo
 pub enum Error {
     #[error("failed to bind tunnel socket")]
     TunnelSocketBind(#[source] io::Error),
+    #[error("failed to share tunnel socket with the forwarding tasks")]
+    TunnelSocketShare(#[source] io::Error),
     #[error("failed to connect to remote")]
     RemoteConnect(#[source] io::Error),
+    #[error("remote {0} is this end's own tunnel socket")]
+    RemoteLoopback(SocketAddr),
     #[error("failed to send connect packet")]
     ConnectSend(#[source] io::Error),
+    #[error("failed to send connect response")]
+    ConnectRespond(#[source] io::Error),
     #[error("failed to receive connect response")]
     ConnectRecv(#[source] io::Error),
+    #[error("no answer to connect after {0} attempts")]
+    ConnectTimeout(u32),
     #[error("remote sent invalid response to connect: {response}, expected {expected}")]
     ConnectResponse { response: HexFormat<Vec<u8>>, expected: HexFormat<[u8; 3]> },
+    #[error("remote speaks protocol version {theirs}, but this end speaks version {ours}; both ends need to run the same release")]
+    VersionMismatch { ours: u8, theirs: u8 },
+    #[error("remote uses framing features {remote:#04x}, but this end uses {local:#04x}")]
+    FeatureMismatch { local: u8, remote: u8 },
+    #[error("connect is not signed with the shared key, too old or was replayed")]
+    AuthFailed,
+    #[error("answer to connect is not signed with the shared key")]
+    AckAuthFailed,
+    #[error("failed to open entry socket")]
+    EntryBind(#[source] io::Error),
+    #[error("no targets to forward to")]
+    NoTargets,
+    #[error("failed to resolve target")]
+    TargetResolve(#[source] io::Error),
+    #[error("failed to open control socket")]
+    ControlSocket(#[source] io::Error),
+    #[error("failed to parse data log format")]
+    DataFormat(#[source] output::ParseError),
 }
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -81,13 +435,170 @@ impl<T> Display for HexFormat<T>
     }
 }
 
-pub async fn respond_connect(tunnel_socket: &mut UdpSocket, sender_addr: SocketAddr, buffer: &mut [u8], typ: u8) {
+const HEAD_LEN: usize = 16;
+
+// The start of a datagram that couldn't be made sense of, enough to tell
+// noise from a framing bug.
+pub fn head_bytes(packet: &[u8]) -> HexFormat<Vec<u8>> {
+    HexFormat(packet[..packet.len().min(HEAD_LEN)].to_vec())
+}
+
+// Classic hex dump with 16 bytes per row, an offset column and the printable
+// ASCII characters on the side.
+pub struct HexDump<'a>(pub &'a [u8]);
+
+const DUMP_ROW_LEN: usize = 16;
+
+impl Display for HexDump<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, row) in self.0.chunks(DUMP_ROW_LEN).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let hex = HexFormat(row.to_vec()).to_string();
+            let ascii: String = row.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+            write!(f, "{:08x}  {:<width$}  |{}|", i * DUMP_ROW_LEN, hex, ascii, width = DUMP_ROW_LEN * 3 - 1)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn dump_payload(dir: DataDirection, cid: u16, payload: &[u8]) {
+    let dir = match dir {
+        DataDirection::IntoTunnel => "into tunnel",
+        DataDirection::FromTunnel => "from tunnel",
+    };
+    log::data(format_args!("[dump {}]\tid: {}\tlen: {}\n{}", dir, cid, payload.len(), HexDump(payload)));
+}
+
+pub async fn respond_connect(tunnel_socket: &mut TunnelSession, sender_addr: SocketAddr, buffer: &mut [u8], len: usize, typ: u8, features: u8, events: &mut EventSink) -> Result<(), Error> {
+    // never answer or connect to a sender without the right key
+    let nonce = match tunnel_socket.verify_connect(&buffer[..len], unix_time()) {
+        Ok(nonce) => nonce,
+        Err(e) => {
+            events.emit(TunnelEvent::HandshakeRejected { remote: sender_addr, reason: e.to_string() });
+            return Err(e);
+        }
+    };
+    let remote_features = if len > 1 { Some(buffer[1]) } else { None };
     buffer[0] = PACKET_CONN_ACK;
     buffer[1] = typ;
     buffer[2] = PROTO_VERSION;
-    println!("[connect]\tremote: {}", sender_addr);
-    tunnel_socket.connect(sender_addr).await.expect("failed to connect to remote");
-    tunnel_socket.send(&buffer[..3]).await.expect("failed to send connect response");
+    buffer[3] = features;
+    info!("[connect]\tremote: {}", sender_addr);
+    tunnel_socket.connect(sender_addr).await.map_err(Error::RemoteConnect)?;
+    let response_len = tunnel_socket.sign_ack(buffer, if remote_features.is_some() { 4 } else { 3 }, nonce);
+    tunnel_socket.send(&buffer[..response_len]).await.map_err(Error::ConnectRespond)?;
+    // the remote end rejects the handshake itself, the error is only reported
+    match remote_features.unwrap_or(0) {
+        f if f == features => {
+            tunnel_socket.version = Some(PROTO_VERSION);
+            // CONNECT doesn't say what the sender is, but it can only be
+            // the other kind of end
+            tunnel_socket.peer_type = Some(if typ == TYPE_SERVER { TYPE_CLIENT } else { TYPE_SERVER });
+            tunnel_socket.features = features;
+            events.emit(TunnelEvent::HandshakeCompleted { remote: sender_addr });
+            Ok(())
+        }
+        f => {
+            let e = Error::FeatureMismatch { local: features, remote: f };
+            events.emit(TunnelEvent::HandshakeRejected { remote: sender_addr, reason: e.to_string() });
+            Err(e)
+        }
+    }
+}
+
+// What a connected UDP socket reports after an ICMP port unreachable; it
+// comes back for every packet sent to a peer that is gone
+pub fn is_unreachable(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset)
+}
+
+// Tells the other end that a connection is gone, so it can free the ID right
+// away instead of waiting for the timeout.
+pub async fn send_close(tunnel_socket: &mut TunnelSession, packet: io::Result<Vec<u8>>) -> io::Result<()> {
+    tunnel_socket.send(&packet?).await.map(|_| ())
+}
+
+// The empty body of a close goes through the middleware like a data payload,
+// so with --hmac or --encrypt nobody else can close connections. Built apart
+// from sending it so a lock on the middleware isn't held while waiting.
+pub fn close_packet(mw: &mut Option<Box<dyn Middleware>>, cid: u16, bufsize: usize) -> io::Result<Vec<u8>> {
+    let mut packet = vec![0; bufsize];
+    packet[0] = PACKET_CLOSE;
+    packet[1..DATA_HEADER_LEN].copy_from_slice(&cid.to_be_bytes());
+    let len = middleware::outbound(mw, cid, &mut packet[DATA_HEADER_LEN..], 0).map_err(io::Error::other)?;
+    packet.truncate(DATA_HEADER_LEN + len);
+    Ok(packet)
+}
+
+// connection ID from the header of a data or close packet
+pub fn read_cid(packet: &[u8]) -> u16 {
+    u16::from_be_bytes([packet[1], packet[2]])
+}
+
+// Counts a packet or handshake dropped at the tunnel and, from verbosity 1
+// on, logs where it came from and why, independently of data logging.
+pub fn reject(stats: &Stats, verbosity: u64, source: impl Display, reason: Rejection, detail: impl Display) {
+    stats.reject(reason);
+    if verbosity >= 1 {
+        debug!("[reject]\tsource: {}\treason: {}\tdetail: {}", source, reason, detail);
+    }
+}
+
+pub fn log_opened(cid: u16, peer: SocketAddr, source: Option<SocketAddr>, target: Option<SocketAddr>) {
+    let fmt_addr = |a: Option<SocketAddr>| a.map_or("-".to_string(), |a| a.to_string());
+    log::in_connection(cid, peer, || info!("[open]\tid: {}\tpeer: {}\tsource: {}\ttarget: {}", cid, peer, fmt_addr(source), fmt_addr(target)));
+}
+
+pub fn log_closed(info: &ConnectionInfo, reason: CloseReason) {
+    log::in_connection(info.cid, info.peer, || info!(
+        "[close]\tid: {}\tpeer: {}\treason: {}\tlifetime: {}s\tin: {}/{}B\tout: {}/{}B",
+        info.cid, info.peer, reason,
+        info.last_access.signed_duration_since(info.created).num_seconds(),
+        info.stats.packets_in, info.stats.bytes_in,
+        info.stats.packets_out, info.stats.bytes_out,
+    ));
+}
+
+// idle time as of the cleanup that removed it, so at least the timeout
+pub fn log_expired(info: &ConnectionInfo) {
+    let idle = Local::now().signed_duration_since(info.last_access).num_seconds();
+    log::in_connection(info.cid, info.peer, || info!("connection {}@{} expired after {}s idle", info.cid, info.peer, idle));
+}
+
+// A packet received at `offset` that fills the buffer completely most likely
+// got truncated. Warns about it and grows the buffer once to `grow_to` bytes
+// the first time it happens. Returns whether the packet should be dropped.
+pub fn check_truncated(buffer: &mut Vec<u8>, offset: usize, received: usize, source: SocketAddr, grow_to: &mut Option<usize>, strict: bool) -> bool {
+    if offset + received < buffer.len() {
+        return false;
+    }
+    warn!("packet from {} may be truncated at {} bytes{}", source, received, if strict { ", dropping it" } else { "" });
+    if let Some(size) = grow_to.take() {
+        if size > buffer.len() {
+            warn!("growing the buffer from {} to {} bytes", buffer.len(), size);
+            buffer.resize(size, 0);
+        }
+    }
+    strict
+}
+
+// Whether `addr` is the socket bound to `local`, as far as that can be told
+// without going through the addresses of every interface
+pub fn is_own_addr(local: SocketAddr, addr: SocketAddr) -> bool {
+    let (local_ip, ip) = (unmap(local.ip()), unmap(addr.ip()));
+    addr.port() == local.port() && (ip == local_ip || ip.is_loopback() && (local_ip.is_unspecified() || local_ip.is_loopback()))
+}
+
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip,
+        },
+        ip => ip,
+    }
 }
 
 pub fn default_listen_ip(mode: IpMode) -> SocketAddr {
@@ -97,9 +608,193 @@ pub fn default_listen_ip(mode: IpMode) -> SocketAddr {
     }
 }
 
-pub async fn poll_sockets<'a, T>(sockets: &'a [(T, &UdpSocket)], buf: &mut [u8]) -> (&'a T, io::Result<(usize, SocketAddr)>) {
+// The random number generator for everything that picks addresses or IDs, so
+// a fixed seed makes them reproducible. Not for anything secret.
+pub fn seeded_rng(seed: Option<u64>) -> SmallRng {
+    match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
+    }
+}
+
+// Smooth weighted round-robin over sockets. Every socket that keeps getting
+// packets is serviced in turn, in proportion to its weight, however busy the
+// others are. A weight of 0 puts a socket behind all others.
+pub struct FairOrder<K> {
+    slots: Vec<FairSlot<K>>,
+}
+
+struct FairSlot<K> {
+    key: K,
+    weight: u32,
+    // how far the socket is behind its share, the highest goes first
+    credit: i64,
+}
+
+impl<K> Default for FairOrder<K> {
+    fn default() -> Self {
+        FairOrder { slots: Vec::new() }
+    }
+}
+
+impl<K: Copy + Eq> FairOrder<K> {
+    // sockets that stay keep what they're owed
+    pub fn set(&mut self, slots: impl Iterator<Item=(K, u32)>) {
+        let old = std::mem::take(&mut self.slots);
+        self.slots = slots.map(|(key, weight)| {
+            let credit = old.iter().find(|s| s.key == key).map_or(0, |s| s.credit);
+            FairSlot { key, weight, credit }
+        }).collect();
+    }
+
+    // sorts the sockets by how long they have been waiting for their turn
+    pub fn sort(&mut self) {
+        self.slots.sort_by_key(|s| Reverse(s.credit));
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item=K> + '_ {
+        self.slots.iter().map(|s| s.key)
+    }
+
+    pub fn serviced(&mut self, key: K) {
+        let total: i64 = self.slots.iter().map(|s| i64::from(s.weight)).sum();
+        for s in &mut self.slots {
+            s.credit += i64::from(s.weight);
+            if s.key == key {
+                s.credit -= total;
+            }
+        }
+    }
+}
+
+// what a receive gave
+type Received = io::Result<(usize, SocketAddr)>;
+
+// Datagrams read ahead in one go after a socket turned out to be readable.
+// They are handed out one at a time before the sockets are polled again.
+pub struct Batch<T> {
+    bufs: Vec<Vec<u8>>,
+    // direction, index into bufs and what the receive gave
+    pending: VecDeque<(T, usize, Received)>,
+    // split out of a coalesced datagram, handed out before anything pending
+    frames: VecDeque<(T, Vec<u8>, SocketAddr)>,
+}
+
+impl<T: Copy> Batch<T> {
+    // `size` counts the datagram that was already received the normal way
+    pub fn new(size: usize) -> Self {
+        Batch { bufs: vec![Vec::new(); size.saturating_sub(1)], pending: VecDeque::new(), frames: VecDeque::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty() && self.pending.is_empty()
+    }
+
+    pub fn pop_into(&mut self, buf: &mut [u8]) -> Option<(T, io::Result<(usize, SocketAddr)>)> {
+        if let Some((dir, frame, addr)) = self.frames.pop_front() {
+            let len = frame.len().min(buf.len());
+            buf[..len].copy_from_slice(&frame[..len]);
+            return Some((dir, Ok((len, addr))));
+        }
+        let (dir, i, r) = self.pending.pop_front()?;
+        Some((dir, r.map(|(len, addr)| {
+            let len = len.min(buf.len());
+            buf[..len].copy_from_slice(&self.bufs[i][..len]);
+            (len, addr)
+        })))
+    }
+
+    // Queues the packets in the body of a PACKET_COALESCED datagram. Nothing
+    // is queued if the lengths don't add up.
+    pub fn split(&mut self, dir: T, body: &[u8], addr: SocketAddr) -> bool {
+        let mut frames = Vec::new();
+        let mut rest = body;
+        while !rest.is_empty() {
+            if rest.len() < 2 {
+                return false;
+            }
+            let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            match rest.get(2..2 + len) {
+                Some(frame) => frames.push(frame.to_vec()),
+                None => return false,
+            }
+            rest = &rest[2 + len..];
+        }
+        for frame in frames.into_iter().rev() {
+            self.frames.push_front((dir, frame, addr));
+        }
+        true
+    }
+
+    // reads what else is already waiting on `socket`, without waiting for more
+    pub fn fill(&mut self, dir: T, socket: &UdpSocket, bufsize: usize) {
+        if self.bufs.is_empty() {
+            return;
+        }
+        for b in &mut self.bufs {
+            b.resize(bufsize, 0);
+        }
+        match sockopt::recv_batch(socket, &mut self.bufs) {
+            Ok(received) => self.pending.extend(received.into_iter().enumerate().map(|(i, r)| (dir, i, Ok(r)))),
+            Err(e) => self.pending.push_back((dir, 0, Err(e))),
+        }
+    }
+}
+
+// Data packets into the tunnel that go out together in one PACKET_COALESCED
+// datagram. They are held back only while more packets are already waiting
+// to be read, so coalescing never adds latency.
+pub struct Coalesce {
+    enabled: bool,
+    max: usize,
+    buf: Vec<u8>,
+    count: usize,
+}
+
+impl Coalesce {
+    // `max` is the largest datagram the other end can take
+    pub fn new(enabled: bool, max: usize) -> Self {
+        Coalesce { enabled, max, buf: Vec::new(), count: 0 }
+    }
+
+    // Sends whatever was held back first if the packet doesn't fit next to
+    // it, so errors can be from earlier packets.
+    pub async fn send(&mut self, socket: &mut TunnelSession, packet: &[u8]) -> io::Result<()> {
+        if !self.enabled || 1 + 2 + packet.len() > self.max {
+            self.flush(socket).await?;
+            return socket.send(packet).await.map(|_| ());
+        }
+        if self.buf.len() + 2 + packet.len() > self.max {
+            self.flush(socket).await?;
+        }
+        if self.buf.is_empty() {
+            self.buf.push(PACKET_COALESCED);
+        }
+        self.buf.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(packet);
+        self.count += 1;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self, socket: &mut TunnelSession) -> io::Result<()> {
+        let r = match self.count {
+            0 => return Ok(()),
+            // a single packet goes out as it is
+            1 => socket.send(&self.buf[3..]).await,
+            _ => socket.send(&self.buf).await,
+        };
+        self.buf.clear();
+        self.count = 0;
+        r.map(|_| ())
+    }
+}
+
+// `sockets` is called again every time the sockets are polled and has to
+// return them in the same order each time.
+pub async fn poll_sockets<'a, T, I>(sockets: impl Fn() -> I, buf: &mut [u8]) -> (T, io::Result<(usize, SocketAddr)>)
+    where I: Iterator<Item=(T, &'a UdpSocket)> {
     poll_fn(|cx| {
-        sockets.iter().filter_map(|(dir, sock)| match sock.poll_recv_from(cx, buf) {
+        sockets().filter_map(|(dir, sock)| match sock.poll_recv_from(cx, buf) {
             Poll::Ready(r) => Some((dir, r)),
             Poll::Pending => None,
         }).next().map(Poll::Ready).unwrap_or(Poll::Pending)
@@ -107,22 +802,436 @@ pub async fn poll_sockets<'a, T>(sockets: &'a [(T, &UdpSocket)], buf: &mut [u8])
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DataDirection {
+    IntoTunnel,
+    FromTunnel,
+}
+
+// "both", "v4-only" or "v6-only" in the config file
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum IpMode {
     Both,
     V4Only,
     V6Only,
 }
 
+impl IpMode {
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpMode::Both => true,
+            IpMode::V4Only => addr.is_ipv4(),
+            IpMode::V6Only => addr.is_ipv6(),
+        }
+    }
+}
+
 pub enum Format<'a> {
     Default,
     Custom(&'a str),
+    Json,
 }
 
 impl<'a> Format<'a> {
     pub fn with_default(&'a self, default: &'a str) -> &'a str {
         match self {
-            Format::Default => default,
+            Format::Default | Format::Json => default,
             Format::Custom(c) => c,
         }
     }
+}
+
+// Tells when nothing was sent into the tunnel for the configured interval, so
+// a keepalive should go out to keep NAT mappings on the way open.
+pub struct Keepalive {
+    interval: Option<time::Duration>,
+    last_sent: time::Instant,
+}
+
+impl Keepalive {
+    pub fn new(interval: Option<time::Duration>) -> Self {
+        Keepalive { interval, last_sent: time::Instant::now() }
+    }
+
+    pub fn sent(&mut self) {
+        self.last_sent = time::Instant::now();
+    }
+
+    pub async fn due(&self) {
+        match self.interval {
+            Some(interval) => loop {
+                time::delay_until(self.last_sent + interval).await;
+                if self.last_sent.elapsed() >= interval {
+                    return;
+                }
+            },
+            None => future::pending().await,
+        }
+    }
+}
+
+pub enum HealthCheck {
+    Probe,
+    Lost,
+}
+
+// Tells when the remote should be probed with a keepalive that asks for an
+// answer, and when it has been quiet for long enough to count as gone.
+pub struct Health {
+    timeout: Option<time::Duration>,
+    last_heard: time::Instant,
+    last_probe: Option<time::Instant>,
+}
+
+impl Health {
+    pub fn new(timeout: Option<time::Duration>) -> Self {
+        Health { timeout, last_heard: time::Instant::now(), last_probe: None }
+    }
+
+    pub fn heard(&mut self) {
+        self.last_heard = time::Instant::now();
+        self.last_probe = None;
+    }
+
+    pub fn probed(&mut self) {
+        self.last_probe = Some(time::Instant::now());
+    }
+
+    // two probes go unanswered before the remote is given up on
+    pub async fn check(&self) -> HealthCheck {
+        match self.timeout {
+            Some(timeout) => {
+                let next_probe = self.last_probe.unwrap_or(self.last_heard) + timeout / 3;
+                let lost = self.last_heard + timeout;
+                if next_probe < lost {
+                    time::delay_until(next_probe).await;
+                    HealthCheck::Probe
+                } else {
+                    time::delay_until(lost).await;
+                    HealthCheck::Lost
+                }
+            }
+            None => future::pending().await,
+        }
+    }
+}
+
+// Stops new connections from being accepted and ends the tunnel once all
+// existing ones are gone or the timeout has passed.
+pub struct Drain {
+    timeout: time::Duration,
+    deadline: Option<time::Instant>,
+    ticker: time::Interval,
+}
+
+impl Drain {
+    pub fn new(timeout: time::Duration) -> Self {
+        Drain {
+            timeout,
+            deadline: None,
+            ticker: time::interval(time::Duration::from_secs(1)),
+        }
+    }
+
+    pub fn start(&mut self, stats: &Stats) {
+        if self.deadline.is_none() {
+            self.deadline = Some(time::Instant::now() + self.timeout);
+            stats.draining.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    // resolves periodically while draining, with true once the deadline passed
+    pub async fn tick(&mut self) -> bool {
+        match self.deadline {
+            Some(deadline) => {
+                self.ticker.tick().await;
+                time::Instant::now() >= deadline
+            }
+            None => future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn open(psk: Option<&[u8]>) -> TunnelSession {
+        open_tunnel(Some("127.0.0.1:0"), None::<SocketAddr>, IpMode::V4Only, &SocketOptions::default(), None, psk).await.unwrap()
+    }
+
+    // One connect from a client with `client_key` to a server with
+    // `server_key`, returns what both ends made of it.
+    async fn handshake(server_key: &[u8], client_key: &[u8]) -> (Result<(), Error>, Result<(), Error>) {
+        let mut server = open(Some(server_key)).await;
+        let mut client = open(Some(client_key)).await;
+        handshake_on(&mut server, &mut client).await
+    }
+
+    async fn handshake_on(server: &mut TunnelSession, client: &mut TunnelSession) -> (Result<(), Error>, Result<(), Error>) {
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        let respond = async {
+            let mut buf = [0; 64];
+            let (len, addr) = server.recv_from(&mut buf).await.unwrap();
+            respond_connect(server, addr, &mut buf, len, TYPE_SERVER, 0, &mut EventSink::new(None)).await
+        };
+        let mut buf = [0; 64];
+        let mut events = EventSink::new(None);
+        let connect = send_connect(client, &mut buf, TYPE_SERVER, 0, 0, Duration::from_millis(200), &mut events);
+        tokio::join!(connect, respond)
+    }
+
+    #[tokio::test]
+    async fn connect_with_right_key() {
+        let (client, server) = handshake(b"key", b"key").await;
+        client.unwrap();
+        server.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_with_wrong_key() {
+        let (client, server) = handshake(b"key", b"other").await;
+        assert!(matches!(server, Err(Error::AuthFailed)));
+        // dropped without an answer
+        assert!(matches!(client, Err(Error::ConnectTimeout(1))));
+    }
+
+    // an IPv6 sender can't be reached from an IPv4 socket
+    #[tokio::test]
+    async fn respond_to_unroutable() {
+        let mut server = open(None).await;
+        let mut buf = [PACKET_CONNECT, 0, 0, 0];
+        let sender = SocketAddr::from((Ipv6Addr::LOCALHOST, 1000));
+        let r = respond_connect(&mut server, sender, &mut buf, 2, TYPE_SERVER, 0, &mut EventSink::new(None)).await;
+        assert!(matches!(r, Err(Error::RemoteConnect(_))));
+
+        // and the socket still takes the next connect
+        let (connected, responded) = handshake_on(&mut server, &mut open(None).await).await;
+        connected.unwrap();
+        responded.unwrap();
+    }
+
+    // services the first in the order `rounds` times with every key always
+    // ready, returns how often each key came first
+    fn fair_turns(weights: &[u32], rounds: usize) -> Vec<usize> {
+        let mut order = FairOrder::default();
+        order.set(weights.iter().copied().enumerate());
+        let mut turns = vec![0; weights.len()];
+        for _ in 0..rounds {
+            order.sort();
+            let first = order.keys().next().unwrap();
+            order.serviced(first);
+            turns[first] += 1;
+        }
+        turns
+    }
+
+    #[test]
+    fn fair_order_by_weight() {
+        assert_eq!(fair_turns(&[1, 1], 100), [50, 50]);
+        assert_eq!(fair_turns(&[3, 1], 400), [300, 100]);
+        assert_eq!(fair_turns(&[1, 0], 100), [100, 0]);
+    }
+
+    #[test]
+    fn fair_order_keeps_credit() {
+        let mut order = FairOrder::default();
+        order.set([(0, 1), (1, 1)].iter().copied());
+        order.serviced(0);
+        // 1 is owed a turn, also after a socket was added
+        order.set([(0, 1), (1, 1), (2, 1)].iter().copied());
+        order.sort();
+        assert_eq!(order.keys().next(), Some(1));
+    }
+
+    // more is queued on both sockets than is read, so both are always ready
+    #[tokio::test]
+    async fn fair_poll_with_two_busy_sockets() {
+        let sockets = [UdpSocket::bind("127.0.0.1:0").await.unwrap(), UdpSocket::bind("127.0.0.1:0").await.unwrap()];
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for _ in 0..110 {
+            for socket in &sockets {
+                sender.send_to(b"x", socket.local_addr().unwrap()).unwrap();
+            }
+        }
+        let mut order = FairOrder::default();
+        order.set([(0, 1), (1, 1)].iter().copied());
+        let mut turns = [0; 2];
+        let mut buf = [0; 16];
+        for _ in 0..200 {
+            order.sort();
+            let (i, r) = poll_sockets(|| order.keys().map(|i| (i, &sockets[i])), &mut buf).await;
+            r.unwrap();
+            order.serviced(i);
+            turns[i] += 1;
+        }
+        assert_eq!(turns, [100, 100]);
+    }
+
+    // against a responder that ignores the first `dropped` connects
+    async fn connect_with_loss(dropped: usize, retries: u32) -> Result<(), Error> {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = open(None).await;
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        let respond = async {
+            let mut buf = [0; 64];
+            for _ in 0..dropped {
+                server.recv_from(&mut buf).await.unwrap();
+            }
+            let (_, addr) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(&[PACKET_CONN_ACK, TYPE_SERVER, PROTO_VERSION, 0], &addr).await.unwrap();
+        };
+        let mut buf = [0; 64];
+        let mut events = EventSink::new(None);
+        let connect = send_connect(&mut client, &mut buf, TYPE_SERVER, 0, retries, Duration::from_millis(50), &mut events);
+        let (connected, _) = tokio::join!(connect, time::timeout(Duration::from_secs(1), respond));
+        connected
+    }
+
+    #[tokio::test]
+    async fn connect_retries() {
+        connect_with_loss(2, 2).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_out_of_retries() {
+        assert!(matches!(connect_with_loss(2, 1).await, Err(Error::ConnectTimeout(2))));
+    }
+
+    #[tokio::test]
+    async fn connect_refused() {
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut client = open(None).await;
+        client.connect(closed).await.unwrap();
+        let mut buf = [0; 64];
+        let r = send_connect(&mut client, &mut buf, TYPE_SERVER, 0, 1, Duration::from_millis(50), &mut EventSink::new(None)).await;
+        assert!(matches!(r, Err(Error::ConnectTimeout(2))));
+    }
+
+    #[tokio::test]
+    async fn both_accepts_v4() {
+        let mut server = open_tunnel(None::<SocketAddr>, None::<SocketAddr>, IpMode::Both, &SocketOptions::default(), None, None).await.unwrap();
+        assert!(!sockopt::is_v6_only(&server).unwrap());
+        let port = server.local_addr().unwrap().port();
+        let mut client = open(None).await;
+        client.connect((Ipv4Addr::LOCALHOST, port)).await.unwrap();
+        let mut buf = [0; 64];
+        let mut events = EventSink::new(None);
+        let connect = send_connect(&mut client, &mut buf, TYPE_SERVER, 0, 0, Duration::from_millis(200), &mut events);
+        let respond = async {
+            let mut buf = [0; 64];
+            let (len, addr) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(unmap(addr.ip()), IpAddr::V4(Ipv4Addr::LOCALHOST));
+            respond_connect(&mut server, addr, &mut buf, len, TYPE_SERVER, 0, &mut EventSink::new(None)).await
+        };
+        let (connected, responded) = tokio::join!(connect, respond);
+        connected.unwrap();
+        responded.unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_is_own_socket() {
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let r = open_tunnel(Some(addr), Some(addr), IpMode::V4Only, &SocketOptions::default(), None, None).await;
+        assert!(matches!(r, Err(Error::RemoteLoopback(a)) if a == addr));
+    }
+
+    #[test]
+    fn own_addr() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(is_own_addr(addr("0.0.0.0:1000"), addr("127.0.0.1:1000")));
+        assert!(is_own_addr(addr("[::]:1000"), addr("[::ffff:127.0.0.1]:1000")));
+        assert!(is_own_addr(addr("10.0.0.1:1000"), addr("10.0.0.1:1000")));
+        assert!(!is_own_addr(addr("0.0.0.0:1000"), addr("127.0.0.1:1001")));
+        assert!(!is_own_addr(addr("10.0.0.1:1000"), addr("127.0.0.1:1000")));
+    }
+
+    const NOW: u64 = 1_000_000;
+
+    // a connect signed with "key" at `time`
+    async fn signed_connect(time: u64) -> (TunnelSession, Vec<u8>) {
+        let mut client = open(Some(b"key")).await;
+        let mut buf = vec![PACKET_CONNECT; 64];
+        let len = client.sign_connect(&mut buf, time);
+        buf.truncate(len);
+        (client, buf)
+    }
+
+    #[tokio::test]
+    async fn unsigned_connect() {
+        let mut server = open(Some(b"key")).await;
+        assert!(matches!(server.verify_connect(&[PACKET_CONNECT, 0], NOW), Err(Error::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn replayed_connect() {
+        let (_, connect) = signed_connect(NOW).await;
+        let mut server = open(Some(b"key")).await;
+        server.verify_connect(&connect, NOW).unwrap();
+        assert!(matches!(server.verify_connect(&connect, NOW + 1), Err(Error::AuthFailed)));
+        // still after everything else was forgotten
+        assert!(matches!(server.verify_connect(&connect, NOW + CONNECT_MAX_AGE + 1), Err(Error::AuthFailed)));
+        assert!(server.seen_nonces.is_empty());
+    }
+
+    // a restarted server doesn't remember any nonces
+    #[tokio::test]
+    async fn old_connect() {
+        let (_, connect) = signed_connect(NOW - CONNECT_MAX_AGE - 1).await;
+        assert!(matches!(open(Some(b"key")).await.verify_connect(&connect, NOW), Err(Error::AuthFailed)));
+        let (_, connect) = signed_connect(NOW - CONNECT_MAX_AGE).await;
+        open(Some(b"key")).await.verify_connect(&connect, NOW).unwrap();
+    }
+
+    #[tokio::test]
+    async fn connect_from_the_future() {
+        let (_, connect) = signed_connect(NOW + CONNECT_MAX_AGE + 1).await;
+        assert!(matches!(open(Some(b"key")).await.verify_connect(&connect, NOW), Err(Error::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn tampered_connect() {
+        let (_, mut connect) = signed_connect(NOW).await;
+        // moving the timestamp breaks the tag
+        connect[2 + TIMESTAMP_LEN - 1] ^= 1;
+        assert!(matches!(open(Some(b"key")).await.verify_connect(&connect, NOW), Err(Error::AuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn signed_ack() {
+        let (client, connect) = signed_connect(NOW).await;
+        let mut server = open(Some(b"key")).await;
+        let nonce = server.verify_connect(&connect, NOW).unwrap();
+        let mut ack = [0; 64];
+        ack[..4].copy_from_slice(&[PACKET_CONN_ACK, TYPE_SERVER, PROTO_VERSION, 0]);
+        let len = server.sign_ack(&mut ack, 4, nonce);
+        assert_eq!(client.verify_ack(&ack[..len]).unwrap(), 4);
+        // without a tag, or one for another connect
+        assert!(matches!(client.verify_ack(&ack[..4]), Err(Error::AckAuthFailed)));
+        let (other, _) = signed_connect(NOW).await;
+        assert!(matches!(other.verify_ack(&ack[..len]), Err(Error::AckAuthFailed)));
+    }
+
+    #[tokio::test]
+    async fn unsigned_ack() {
+        let mut client = open(Some(b"key")).await;
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        let answer = async {
+            let mut buf = [0; 64];
+            let (_, addr) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(&[PACKET_CONN_ACK, TYPE_SERVER, PROTO_VERSION, 0], &addr).await.unwrap();
+        };
+        let mut buf = [0; 64];
+        let mut events = EventSink::new(None);
+        let connect = send_connect(&mut client, &mut buf, TYPE_SERVER, 0, 0, Duration::from_millis(200), &mut events);
+        let (r, _) = tokio::join!(connect, answer);
+        assert!(matches!(r, Err(Error::AckAuthFailed)));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,48 @@
+use std::{fs, io};
+use std::path::Path;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::common::IpMode;
+use crate::sourcefmt::SourceFormat;
+
+// Settings that can be given in the --config file as well as on the command
+// line. The command line takes precedence. Only the source format, targets
+// and format are re-read on SIGHUP, the rest is read once at startup.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub source_format: Option<String>,
+    pub target: Option<Vec<String>>,
+    pub format: Option<String>,
+    pub entry: Option<Vec<String>>,
+    pub remote: Option<String>,
+    pub listen: Option<String>,
+    pub timeout: Option<i64>,
+    pub bufsize: Option<usize>,
+    pub ip_mode: Option<IpMode>,
+}
+
+// Config file merged with the command line
+#[derive(Debug, Clone)]
+pub struct Reloadable {
+    pub source_format: Option<SourceFormat>,
+    pub targets: Vec<String>,
+    pub format: Option<String>,
+}
+
+pub type Reload<'a> = Box<dyn Fn() -> Result<Reloadable, String> + 'a>;
+
+pub fn load(path: &Path) -> Result<Config, Error> {
+    let s = fs::read_to_string(path).map_err(Error::Read)?;
+    toml::from_str(&s).map_err(Error::Parse)
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to read config file: {0}")]
+    Read(io::Error),
+    #[error("failed to parse config file: {0}")]
+    Parse(toml::de::Error),
+}
\ No newline at end of file
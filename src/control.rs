@@ -0,0 +1,239 @@
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::fs::Permissions;
+use std::fmt::Display;
+use std::future;
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+
+use chrono::Local;
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::stats::{ConnectionInfo, Counters, Stats, TargetStats};
+
+// Line based protocol, every command gets a response terminated by an empty
+// line.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Command {
+    List,
+    Kill(String),
+    Drain,
+    Stats,
+    // relative priority when polling the connection's socket
+    Weight(String, u32),
+    // moves a connection to a new client address
+    Rebind(String, String),
+}
+
+impl FromStr for Command {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let command = match (parts.next(), parts.next()) {
+            (Some("list"), None) => Command::List,
+            (Some("kill"), Some(id)) => Command::Kill(id.to_string()),
+            (Some("drain"), None) => Command::Drain,
+            (Some("stats"), None) => Command::Stats,
+            (Some("rebind"), Some(id)) => Command::Rebind(id.to_string(), parts.next().ok_or(())?.to_string()),
+            (Some("weight"), Some(id)) => {
+                let weight = parts.next().and_then(|w| w.parse().ok()).ok_or(())?;
+                Command::Weight(id.to_string(), weight)
+            }
+            _ => return Err(()),
+        };
+        match parts.next() {
+            None => Ok(command),
+            Some(_) => Err(()),
+        }
+    }
+}
+
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ControlAddr {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for ControlAddr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = s.strip_prefix("unix:") {
+                return Ok(ControlAddr::Unix(path.into()));
+            }
+        }
+        match s.strip_prefix("tcp:") {
+            Some(addr) => addr.parse().map(ControlAddr::Tcp).map_err(|_| ()),
+            None => Err(()),
+        }
+    }
+}
+
+pub enum Listener {
+    #[cfg(unix)]
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+async fn bind(addr: &ControlAddr) -> io::Result<Listener> {
+    match addr {
+        #[cfg(unix)]
+        ControlAddr::Unix(path) => {
+            let listener = UnixListener::bind(path)?;
+            fs::set_permissions(path, Permissions::from_mode(0o600))?;
+            Ok(Listener::Unix(listener))
+        }
+        ControlAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+    }
+}
+
+async fn serve(mut listener: Listener, tx: mpsc::Sender<Request>) {
+    loop {
+        let r = match &mut listener {
+            #[cfg(unix)]
+            Listener::Unix(l) => l.accept().await.map(|(s, _)| { tokio::spawn(handle(s, tx.clone())); }),
+            Listener::Tcp(l) => l.accept().await.map(|(s, _)| { tokio::spawn(handle(s, tx.clone())); }),
+        };
+        if let Err(e) = r {
+            error!("failed to accept control connection: {}", e);
+        }
+    }
+}
+
+async fn handle<S>(stream: S, mut tx: mpsc::Sender<Request>) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let (r, mut w) = tokio::io::split(stream);
+    let mut r = BufReader::new(r);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if r.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        let reply = match line.trim().parse() {
+            Ok(command) => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                if tx.send(Request { command, reply: reply_tx }).await.is_err() {
+                    return Ok(());
+                }
+                reply_rx.await.unwrap_or_else(|_| "error: tunnel stopped\n".to_string())
+            }
+            Err(_) => "error: unknown command\n".to_string(),
+        };
+        w.write_all(reply.as_bytes()).await?;
+        w.write_all(b"\n").await?;
+    }
+}
+
+pub async fn recv(rx: &mut Option<mpsc::Receiver<Request>>) -> Option<Request> {
+    match rx {
+        Some(rx) => rx.recv().await,
+        None => future::pending().await,
+    }
+}
+
+pub async fn start(addr: Option<ControlAddr>) -> io::Result<Option<mpsc::Receiver<Request>>> {
+    match addr {
+        Some(addr) => {
+            let listener = bind(&addr).await?;
+            let (tx, rx) = mpsc::channel(16);
+            tokio::spawn(serve(listener, tx));
+            Ok(Some(rx))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn format_kill<T: Display>(id: &str, removed: Option<T>) -> String {
+    match removed {
+        Some(id) => format!("killed {}\n", id),
+        None => format!("error: no connection {}\n", id),
+    }
+}
+
+pub fn format_weight(id: &str, weight: u32, found: bool) -> String {
+    if found {
+        format!("set weight of {} to {}\n", id, weight)
+    } else {
+        format!("error: no connection {}\n", id)
+    }
+}
+
+pub fn format_rebind<E: Display>(id: &str, addr: &str, result: Result<SocketAddr, E>) -> String {
+    match result {
+        Ok(old) => format!("moved {} from {} to {}\n", id, old, addr),
+        Err(e) => format!("error: {}\n", e),
+    }
+}
+
+pub fn format_list(conns: &[ConnectionInfo]) -> String {
+    let now = Local::now();
+    let mut s = String::new();
+    for c in conns {
+        s.push_str(&format!(
+            "{}\t{}\t{}\t{}\tage {}s\tidle {}s\tin {}/{}B\tout {}/{}B{}\n",
+            c.cid,
+            c.peer,
+            c.local.map_or("-".to_string(), |a| a.to_string()),
+            c.target.map_or("-".to_string(), |a| a.to_string()),
+            now.signed_duration_since(c.created).num_seconds(),
+            now.signed_duration_since(c.last_access).num_seconds(),
+            c.stats.packets_in, c.stats.bytes_in,
+            c.stats.packets_out, c.stats.bytes_out,
+            c.jitter.map_or(String::new(), |j| format!("\tjitter {:.2}ms", j)),
+        ));
+    }
+    s
+}
+
+// Bump the version when fields get removed or change their meaning, adding
+// fields is fine.
+const STATS_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct StatsDocument {
+    version: u32,
+    draining: bool,
+    counters: Counters,
+    targets: Vec<TargetEntry>,
+}
+
+#[derive(Serialize)]
+struct TargetEntry {
+    address: SocketAddr,
+    #[serde(flatten)]
+    stats: TargetStats,
+}
+
+pub fn format_stats(stats: &Stats) -> String {
+    let doc = StatsDocument {
+        version: STATS_VERSION,
+        draining: stats.draining.load(Ordering::Relaxed),
+        counters: stats.counters(),
+        targets: stats.target_counters().into_iter().map(|(address, stats)| TargetEntry { address, stats }).collect(),
+    };
+    let mut s = serde_json::to_string(&doc).expect("failed to serialize stats");
+    s.push('\n');
+    s
+}
\ No newline at end of file
@@ -0,0 +1,54 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc::Sender;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TunnelEvent {
+    ConnectionOpened { cid: u16, peer: SocketAddr },
+    ConnectionClosed { cid: u16, peer: SocketAddr, reason: CloseReason },
+    HandshakeCompleted { remote: SocketAddr },
+    HandshakeRejected { remote: SocketAddr, reason: String },
+    SendError { cid: u16, error: String },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CloseReason {
+    Timeout,
+    Killed,
+    // the application on this end refused packets for the connection
+    Refused,
+    // the other end of the tunnel closed it
+    Remote,
+    // made room for a new connection when the connection limit was reached
+    Evicted,
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CloseReason::Timeout => f.write_str("timeout"),
+            CloseReason::Killed => f.write_str("killed"),
+            CloseReason::Refused => f.write_str("refused"),
+            CloseReason::Remote => f.write_str("closed by remote"),
+            CloseReason::Evicted => f.write_str("evicted"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EventSink(Option<Sender<TunnelEvent>>);
+
+impl EventSink {
+    pub fn new(tx: Option<Sender<TunnelEvent>>) -> Self {
+        EventSink(tx)
+    }
+
+    // never waits for the receiver, events are dropped if the channel is full
+    pub fn emit(&mut self, event: TunnelEvent) {
+        if let Some(tx) = &mut self.0 {
+            let _ = tx.try_send(event);
+        }
+    }
+}
\ No newline at end of file
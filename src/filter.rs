@@ -0,0 +1,73 @@
+use std::str;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::common::DataDirection;
+
+pub struct Packet<'a> {
+    pub direction: DataDirection,
+    pub cid: u16,
+    pub payload: &'a [u8],
+}
+
+// Decides whether a data packet gets forwarded. Outbound packets are checked
+// before the middleware runs, inbound ones after, so filters always see the
+// application payload.
+pub trait Filter: Send + Sync {
+    fn accept(&self, packet: &Packet) -> bool;
+}
+
+impl<F> Filter for F
+    where F: Fn(&Packet) -> bool + Send + Sync {
+    fn accept(&self, packet: &Packet) -> bool {
+        self(packet)
+    }
+}
+
+pub fn accepts(filter: &Option<Box<dyn Filter>>, packet: &Packet) -> bool {
+    filter.as_ref().is_none_or(|f| f.accept(packet))
+}
+
+pub struct All(pub Vec<Box<dyn Filter>>);
+
+impl Filter for All {
+    fn accept(&self, packet: &Packet) -> bool {
+        self.0.iter().all(|f| f.accept(packet))
+    }
+}
+
+pub struct MaxSize(pub usize);
+
+impl Filter for MaxSize {
+    fn accept(&self, packet: &Packet) -> bool {
+        packet.payload.len() <= self.0
+    }
+}
+
+pub struct Magic(pub Vec<u8>);
+
+impl Filter for Magic {
+    fn accept(&self, packet: &Packet) -> bool {
+        packet.payload.starts_with(&self.0)
+    }
+}
+
+impl FromStr for Magic {
+    type Err = InvalidHex;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_start_matches("0x");
+        if s.is_empty() || !s.len().is_multiple_of(2) || !s.is_ascii() {
+            return Err(InvalidHex);
+        }
+        let bytes = s.as_bytes().chunks(2)
+            .map(|c| u8::from_str_radix(str::from_utf8(c).unwrap(), 16).map_err(|_| InvalidHex))
+            .collect::<Result<_, _>>()?;
+        Ok(Magic(bytes))
+    }
+}
+
+#[derive(Error, Debug, Copy, Clone)]
+#[error("invalid hex string")]
+pub struct InvalidHex;
\ No newline at end of file
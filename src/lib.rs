@@ -0,0 +1,55 @@
+#[macro_use]
+pub mod log;
+pub mod logfile;
+pub mod server;
+pub mod client;
+pub mod common;
+pub mod cache;
+mod server_cache;
+mod server_task;
+pub mod sourcefmt;
+pub mod output;
+pub mod middleware;
+pub mod stats;
+pub mod event;
+pub mod filter;
+pub mod control;
+mod signal;
+pub mod config;
+pub mod statsd;
+pub mod prometheus;
+pub mod sockopt;
+pub mod syslog;
+
+pub use cache::IdAlloc;
+pub use client::{ClientParams, start_client, start_client_until};
+pub use common::{Error, Format, IpMode};
+pub use server::{HashKey, ServerParams, TargetSelect, start_server, start_server_until};
+
+pub mod proto {
+    // 0x02: connection IDs are 16 bit
+    pub const PROTO_VERSION: u8 = 0x02;
+
+    pub const PACKET_CONNECT: u8 = 0x00;
+    pub const PACKET_CONN_ACK: u8 = 0x01;
+    pub const PACKET_KEEPALIVE: u8 = 0x02;
+    // second byte of a keepalive that wants a keepalive back
+    pub const KEEPALIVE_PROBE: u8 = 0x01;
+    pub const PACKET_CLOSE: u8 = 0x03;
+    pub const PACKET_DATA: u8 = 0x10;
+    // data packets, each behind its length as u16, with FEATURE_COALESCE
+    pub const PACKET_COALESCED: u8 = 0x11;
+    // type and connection ID
+    pub const DATA_HEADER_LEN: usize = 3;
+
+    pub const FEATURE_PADDING: u8 = 0x01;
+    pub const FEATURE_CHECKSUM: u8 = 0x02;
+    pub const FEATURE_HMAC: u8 = 0x04;
+    pub const FEATURE_REPLAY: u8 = 0x08;
+    pub const FEATURE_ENCRYPT: u8 = 0x10;
+    pub const FEATURE_COALESCE: u8 = 0x20;
+    pub const FEATURE_COMPRESS: u8 = 0x40;
+
+    pub const TYPE_SERVER: u8 = 0x00;
+    pub const TYPE_CLIENT: u8 = 0x01;
+}
\ No newline at end of file
@@ -0,0 +1,172 @@
+use std::fmt::Arguments;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::logfile::LogFile;
+use crate::syslog::{Severity, Syslog};
+
+// for the exported macros, so users of the library don't need tracing as a
+// direct dependency
+#[cfg(feature = "structured-logging")]
+pub use tracing;
+
+// Logging goes through these macros so it can be routed to the tracing crate
+// when built with the structured-logging feature. Without it, informational
+// lines go to stdout and everything else to stderr, like before, unless
+// syslog output is enabled. The data log is printed separately through
+// `data`, and with tracing it becomes debug events.
+//
+// The macros are exported so the binary logs through the same sinks.
+
+#[cfg(feature = "structured-logging")]
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::tracing::info!($($arg)*) };
+}
+
+#[cfg(not(feature = "structured-logging"))]
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::write($crate::syslog::Severity::Info, format_args!($($arg)*)) };
+}
+
+#[cfg(feature = "structured-logging")]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log::tracing::warn!($($arg)*) };
+}
+
+#[cfg(not(feature = "structured-logging"))]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log::write($crate::syslog::Severity::Warning, format_args!($($arg)*)) };
+}
+
+#[cfg(feature = "structured-logging")]
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::tracing::error!($($arg)*) };
+}
+
+#[cfg(not(feature = "structured-logging"))]
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::write($crate::syslog::Severity::Error, format_args!($($arg)*)) };
+}
+
+// only reached when the matching verbosity was requested, so the plain
+// variant doesn't need to filter anything
+#[cfg(feature = "structured-logging")]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::tracing::debug!($($arg)*) };
+}
+
+#[cfg(not(feature = "structured-logging"))]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::write($crate::syslog::Severity::Debug, format_args!($($arg)*)) };
+}
+
+static VERBOSITY: AtomicU64 = AtomicU64::new(0);
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Whether lines meant for at least `level` times -v should be logged; never
+// with -q.
+pub fn verbose(level: u64) -> bool {
+    !QUIET.load(Ordering::Relaxed) && VERBOSITY.load(Ordering::Relaxed) >= level
+}
+
+fn set_level(verbosity: u64, quiet: bool) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+#[cfg(feature = "structured-logging")]
+pub fn init(verbosity: u64, quiet: bool) {
+    use tracing::Level;
+
+    set_level(verbosity, quiet);
+    // info lines like [connect] show by default, like in the plain build;
+    // debug lines are gated by verbosity where they are logged
+    let level = match verbosity {
+        _ if quiet => Level::ERROR,
+        0 => Level::INFO,
+        1 | 2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+#[cfg(not(feature = "structured-logging"))]
+pub fn init(verbosity: u64, quiet: bool) {
+    set_level(verbosity, quiet);
+}
+
+// Runs `f` inside a span for the connection, so every event logged from it
+// carries the connection ID and peer address.
+#[cfg(feature = "structured-logging")]
+pub fn in_connection<R>(cid: u16, peer: SocketAddr, f: impl FnOnce() -> R) -> R {
+    tracing::info_span!("connection", cid, %peer).in_scope(f)
+}
+
+#[cfg(not(feature = "structured-logging"))]
+pub fn in_connection<R>(_cid: u16, _peer: SocketAddr, f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+struct SyslogSink {
+    syslog: Syslog,
+    // tracing takes the data log with everything else
+    #[cfg_attr(feature = "structured-logging", allow(dead_code))]
+    data: bool,
+}
+
+static SYSLOG: OnceCell<SyslogSink> = OnceCell::new();
+
+// Sends all further diagnostics to syslog, and the data log as well if
+// `data` is set.
+pub fn set_syslog(syslog: Syslog, data: bool) {
+    let _ = SYSLOG.set(SyslogSink { syslog, data });
+}
+
+static FILE: OnceCell<LogFile> = OnceCell::new();
+
+// Writes everything that would go to stdout or stderr into this file
+// instead.
+pub fn set_log_file(file: LogFile) {
+    let _ = FILE.set(file);
+}
+
+#[cfg_attr(feature = "structured-logging", allow(dead_code))]
+pub fn write(severity: Severity, msg: Arguments) {
+    if QUIET.load(Ordering::Relaxed) && severity != Severity::Error {
+        return;
+    }
+    // nowhere left to report a failure to
+    match (SYSLOG.get(), FILE.get()) {
+        (Some(sink), _) => { let _ = sink.syslog.send(severity, msg); }
+        (None, Some(file)) => { let _ = file.write_line(&msg.to_string()); }
+        (None, None) if severity == Severity::Info => println!("{}", msg),
+        (None, None) => eprintln!("{}", msg),
+    }
+}
+
+#[cfg(feature = "structured-logging")]
+pub fn data(msg: Arguments) {
+    tracing::debug!(target: "udptun::data", "{}", msg);
+}
+
+#[cfg(not(feature = "structured-logging"))]
+pub fn data(msg: Arguments) {
+    match (SYSLOG.get(), FILE.get()) {
+        (Some(sink), _) if sink.data => { let _ = sink.syslog.send(Severity::Info, msg); }
+        (_, Some(file)) => { let _ = file.write_line(&msg.to_string()); }
+        _ => println!("{}", msg),
+    }
+}
\ No newline at end of file
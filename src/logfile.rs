@@ -0,0 +1,202 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Local, Timelike};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Rotate {
+    Hourly,
+    Daily,
+}
+
+impl Rotate {
+    fn period_start(self, t: DateTime<Local>) -> DateTime<Local> {
+        let t = t.with_nanosecond(0).unwrap().with_second(0).unwrap().with_minute(0).unwrap();
+        match self {
+            Rotate::Hourly => t,
+            // 0 o'clock always exists in local time, at least outside of
+            // very exotic DST rules
+            Rotate::Daily => t.with_hour(0).unwrap_or(t),
+        }
+    }
+}
+
+impl FromStr for Rotate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hourly" => Ok(Rotate::Hourly),
+            "daily" => Ok(Rotate::Daily),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Current {
+    file: File,
+    size: u64,
+    period: Option<DateTime<Local>>,
+}
+
+// A log file that moves itself aside to `<path>.<timestamp>` and starts over
+// once it gets too large or a new rotation period begins. Rotation happens
+// under the same lock as writing, so no lines get lost in between.
+pub struct LogFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    rotate: Option<Rotate>,
+    current: Mutex<Current>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, max_size: Option<u64>, rotate: Option<Rotate>) -> io::Result<Self> {
+        let current = Mutex::new(open_current(path, rotate)?);
+        Ok(LogFile { path: path.to_path_buf(), max_size, rotate, current })
+    }
+
+    pub fn write_line(&self, line: &str) -> io::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        // if rotating fails, keep writing to the old file rather than losing
+        // the line
+        let rotated = if self.needs_rotation(&current, line.len() as u64 + 1) {
+            self.rotate(&mut current)
+        } else {
+            Ok(())
+        };
+        writeln!(current.file, "{}", line)?;
+        current.size += line.len() as u64 + 1;
+        rotated
+    }
+
+    fn needs_rotation(&self, current: &Current, incoming: u64) -> bool {
+        // an empty file can't get any smaller by rotating it
+        let too_large = self.max_size.is_some_and(|max| current.size > 0 && current.size + incoming > max);
+        let new_period = match (self.rotate, current.period) {
+            (Some(r), Some(p)) => r.period_start(Local::now()) != p,
+            _ => false,
+        };
+        too_large || new_period
+    }
+
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        current.file.flush()?;
+        std::fs::rename(&self.path, self.rotated_path())?;
+        *current = open_current(&self.path, self.rotate)?;
+        Ok(())
+    }
+
+    // Another rotation in the same second gets a counter behind the
+    // timestamp, renaming onto the earlier file would replace it.
+    fn rotated_path(&self) -> PathBuf {
+        let mut base = self.path.clone().into_os_string();
+        base.push(format!(".{}", Local::now().format("%Y%m%d-%H%M%S")));
+        let mut rotated = PathBuf::from(&base);
+        for n in 1.. {
+            if !rotated.exists() {
+                break;
+            }
+            let mut name = base.clone();
+            name.push(format!(".{}", n));
+            rotated = name.into();
+        }
+        rotated
+    }
+}
+
+fn open_current(path: &Path, rotate: Option<Rotate>) -> io::Result<Current> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    let period = rotate.map(|r| r.period_start(Local::now()));
+    Ok(Current { file, size, period })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    // A path in the temporary directory, removed together with everything
+    // rotated from it
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new(name: &str) -> Self {
+            TempLog(std::env::temp_dir().join(format!("udptun-{}-{}.log", std::process::id(), name)))
+        }
+
+        // the log file and its rotated copies
+        fn files(&self) -> Vec<PathBuf> {
+            let name = self.0.file_name().unwrap().to_str().unwrap();
+            fs::read_dir(self.0.parent().unwrap()).unwrap()
+                .map(|e| e.unwrap().path())
+                .filter(|p| p.file_name().unwrap().to_str().unwrap().starts_with(name))
+                .collect()
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            for file in self.files() {
+                let _ = fs::remove_file(file);
+            }
+        }
+    }
+
+    #[test]
+    fn period_start() {
+        let t = Local.with_ymd_and_hms(2020, 3, 4, 13, 45, 12).unwrap();
+        assert_eq!(Rotate::Hourly.period_start(t), Local.with_ymd_and_hms(2020, 3, 4, 13, 0, 0).unwrap());
+        assert_eq!(Rotate::Daily.period_start(t), Local.with_ymd_and_hms(2020, 3, 4, 0, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn rotates_when_too_large() {
+        let tmp = TempLog::new("size");
+        let log = LogFile::open(&tmp.0, Some(10), None).unwrap();
+        log.write_line("12345").unwrap();
+        log.write_line("123").unwrap();
+        assert_eq!(tmp.files().len(), 1);
+        // 10 bytes with this one
+        log.write_line("1").unwrap();
+        assert_eq!(tmp.files().len(), 2);
+        assert_eq!(fs::read_to_string(&tmp.0).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn rotates_twice_in_a_second() {
+        let tmp = TempLog::new("twice");
+        let log = LogFile::open(&tmp.0, Some(10), None).unwrap();
+        for line in &["first", "second", "third"] {
+            log.write_line(line).unwrap();
+        }
+        let mut lines: Vec<_> = tmp.files().iter().map(|f| fs::read_to_string(f).unwrap()).collect();
+        lines.sort();
+        assert_eq!(lines, ["first\n", "second\n", "third\n"]);
+    }
+
+    #[test]
+    fn long_line_into_empty_file() {
+        let tmp = TempLog::new("long");
+        let log = LogFile::open(&tmp.0, Some(10), None).unwrap();
+        log.write_line("a line longer than the limit").unwrap();
+        assert_eq!(tmp.files().len(), 1);
+    }
+
+    #[test]
+    fn rotates_in_new_period() {
+        let tmp = TempLog::new("period");
+        let log = LogFile::open(&tmp.0, None, Some(Rotate::Hourly)).unwrap();
+        let mut current = log.current.lock().unwrap();
+        assert!(!log.needs_rotation(&current, 1));
+        current.period = current.period.map(|p| p - Duration::hours(1));
+        assert!(log.needs_rotation(&current, 1));
+    }
+}
\ No newline at end of file
@@ -1,75 +1,451 @@
-use chrono::Duration;
-use clap::{app_from_crate, Arg};
-
-use crate::client::ClientParams;
-use crate::common::{Format, IpMode};
-use crate::server::ServerParams;
-
-mod server;
-mod client;
-mod common;
-mod cache;
-mod server_cache;
-mod sourcefmt;
-mod output;
+use std::net::SocketAddr;
+use std::num::ParseIntError;
+use std::path::PathBuf;
+use std::sync::Arc;
 
-mod proto {
-  pub const PROTO_VERSION: u8 = 0x01;
+use chrono::Duration;
+use chrono::format::{Item, StrftimeItems};
+use clap::{app_from_crate, Arg, ArgGroup, ArgMatches};
 
-  pub const PACKET_CONNECT: u8 = 0x00;
-  pub const PACKET_CONN_ACK: u8 = 0x01;
-  pub const PACKET_DATA: u8 = 0x10;
-
-  pub const TYPE_SERVER: u8 = 0x00;
-  pub const TYPE_CLIENT: u8 = 0x01;
-}
+use udptun::{client, ClientParams, config, Format, IpMode, log, output, prometheus, server, ServerParams, statsd, warn};
+use udptun::config::{Config, Reload, Reloadable};
+use udptun::filter::{All, Filter, Magic, MaxSize};
+use udptun::logfile::LogFile;
+use udptun::middleware::{Authenticate, Chain, Checksum, Compress, Encrypt, Middleware, Padding, Replay};
+use udptun::sockopt::{ListenInterface, SocketOptions};
+use udptun::stats::Stats;
+use udptun::syslog::Syslog;
 
 #[tokio::main]
 async fn main() {
   let matches = app_from_crate!()
-    .arg(Arg::with_name("target").short('T').long("target").value_name("ADDRESS").about("Specifies that this is the end of the tunnel the actual server is at; the specified address is the one of the actual server to proxy").conflicts_with("entry"))
-    .arg(Arg::with_name("entry").short('E').long("entry").value_name("ADDRESS").about("Specifies that this is the tunnel entry point; the specified address is the one clients connect to"))
+    .arg(Arg::with_name("target").short('T').long("target").value_name("ADDRESS").about("Specifies that this is the end of the tunnel the actual server is at; the specified address is the one of the actual server to proxy; can be given multiple times").multiple_occurrences(true).conflicts_with("entry"))
+    .arg(Arg::with_name("entry").short('E').long("entry").value_name("ADDRESS").about("Specifies that this is the tunnel entry point; the specified address is the one clients connect to; can be given multiple times to accept clients on several addresses, and HOST:FIRST-LAST listens on every port of the range").multiple_occurrences(true))
     .arg(Arg::with_name("timeout").short('x').long("timeout").default_value("3600").value_name("SECS").about("Time in seconds after the last received packet after which a connection is determined closed"))
     .arg(Arg::with_name("bufsize").short('b').long("bufsize").default_value("65536").value_name("SIZE").about("Packet buffer size, if smaller than packets sent they will get truncated"))
-    .arg(Arg::with_name("listen").short('l').long("listen").value_name("ADDRESS").about("The address/port to use for communication inside the tunnel").required_unless("remote"))
-    .arg(Arg::with_name("remote").short('r').long("remote").value_name("ADDRESS").about("Specifies the address of the other end of the tunnel").required_unless("listen"))
-    .arg(Arg::with_name("source-format").long("source-format").value_name("ADDRESS-FMT").about("Specifies the IP address range for created dummy client sockets").requires("target"))
+    .arg(Arg::with_name("listen").short('l').long("listen").value_name("ADDRESS").about("The address/port to use for communication inside the tunnel"))
+    .arg(Arg::with_name("remote").short('r').long("remote").value_name("ADDRESS").about("Specifies the address of the other end of the tunnel; with a comma-separated list, the entry end fails over to the next one when the current one stops answering"))
+    .arg(Arg::with_name("source-format").long("source-format").value_name("ADDRESS-FMT").about("Specifies the IP address range for created dummy client sockets; multiple ranges can be given separated by ',', each with an optional '@WEIGHT' suffix").requires("target"))
+    .arg(Arg::with_name("source-alloc").long("source-alloc").value_name("MODE").possible_values(&["random", "sticky", "sequential"]).default_value("random").about("How addresses are picked from the source format; 'sticky' derives them from the connection so a client keeps its address, 'sequential' goes through them in order"))
+    .arg(Arg::with_name("target-select").long("target-select").value_name("MODE").possible_values(&["hash", "round-robin"]).default_value("hash").about("How a new connection picks one of several targets; 'hash' keeps a client on the same target, 'round-robin' takes them in turn"))
+    .arg(Arg::with_name("hash-key").long("hash-key").value_name("KEY").possible_values(&["connection", "peer"]).default_value("connection").about("What identifies a client when picking its target and sticky source address"))
+    .arg(Arg::with_name("id-alloc").long("id-alloc").value_name("STRATEGY").possible_values(&["lowest", "round-robin", "random", "shuffled"]).default_value("lowest").about("How connection IDs are assigned to new clients on the entry side; 'shuffled' hides the ID sequence on the wire but is no substitute for encryption"))
     .arg(Arg::with_name("ipv4").short('4').conflicts_with("ipv6").about("Exclusively use IPv4"))
     .arg(Arg::with_name("ipv6").short('6').about("Exclusively use IPv6"))
     .arg(Arg::with_name("log-data").short('L').long("log-data").about("Print a log line per data packet transferred"))
-    .arg(Arg::with_name("format").short('f').long("format").value_name("FORMAT").requires("log-data").about("Set the log line format"))
+    .arg(Arg::with_name("format").short('f').long("format").value_name("FORMAT").requires("log-data").about("Set the log line format; \\t, \\n and \\\\ insert a tab, a newline and a backslash, and a width like %10c or %-10c pads or cuts a column to that many characters, right or left aligned"))
+    .arg(Arg::with_name("format-json").long("format-json").requires("log-data").conflicts_with("format").about("Print the data log as one JSON object per packet instead of a formatted line"))
+    .arg(Arg::with_name("width-window").long("width-window").value_name("ROWS").about("Size the columns of the log line format by the last ROWS lines only, so they shrink again after a long value instead of keeping the widest width seen"))
+    .arg(Arg::with_name("timestamp-format").long("timestamp-format").value_name("FORMAT").default_value(output::DEFAULT_TIME_FORMAT).about("strftime pattern for the %T timestamp column of the log line format"))
+    .arg(Arg::with_name("color").long("color").value_name("WHEN").possible_values(&["auto", "always", "never"]).default_value("auto").about("Color the direction and timestamp columns of the log line; 'auto' does so when printing to a terminal"))
     .arg(Arg::with_name("print-data-buffer").short('B').long("print-data-buffer").about("Print the contents of the data buffer for each packet transferred"))
-    .arg(Arg::with_name("verbose").short('v').long("verbose").about("Print more information").multiple_occurrences(true))
+    .arg(Arg::with_name("filter-magic").long("filter-magic").value_name("HEX").about("Only forward data packets whose payload starts with these bytes"))
+    .arg(Arg::with_name("filter-max-size").long("filter-max-size").value_name("SIZE").about("Only forward data packets with a payload of at most this many bytes"))
+    .arg(Arg::with_name("control").long("control").value_name("ADDRESS").about("Serve a control socket on unix:PATH or tcp:ADDRESS for listing, killing, weighting and rebinding connections and reading stats as JSON"))
+    .arg(Arg::with_name("cleanup-interval").long("cleanup-interval").value_name("SECS").about("Time in seconds between looking for and removing timed out connections; defaults to a quarter of the timeout"))
+    .arg(Arg::with_name("drain-timeout").long("drain-timeout").value_name("SECS").default_value("300").about("Time in seconds to wait for connections to finish after a drain was requested (SIGUSR2 or control socket)"))
+    .arg(Arg::with_name("config").short('c').long("config").value_name("FILE").about("Read settings from this TOML file; command line options take precedence, and source format, targets and log format are re-read on SIGHUP"))
+    .arg(Arg::with_name("metrics-addr").long("metrics-addr").value_name("ADDRESS").about("Serve counters and gauges for Prometheus over HTTP at /metrics on this address"))
+    .arg(Arg::with_name("statsd").long("statsd").value_name("ADDRESS").about("Push counters and gauges to the StatsD server at this address"))
+    .arg(Arg::with_name("statsd-prefix").long("statsd-prefix").value_name("PREFIX").default_value("udptun").about("Prefix for StatsD metric names"))
+    .arg(Arg::with_name("statsd-interval").long("statsd-interval").value_name("SECS").default_value("10").about("Time in seconds between pushes to the StatsD server"))
+    .arg(Arg::with_name("jitter").long("jitter").about("Estimate the jitter of packets coming out of the tunnel per connection, available as the %j log column and in the control socket listing"))
+    .arg(Arg::with_name("compress").long("compress").about("LZ4-compress data packets, leaving those that wouldn't get smaller as they are; both ends of the tunnel need the same setting"))
+    .arg(Arg::with_name("pad").long("pad").value_name("MAX").about("Pad data packets with up to this many random bytes to obscure their length; both ends of the tunnel need the same setting"))
+    .arg(Arg::with_name("checksum").long("checksum").about("Append a CRC32 to data packets and drop packets that arrive corrupted; both ends of the tunnel need the same setting"))
+    .arg(Arg::with_name("auth-key").long("auth-key").value_name("KEY").about("Shared secret for authenticating tunnel traffic; the handshake is signed with it and connect packets without a valid signature are dropped without an answer; both ends of the tunnel need the same key and clocks at most 30 seconds apart"))
+    .arg(Arg::with_name("hmac").long("hmac").requires("auth-key").about("Append an HMAC tag to data and close packets and drop packets that fail authentication; both ends of the tunnel need the same setting"))
+    .arg(Arg::with_name("replay-window").long("replay-window").value_name("SIZE").requires("keyed").about("Number the data packets and drop duplicates and packets more than SIZE behind the newest one; needs --hmac or --encrypt, which protect the numbers; both ends of the tunnel need the same setting"))
+    .arg(Arg::with_name("encrypt").long("encrypt").value_name("KEY").about("Encrypt data and close packets with ChaCha20-Poly1305 using a key derived from KEY and drop packets that fail to decrypt; both ends of the tunnel need the same key"))
+    // the layers that can tell a forged sequence number, --replay-window needs one
+    .group(ArgGroup::with_name("keyed").args(&["hmac", "encrypt"]).multiple(true))
+    .arg(Arg::with_name("no-encrypt").long("no-encrypt").about("INSECURE: skip all keyed layers (HMAC, replay protection, encryption) so tunnel traffic can be captured and inspected; both ends of the tunnel need the same setting"))
+    .arg(Arg::with_name("max-connections").long("max-connections").value_name("N").conflicts_with("target").about("Maximum number of simultaneous clients on the entry side; a new client beyond that replaces the one that was idle the longest"))
+    .arg(Arg::with_name("max-conns-per-source").long("max-conns-per-source").value_name("N").about("Maximum number of simultaneous connections from a single tunnel peer address; packets for further connections are dropped"))
+    .arg(Arg::with_name("target-batch").long("target-batch").value_name("N").about("Read at most N packets in a row from target sockets before the tunnel socket gets priority again; 0 always prefers the tunnel, by default sockets take turns"))
+    .arg(Arg::with_name("log-connections").long("log-connections").about("Print a log line when a connection is opened and one with its totals when it is closed"))
+    .arg(Arg::with_name("log-expiry").long("log-expiry").about("Print a log line when a connection is removed after being idle for longer than the timeout"))
+    .arg(Arg::with_name("list-columns").long("list-columns").about("Print the columns the log line format can use at the end given by -T/--target or -E/--entry, or at both, then exit"))
+    .arg(Arg::with_name("check").long("check").about("Check the options, bind all sockets and resolve all addresses, then exit without connecting"))
+    .arg(Arg::with_name("rcvbuf").long("rcvbuf").value_name("SIZE").about("Set the receive buffer size of all sockets (SO_RCVBUF)"))
+    .arg(Arg::with_name("sndbuf").long("sndbuf").value_name("SIZE").about("Set the send buffer size of all sockets (SO_SNDBUF)"))
+    .arg(Arg::with_name("dscp").long("dscp").value_name("DSCP").about("Mark packets sent from IPv4 sockets with this DSCP value; not supported on Windows"))
+    .arg(Arg::with_name("bind-device").long("bind-device").value_name("INTERFACE").about("Bind all sockets to this network interface (SO_BINDTODEVICE); Linux only"))
+    .arg(Arg::with_name("listen-interface").long("listen-interface").value_name("INTERFACE").about("Only accept tunnel traffic on this network interface, given by name (Linux only) or by one of its addresses; combines with the port from --listen"))
+    .arg(Arg::with_name("state-file").long("state-file").value_name("FILE").about("Keep the client's connection IDs in this file across restarts, so returning peers get the same ID; written on shutdown and read on startup"))
+    .arg(Arg::with_name("coalesce").long("coalesce").about("Pack data packets that are read at the same time into one tunnel datagram, most useful together with --batch; both ends of the tunnel need the same setting"))
+    .arg(Arg::with_name("strict-mtu").long("strict-mtu").about("Drop packets that fill the whole buffer and were probably truncated instead of forwarding them"))
+    .arg(Arg::with_name("grow-buffer").long("grow-buffer").value_name("SIZE").about("Grow the packet buffer to SIZE once the first time a packet fills it completely, instead of truncating all further large packets"))
+    .arg(Arg::with_name("bind-retries").long("bind-retries").value_name("COUNT").about("How many other source addresses to try when the one drawn from the source format is already in use").default_value("3"))
+    .arg(Arg::with_name("syslog").long("syslog").value_name("TARGET").about("Send log output to syslog instead of stdout/stderr; TARGET is either 'local' or the address of a remote syslog server (RFC 5424 over UDP)"))
+    .arg(Arg::with_name("syslog-facility").long("syslog-facility").value_name("FACILITY").about("Syslog facility to log with").default_value("daemon").possible_values(&["kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news", "uucp", "cron", "authpriv", "ftp", "local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7"]))
+    .arg(Arg::with_name("syslog-app-name").long("syslog-app-name").value_name("NAME").about("Application name to log to syslog with").default_value("udptun"))
+    .arg(Arg::with_name("syslog-data").long("syslog-data").about("Also send the data log to syslog").requires("syslog"))
+    .arg(Arg::with_name("log-file").long("log-file").value_name("FILE").about("Write log output to this file instead of stdout/stderr"))
+    .arg(Arg::with_name("log-max-size").long("log-max-size").value_name("BYTES").about("Move the log file aside to FILE.<timestamp> and start a new one when it would grow beyond this size").requires("log-file"))
+    .arg(Arg::with_name("log-rotate").long("log-rotate").value_name("PERIOD").possible_values(&["hourly", "daily"]).about("Move the log file aside to FILE.<timestamp> and start a new one every hour or day").requires("log-file"))
+    .arg(Arg::with_name("batch").long("batch").value_name("N").default_value("1").about("Read up to N datagrams with a single syscall once a socket has packets waiting; only has an effect on Linux"))
+    .arg(Arg::with_name("rng-seed").long("rng-seed").value_name("SEED").hidden(true).about("Seed for picking source addresses and connection IDs, for reproducible tests"))
+    .arg(Arg::with_name("tunnel-weight").long("tunnel-weight").value_name("WEIGHT").about("How strongly the tunnel socket is preferred over the other sockets when several have packets waiting, relative to the weight of 1 the others have by default; sockets that all have packets waiting take turns in proportion to their weights").default_value("1"))
+    .arg(Arg::with_name("keepalive").long("keepalive").value_name("SECS").about("Send a keepalive packet into the tunnel whenever nothing was sent for this many seconds, so NAT mappings don't expire; keepalives don't keep idle connections from timing out"))
+    .arg(Arg::with_name("connect-retries").long("connect-retries").value_name("COUNT").about("How often to resend the connect packet when the other end doesn't answer").default_value("5"))
+    .arg(Arg::with_name("connect-timeout").long("connect-timeout").value_name("SECS").about("Time in seconds to wait for an answer to the first connect packet, doubled for every retry").default_value("2"))
+    .arg(Arg::with_name("failover-timeout").long("failover-timeout").value_name("SECS").about("With several remotes, fail over to the next one once the current one hasn't answered for this many seconds").default_value("30"))
+    .arg(Arg::with_name("task-per-connection").long("task-per-connection").requires("target").conflicts_with_all(&["coalesce", "target-batch"]).about("Read every target socket in a task of its own that sends straight into the tunnel, which spreads the connections over all CPU cores; connection weights and --tunnel-weight have no effect then"))
+    .arg(Arg::with_name("verbose").short('v').long("verbose").about("Print more information; -v logs every packet or handshake rejected at the tunnel with its source and reason, -vv every socket created for a connection").multiple_occurrences(true))
+    .arg(Arg::with_name("quiet").short('q').long("quiet").conflicts_with("verbose").about("Print nothing but errors, the data log is still printed when asked for"))
     .get_matches();
 
-  let target = matches.value_of("target");
-  let entry = matches.value_of("entry");
-  let remote = matches.value_of("remote");
-  let timeout = Duration::minutes(matches.value_of("timeout").unwrap().parse().unwrap());
-  let bufsize = matches.value_of("bufsize").unwrap().parse().unwrap();
-  let listen = matches.value_of("listen");
-  let source_format = matches.value_of("source-format").map(|s| s.parse().unwrap());
+  let config_path = matches.value_of("config");
+  let mut config = config_path.map(|p| config::load(p.as_ref()).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    std::process::exit(1);
+  })).unwrap_or_default();
+  // the rest of the file is taken apart by merge_config, which also runs on reload
+  let entry = matches.values_of("entry").map(|v| v.map(str::to_string).collect::<Vec<_>>()).or(config.entry.take());
+  let entry = entry.map(|entry| entry.iter().flat_map(|e| client::expand_entry(e).unwrap_or_else(|err| {
+    eprintln!("invalid entry address {}: {}", e, err);
+    std::process::exit(1);
+  })).collect::<Vec<_>>());
+  if matches.is_present("list-columns") {
+    let target = matches.is_present("target") || config.target.is_some();
+    match (target, entry.is_some()) {
+      (true, false) => print!("{}", server::list_columns()),
+      (false, true) => print!("{}", client::list_columns()),
+      _ => print!("target end (-T):\n{}\nentry end (-E):\n{}", server::list_columns(), client::list_columns()),
+    }
+    return;
+  }
+  let remote = matches.value_of("remote").map(str::to_string).or(config.remote.take());
+  let listen = matches.value_of("listen").map(str::to_string).or(config.listen.take());
+  if listen.is_none() && remote.is_none() {
+    eprintln!("One of -l/--listen, -r/--remote is required!");
+    std::process::exit(1);
+  }
+  // the ones after the first are only used when it stops answering
+  let mut fallback_remotes = remote.as_deref().map_or(Vec::new(), |r| r.split(',').map(str::to_string).collect::<Vec<_>>());
+  let remote = if fallback_remotes.is_empty() { None } else { Some(fallback_remotes.remove(0)) };
+  if !fallback_remotes.is_empty() && listen.is_some() {
+    eprintln!("Several remotes can only be given when this end connects, not together with -l/--listen!");
+    std::process::exit(1);
+  }
+  // flags with a default value only win over the file when given explicitly
+  let timeout = match config.timeout.filter(|_| matches.occurrences_of("timeout") == 0) {
+    Some(secs) => Ok(Duration::seconds(secs)),
+    None => parse_timeout(matches.value_of("timeout").unwrap()),
+  };
+  let timeout = match timeout {
+    Ok(timeout) => timeout,
+    Err(_) => {
+      eprintln!("--timeout must be a whole number of seconds!");
+      std::process::exit(1);
+    }
+  };
+  let bufsize = match config.bufsize.filter(|_| matches.occurrences_of("bufsize") == 0) {
+    Some(size) => size,
+    None => matches.value_of("bufsize").unwrap().parse().unwrap(),
+  };
+  let ip_mode = if matches.is_present("ipv4") {
+    IpMode::V4Only
+  } else if matches.is_present("ipv6") {
+    IpMode::V6Only
+  } else {
+    config.ip_mode.unwrap_or(IpMode::Both)
+  };
+  let Reloadable { source_format, targets, format: log_format } = merge_config(&matches, config).unwrap_or_else(|e| {
+    eprintln!("{}", e);
+    std::process::exit(1);
+  });
+  let reload: Option<Reload> = config_path.map(|p| {
+    let matches = &matches;
+    Box::new(move || merge_config(matches, config::load(p.as_ref()).map_err(|e| e.to_string())?)) as Reload
+  });
+  let source_alloc = matches.value_of("source-alloc").unwrap().parse().unwrap();
+  let hash_key = matches.value_of("hash-key").unwrap().parse().unwrap();
+  let target_select = matches.value_of("target-select").unwrap().parse().unwrap();
+  let id_alloc = matches.value_of("id-alloc").unwrap().parse().unwrap();
   let verbosity = matches.occurrences_of("verbose");
-  let ip_mode = if matches.is_present("ipv4") { IpMode::V4Only } else if matches.is_present("ipv6") { IpMode::V6Only } else { IpMode::Both };
+  let quiet = matches.is_present("quiet");
+  log::init(verbosity, quiet);
+  if let Some(path) = matches.value_of("log-file") {
+    if cfg!(feature = "structured-logging") {
+      eprintln!("--log-file is not available when built with structured logging, use a tracing subscriber instead!");
+      std::process::exit(1);
+    }
+    let max_size = matches.value_of("log-max-size").map(|s| s.parse().unwrap());
+    let rotate = matches.value_of("log-rotate").map(|s| s.parse().unwrap());
+    match LogFile::open(path.as_ref(), max_size, rotate) {
+      Ok(file) => log::set_log_file(file),
+      Err(e) => {
+        eprintln!("Failed to open log file: {}", e);
+        std::process::exit(1);
+      }
+    }
+  }
+  if let Some(target) = matches.value_of("syslog") {
+    if cfg!(feature = "structured-logging") {
+      eprintln!("--syslog is not available when built with structured logging, use a tracing subscriber instead!");
+      std::process::exit(1);
+    }
+    let facility = matches.value_of("syslog-facility").unwrap().parse().unwrap();
+    let app_name = matches.value_of("syslog-app-name").unwrap();
+    match Syslog::connect(target, facility, app_name) {
+      Ok(syslog) => log::set_syslog(syslog, matches.is_present("syslog-data")),
+      Err(e) => {
+        eprintln!("Failed to connect to syslog: {}", e);
+        std::process::exit(1);
+      }
+    }
+  }
   let log_data = matches.is_present("log-data");
   let format = if log_data {
-    if let Some(s) = matches.value_of("format") {
+    if matches.is_present("format-json") {
+      Some(Format::Json)
+    } else if let Some(s) = &log_format {
       Some(Format::Custom(s))
     } else {
       Some(Format::Default)
     }
   } else { None };
+  let timestamp_format = matches.value_of("timestamp-format").unwrap();
+  if StrftimeItems::new(timestamp_format).any(|i| i == Item::Error) {
+    eprintln!("Invalid timestamp format {}!", timestamp_format);
+    std::process::exit(1);
+  }
+  output::set_time_format(timestamp_format.to_string());
+  match matches.value_of("width-window").map(|s| s.parse::<usize>()) {
+    Some(Ok(rows)) if rows > 0 => output::set_width_window(rows),
+    Some(_) => {
+      eprintln!("--width-window must be a positive number of rows!");
+      std::process::exit(1);
+    }
+    None => {}
+  }
+  output::set_color(match matches.value_of("color").unwrap() {
+    "always" => true,
+    "never" => false,
+    // the data log only goes to stdout without a log file or syslog
+    _ => atty::is(atty::Stream::Stdout) && !matches.is_present("log-file") && !matches.is_present("syslog-data"),
+  });
   let print_data_buffer = matches.is_present("print-data-buffer");
+  let jitter = matches.is_present("jitter");
+  let coalesce = matches.is_present("coalesce");
+  let log_connections = matches.is_present("log-connections");
+  let log_expiry = matches.is_present("log-expiry");
+  let check = matches.is_present("check");
+  let grow_buffer = matches.value_of("grow-buffer").map(|s| s.parse().unwrap());
+  let strict_mtu = matches.is_present("strict-mtu");
+  let connect_retries = matches.value_of("connect-retries").unwrap().parse().unwrap_or_else(|_| {
+    eprintln!("--connect-retries must be a whole number!");
+    std::process::exit(1);
+  });
+  let connect_timeout = match matches.value_of("connect-timeout").unwrap().parse::<u64>() {
+    Ok(secs) if secs > 0 => std::time::Duration::from_secs(secs),
+    _ => {
+      eprintln!("--connect-timeout must be a positive whole number of seconds!");
+      std::process::exit(1);
+    }
+  };
+  let failover_timeout = match matches.value_of("failover-timeout").unwrap().parse::<u64>() {
+    Ok(secs) if secs > 0 => Some(std::time::Duration::from_secs(secs)).filter(|_| !fallback_remotes.is_empty()),
+    _ => {
+      eprintln!("--failover-timeout must be a positive whole number of seconds!");
+      std::process::exit(1);
+    }
+  };
+  let keepalive = matches.value_of("keepalive").map(|s| std::time::Duration::from_secs(s.parse().unwrap()));
+  let psk = matches.value_of("auth-key").map(|k| k.as_bytes().to_vec());
+  let tunnel_weight = matches.value_of("tunnel-weight").unwrap().parse().unwrap();
+  let rng_seed = matches.value_of("rng-seed").map(|s| s.parse().unwrap_or_else(|_| {
+    eprintln!("--rng-seed must be a number!");
+    std::process::exit(1);
+  }));
+  let batch = match matches.value_of("batch").unwrap().parse() {
+    Ok(n) if n > 0 => n,
+    _ => {
+      eprintln!("--batch must be a positive number!");
+      std::process::exit(1);
+    }
+  };
+  let bind_retries = matches.value_of("bind-retries").unwrap().parse().unwrap();
+  let socket_options = SocketOptions {
+    recv_buffer: matches.value_of("rcvbuf").map(|s| s.parse().unwrap()),
+    send_buffer: matches.value_of("sndbuf").map(|s| s.parse().unwrap()),
+    dscp: matches.value_of("dscp").map(|s| s.parse().unwrap()),
+    device: matches.value_of("bind-device").map(str::to_string),
+    // the default differs between systems, a v6 socket in Both mode has to
+    // take v4-mapped addresses too
+    only_v6: match ip_mode {
+      IpMode::V6Only => Some(true),
+      IpMode::Both => Some(false),
+      IpMode::V4Only => None,
+    },
+  };
+  let listen_interface = matches.value_of("listen-interface").map(|s| s.parse::<ListenInterface>().unwrap_or_else(|e| {
+    eprintln!("invalid --listen-interface: {}", e);
+    std::process::exit(1);
+  }));
+  let state_file = matches.value_of("state-file").map(PathBuf::from);
+  let target_batch = matches.value_of("target-batch").map(|s| s.parse().unwrap());
+  let task_per_connection = matches.is_present("task-per-connection");
+  let max_connections = matches.value_of("max-connections").map(|s| s.parse().unwrap());
+  let max_conns_per_source = matches.value_of("max-conns-per-source").map(|s| s.parse().unwrap());
+  let control = matches.value_of("control").map(|s| s.parse().unwrap());
+  let drain_timeout = std::time::Duration::from_secs(matches.value_of("drain-timeout").unwrap().parse().unwrap());
+  let cleanup_interval = match matches.value_of("cleanup-interval").map(|s| s.parse::<u64>()) {
+    Some(Ok(secs)) if secs > 0 => std::time::Duration::from_secs(secs),
+    Some(_) => {
+      eprintln!("--cleanup-interval must be a positive whole number of seconds!");
+      std::process::exit(1);
+    }
+    None => std::time::Duration::from_secs((timeout.num_seconds() / 4).max(1) as u64),
+  };
+  let mut filters: Vec<Box<dyn Filter>> = Vec::new();
+  if let Some(magic) = matches.value_of("filter-magic") {
+    filters.push(Box::new(magic.parse::<Magic>().unwrap_or_else(|e| {
+      eprintln!("invalid --filter-magic {}: {}", magic, e);
+      std::process::exit(1);
+    })));
+  }
+  if let Some(size) = matches.value_of("filter-max-size") {
+    filters.push(Box::new(MaxSize(size.parse().unwrap())));
+  }
+  let filter: Option<Box<dyn Filter>> = if filters.is_empty() { None } else { Some(Box::new(All(filters))) };
+  let mut layers: Vec<Box<dyn Middleware>> = Vec::new();
+  // first, padding and encryption leave nothing to compress
+  if matches.is_present("compress") {
+    layers.push(Box::new(Compress::default()));
+  }
+  if let Some(max) = matches.value_of("pad") {
+    layers.push(Box::new(Padding::new(max.parse().unwrap())));
+  }
+  if matches.is_present("checksum") {
+    layers.push(Box::new(Checksum));
+  }
+  let no_encrypt = matches.is_present("no-encrypt");
+  if no_encrypt {
+    warn!("WARNING: --no-encrypt given, tunnel traffic is sent as plaintext without authentication, even though keys may be configured. Only use this for debugging!");
+  } else {
+    // numbered first, so the HMAC or the encryption covers the number
+    if let Some(size) = matches.value_of("replay-window") {
+      layers.push(Box::new(Replay::new(size.parse().unwrap())));
+    }
+    if matches.is_present("hmac") {
+      layers.push(Box::new(Authenticate::new(matches.value_of("auth-key").unwrap().as_bytes(), !targets.is_empty())));
+    }
+    if let Some(key) = matches.value_of("encrypt") {
+      layers.push(Box::new(Encrypt::new(key.as_bytes(), !targets.is_empty())));
+    }
+  }
+  let middleware: Option<Box<dyn Middleware>> = if layers.is_empty() { None } else { Some(Box::new(Chain(layers))) };
+  let stats = Arc::new(Stats::default());
+
+  if let Some(sf) = &source_format {
+    if !sf.allowed_in(ip_mode) {
+      eprintln!("The source format does not match the selected IP version!");
+      std::process::exit(1);
+    }
+  }
+  if let Some(t) = targets.iter().find(|t| t.parse().is_ok_and(|a: SocketAddr| !ip_mode.allows(&a))) {
+    eprintln!("Target {} does not match the selected IP version!", t);
+    std::process::exit(1);
+  }
+
+  if let Some(addr) = matches.value_of("statsd") {
+    let prefix = matches.value_of("statsd-prefix").unwrap().to_string();
+    let interval = std::time::Duration::from_secs(matches.value_of("statsd-interval").unwrap().parse().unwrap());
+    if let Err(e) = statsd::start(addr, prefix, interval, stats.clone()).await {
+      eprintln!("Failed to set up StatsD: {}", e);
+      std::process::exit(1);
+    }
+  }
+  if let Some(addr) = matches.value_of("metrics-addr") {
+    if let Err(e) = prometheus::start(addr, stats.clone()).await {
+      eprintln!("Failed to open metrics endpoint: {}", e);
+      std::process::exit(1);
+    }
+  }
 
-  if let Some(target) = target {
-    let params = ServerParams { target, remote, bufsize, timeout, tunnel_addr: listen, source_format, mode: ip_mode, format, print_data_buffer };
-    server::start_server(params).await;
+  if !targets.is_empty() && entry.is_some() {
+    eprintln!("Only one of target and entry can be given!");
+    std::process::exit(1);
+  }
+  if !targets.is_empty() && !fallback_remotes.is_empty() {
+    eprintln!("Failing over to another remote is only supported at the entry end!");
+    std::process::exit(1);
+  }
+  if !targets.is_empty() {
+    let params = ServerParams { targets, remote, bufsize, timeout, tunnel_addr: listen, source_format, source_alloc, hash_key, target_select, mode: ip_mode, format, print_data_buffer, middleware, filter, stats, events: None, control, drain_timeout, cleanup_interval, reload, jitter, max_conns_per_source, verbosity, target_batch, log_connections, log_expiry, socket_options, listen_interface, grow_buffer, strict_mtu, bind_retries, tunnel_weight, batch, rng_seed, psk, keepalive, connect_retries, connect_timeout, coalesce, task_per_connection };
+    if check {
+      if let Err(e) = params.validate().await {
+        exit_with(&e);
+      }
+      println!("configuration ok");
+    } else if let Err(e) = udptun::start_server(params).await {
+      exit_with(&e);
+    }
   } else if let Some(entry) = entry {
-    let params = ClientParams { entry, remote, timeout, bufsize, tunnel_addr: listen, mode: ip_mode, format, print_data_buffer };
-    client::start_client(params).await;
+    let params = ClientParams { entry, remote, timeout, bufsize, id_alloc, tunnel_addr: listen, mode: ip_mode, format, print_data_buffer, middleware, filter, stats, events: None, control, drain_timeout, cleanup_interval, max_connections, reload, jitter, verbosity, log_connections, log_expiry, socket_options, listen_interface, grow_buffer, strict_mtu, tunnel_weight, batch, rng_seed, state_file, psk, keepalive, connect_retries, connect_timeout, coalesce, fallback_remotes, failover_timeout };
+    if check {
+      if let Err(e) = params.validate().await {
+        exit_with(&e);
+      }
+      println!("configuration ok");
+    } else if let Err(e) = udptun::start_client(params).await {
+      exit_with(&e);
+    }
   } else {
     eprintln!("One of -T/--target, -E/--entry is required!");
     std::process::exit(1);
   }
 }
 
+// prints the error with all its causes, like "failed to bind tunnel socket: Address in use"
+fn exit_with(e: &udptun::Error) -> ! {
+  let mut msg = e.to_string();
+  let mut source = std::error::Error::source(e);
+  while let Some(e) = source {
+    msg.push_str(": ");
+    msg.push_str(&e.to_string());
+    source = e.source();
+  }
+  eprintln!("{}", msg);
+  // a bad format is a usage error, like the ones clap reports
+  let code = if let udptun::Error::DataFormat(_) = e { 2 } else { 1 };
+  std::process::exit(code);
+}
+
+// --timeout is in seconds, not minutes like it once was by accident
+fn parse_timeout(s: &str) -> Result<Duration, ParseIntError> {
+  s.parse().map(Duration::seconds)
+}
+
+fn merge_config(matches: &ArgMatches, config: Config) -> Result<Reloadable, String> {
+  let targets = match matches.values_of("target") {
+    Some(v) => v.map(str::to_string).collect(),
+    None => config.target.unwrap_or_default(),
+  };
+  let source_format = match matches.value_of("source-format").map(str::to_string).or(config.source_format) {
+    Some(s) => Some(s.parse().map_err(|e| format!("invalid source format {}: {}", s, e))?),
+    None => None,
+  };
+  let format = matches.value_of("format").map(str::to_string).or(config.format);
+  Ok(Reloadable { source_format, targets, format })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn timeout_in_seconds() {
+    assert_eq!(parse_timeout("3600"), Ok(Duration::seconds(3600)));
+    assert_eq!(parse_timeout("0"), Ok(Duration::zero()));
+  }
+
+  #[test]
+  fn timeout_not_a_number() {
+    assert!(parse_timeout("1h").is_err());
+    assert!(parse_timeout("").is_err());
+  }
+}
\ No newline at end of file
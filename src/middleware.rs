@@ -0,0 +1,677 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, Tag};
+use chacha20poly1305::aead::{AeadInPlace, NewAead};
+use hmac::{Hmac, Mac, NewMac};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::proto::*;
+
+// Payload transformation at the tunnel boundary. Outbound payloads are about
+// to enter the tunnel, inbound payloads just left it. Both get the whole
+// remaining buffer with the payload in the first `len` bytes and return the
+// new payload length, so the payload may shrink or grow up to `buf.len()`.
+pub trait Middleware: Send {
+    fn transform_outbound(&mut self, _cid: u16, _buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        Ok(len)
+    }
+
+    fn transform_inbound(&mut self, _cid: u16, _buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        Ok(len)
+    }
+
+    // FEATURE_* bits for layers that change the framing, both ends of the
+    // tunnel have to agree on these during the handshake
+    fn features(&self) -> u8 {
+        0
+    }
+}
+
+pub fn outbound(mw: &mut Option<Box<dyn Middleware>>, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+    match mw {
+        Some(mw) => check_len(mw.transform_outbound(cid, buf, len)?, buf.len()),
+        None => Ok(len),
+    }
+}
+
+pub fn inbound(mw: &mut Option<Box<dyn Middleware>>, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+    match mw {
+        Some(mw) => check_len(mw.transform_inbound(cid, buf, len)?, buf.len()),
+        None => Ok(len),
+    }
+}
+
+// Applies the layers in order on the way into the tunnel and in reverse order
+// on the way out.
+pub struct Chain(pub Vec<Box<dyn Middleware>>);
+
+impl Middleware for Chain {
+    fn transform_outbound(&mut self, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        self.0.iter_mut().try_fold(len, |len, mw| check_len(mw.transform_outbound(cid, buf, len)?, buf.len()))
+    }
+
+    fn transform_inbound(&mut self, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        self.0.iter_mut().rev().try_fold(len, |len, mw| check_len(mw.transform_inbound(cid, buf, len)?, buf.len()))
+    }
+
+    fn features(&self) -> u8 {
+        self.0.iter().fold(0, |acc, mw| acc | mw.features())
+    }
+}
+
+// LZ4-compresses the payload behind a byte that tells whether it was
+// compressed. Payloads that wouldn't get smaller, like media that is already
+// compressed, are sent as they are.
+#[derive(Default)]
+pub struct Compress {
+    scratch: Vec<u8>,
+}
+
+const STORED: u8 = 0x00;
+const COMPRESSED: u8 = 0x01;
+
+impl Middleware for Compress {
+    fn transform_outbound(&mut self, _cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len + 1 > buf.len() {
+            return Err(Error::Overflow(len + 1));
+        }
+        self.scratch.resize(lz4_flex::block::get_maximum_output_size(len), 0);
+        match lz4_flex::block::compress_into(&buf[..len], &mut self.scratch) {
+            Ok(compressed) if compressed < len => {
+                buf[0] = COMPRESSED;
+                buf[1..compressed + 1].copy_from_slice(&self.scratch[..compressed]);
+                Ok(compressed + 1)
+            }
+            _ => {
+                buf.copy_within(..len, 1);
+                buf[0] = STORED;
+                Ok(len + 1)
+            }
+        }
+    }
+
+    fn transform_inbound(&mut self, _cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len < 1 {
+            return Err(Error::Other("compressed packet too short".to_string()));
+        }
+        match buf[0] {
+            STORED => {
+                buf.copy_within(1..len, 0);
+                Ok(len - 1)
+            }
+            COMPRESSED => {
+                // the payload can't have been bigger than the buffer before
+                self.scratch.resize(buf.len(), 0);
+                let real = lz4_flex::block::decompress_into(&buf[1..len], &mut self.scratch)
+                    .map_err(|e| Error::Other(format!("failed to decompress packet: {}", e)))?;
+                buf[..real].copy_from_slice(&self.scratch[..real]);
+                Ok(real)
+            }
+            b => Err(Error::Other(format!("unknown compression marker ${:02X}", b))),
+        }
+    }
+
+    fn features(&self) -> u8 {
+        FEATURE_COMPRESS
+    }
+}
+
+// Obfuscates packet lengths by prefixing the payload with its real length
+// and appending up to `max` random bytes.
+pub struct Padding {
+    max: usize,
+    // not ThreadRng, the chain may be used from other threads
+    rng: StdRng,
+}
+
+impl Padding {
+    pub fn new(max: usize) -> Self {
+        Padding { max, rng: StdRng::from_entropy() }
+    }
+}
+
+impl Middleware for Padding {
+    fn transform_outbound(&mut self, _cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len > u16::MAX as usize || len + 2 > buf.len() {
+            return Err(Error::Overflow(len + 2));
+        }
+        let pad = self.rng.gen_range(0..=self.max).min(buf.len() - len - 2);
+        buf.copy_within(..len, 2);
+        buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        self.rng.fill(&mut buf[len + 2..len + 2 + pad]);
+        Ok(len + 2 + pad)
+    }
+
+    fn transform_inbound(&mut self, _cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len < 2 {
+            return Err(Error::Other("padded packet too short".to_string()));
+        }
+        let real = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+        if real > len - 2 {
+            return Err(Error::Other(format!("padded packet claims {} bytes but only has {}", real, len - 2)));
+        }
+        buf.copy_within(2..2 + real, 0);
+        Ok(real)
+    }
+
+    fn features(&self) -> u8 {
+        FEATURE_PADDING
+    }
+}
+
+// Appends a CRC32 of the payload to catch accidental corruption. This is no
+// protection against tampering.
+pub struct Checksum;
+
+impl Middleware for Checksum {
+    fn transform_outbound(&mut self, _cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len + 4 > buf.len() {
+            return Err(Error::Overflow(len + 4));
+        }
+        let crc = crc32fast::hash(&buf[..len]);
+        buf[len..len + 4].copy_from_slice(&crc.to_be_bytes());
+        Ok(len + 4)
+    }
+
+    fn transform_inbound(&mut self, _cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len < 4 {
+            return Err(Error::Corrupt);
+        }
+        let len = len - 4;
+        let crc = u32::from_be_bytes([buf[len], buf[len + 1], buf[len + 2], buf[len + 3]]);
+        if crc != crc32fast::hash(&buf[..len]) {
+            return Err(Error::Corrupt);
+        }
+        Ok(len)
+    }
+
+    fn features(&self) -> u8 {
+        FEATURE_CHECKSUM
+    }
+}
+
+// Appends a truncated HMAC-SHA256 over the connection ID, which end sent the
+// packet and the payload so injected, modified or reflected packets get
+// dropped. The payload stays readable.
+pub struct Authenticate {
+    mac: Hmac<Sha256>,
+    sender: u8,
+}
+
+const TAG_LEN: usize = 8;
+
+impl Authenticate {
+    // `server` tells which end of the tunnel this is
+    pub fn new(key: &[u8], server: bool) -> Self {
+        Authenticate { mac: Hmac::new_varkey(key).expect("HMAC accepts any key length"), sender: if server { 1 } else { 0 } }
+    }
+
+    fn tag(&self, cid: u16, sender: u8, payload: &[u8]) -> [u8; TAG_LEN] {
+        let mut mac = self.mac.clone();
+        mac.update(&cid.to_be_bytes());
+        mac.update(&[sender]);
+        mac.update(payload);
+        let mut tag = [0; TAG_LEN];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+        tag
+    }
+}
+
+impl Middleware for Authenticate {
+    fn transform_outbound(&mut self, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len + TAG_LEN > buf.len() {
+            return Err(Error::Overflow(len + TAG_LEN));
+        }
+        let tag = self.tag(cid, self.sender, &buf[..len]);
+        buf[len..len + TAG_LEN].copy_from_slice(&tag);
+        Ok(len + TAG_LEN)
+    }
+
+    fn transform_inbound(&mut self, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len < TAG_LEN {
+            return Err(Error::Other("packet too short for authentication tag".to_string()));
+        }
+        let len = len - TAG_LEN;
+        let tag = self.tag(cid, 1 - self.sender, &buf[..len]);
+        // constant time comparison
+        let diff = tag.iter().zip(&buf[len..len + TAG_LEN]).fold(0, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(Error::Auth);
+        }
+        Ok(len)
+    }
+
+    fn features(&self) -> u8 {
+        FEATURE_HMAC
+    }
+}
+
+// Appends a sequence number to every packet and drops packets whose number
+// was already seen or is too far behind the highest one, per connection ID.
+// Only meaningful when the sequence number is authenticated, so this needs
+// to come before the HMAC or encryption layer.
+pub struct Replay {
+    next_seq: u64,
+    window: u64,
+    windows: HashMap<u16, ReplayWindow>,
+}
+
+const SEQ_LEN: usize = 8;
+
+impl Replay {
+    pub fn new(window: u64) -> Self {
+        // start at the current time so a restarted peer doesn't reuse
+        // sequence numbers the other end has already seen
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+        Replay { next_seq: now, window, windows: HashMap::new() }
+    }
+}
+
+impl Middleware for Replay {
+    fn transform_outbound(&mut self, _cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len + SEQ_LEN > buf.len() {
+            return Err(Error::Overflow(len + SEQ_LEN));
+        }
+        buf[len..len + SEQ_LEN].copy_from_slice(&self.next_seq.to_be_bytes());
+        self.next_seq += 1;
+        Ok(len + SEQ_LEN)
+    }
+
+    fn transform_inbound(&mut self, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len < SEQ_LEN {
+            return Err(Error::Other("packet too short for sequence number".to_string()));
+        }
+        let len = len - SEQ_LEN;
+        let mut seq = [0; SEQ_LEN];
+        seq.copy_from_slice(&buf[len..len + SEQ_LEN]);
+        let window = self.window;
+        if !self.windows.entry(cid).or_insert_with(|| ReplayWindow::new(window)).accept(u64::from_be_bytes(seq)) {
+            return Err(Error::Replay);
+        }
+        Ok(len)
+    }
+
+    fn features(&self) -> u8 {
+        FEATURE_REPLAY
+    }
+}
+
+// Bitmap of the sequence numbers seen in the last `size` numbers, indexed by
+// the sequence number modulo the bitmap length.
+struct ReplayWindow {
+    highest: Option<u64>,
+    size: u64,
+    bits: Vec<u64>,
+}
+
+impl ReplayWindow {
+    fn new(size: u64) -> Self {
+        let words = size.div_ceil(64).max(1) as usize;
+        ReplayWindow { highest: None, size, bits: vec![0; words] }
+    }
+
+    fn bit_len(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    fn bit(&self, seq: u64) -> (usize, u64) {
+        let i = seq % self.bit_len();
+        ((i / 64) as usize, 1 << (i % 64))
+    }
+
+    fn accept(&mut self, seq: u64) -> bool {
+        match self.highest {
+            Some(h) if seq <= h => {
+                if h - seq >= self.size {
+                    return false;
+                }
+                let (word, mask) = self.bit(seq);
+                if self.bits[word] & mask != 0 {
+                    return false;
+                }
+                self.bits[word] |= mask;
+            }
+            h => {
+                // forget the numbers that are now out of the window
+                let start = h.map_or(seq, |h| h + 1);
+                if seq - start >= self.bit_len() {
+                    self.bits.iter_mut().for_each(|w| *w = 0);
+                } else {
+                    for s in start..=seq {
+                        let (word, mask) = self.bit(s);
+                        self.bits[word] &= !mask;
+                    }
+                }
+                let (word, mask) = self.bit(seq);
+                self.bits[word] |= mask;
+                self.highest = Some(seq);
+            }
+        }
+        true
+    }
+}
+
+// Encrypts and authenticates the payload with ChaCha20-Poly1305, appending
+// the nonce counter and the tag. The nonce is made of the connection ID, which
+// end sent the packet and the counter, so the two ends never use the same
+// nonce with the shared key.
+pub struct Encrypt {
+    cipher: ChaCha20Poly1305,
+    sender: u8,
+    next_counter: u64,
+}
+
+const COUNTER_LEN: usize = 8;
+const AEAD_TAG_LEN: usize = 16;
+
+impl Encrypt {
+    // `server` tells which end of the tunnel this is
+    pub fn new(key: &[u8], server: bool) -> Self {
+        let key = Sha256::digest(key);
+        // start at the current time so a restarted end doesn't reuse nonces
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_micros() as u64);
+        Encrypt {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            sender: if server { 1 } else { 0 },
+            next_counter: now,
+        }
+    }
+
+    fn nonce(cid: u16, sender: u8, counter: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..2].copy_from_slice(&cid.to_be_bytes());
+        nonce[2] = sender;
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+}
+
+impl Middleware for Encrypt {
+    fn transform_outbound(&mut self, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        let total = len + COUNTER_LEN + AEAD_TAG_LEN;
+        if total > buf.len() {
+            return Err(Error::Overflow(total));
+        }
+        let counter = self.next_counter;
+        self.next_counter += 1;
+        let nonce = Encrypt::nonce(cid, self.sender, counter);
+        let tag = self.cipher.encrypt_in_place_detached(Nonce::from_slice(&nonce), &cid.to_be_bytes(), &mut buf[..len])
+            .map_err(|_| Error::Other("encryption failed".to_string()))?;
+        buf[len..len + COUNTER_LEN].copy_from_slice(&counter.to_be_bytes());
+        buf[len + COUNTER_LEN..total].copy_from_slice(&tag);
+        Ok(total)
+    }
+
+    fn transform_inbound(&mut self, cid: u16, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+        if len < COUNTER_LEN + AEAD_TAG_LEN {
+            return Err(Error::Other("packet too short for encryption trailer".to_string()));
+        }
+        let len = len - COUNTER_LEN - AEAD_TAG_LEN;
+        let mut counter = [0; COUNTER_LEN];
+        counter.copy_from_slice(&buf[len..len + COUNTER_LEN]);
+        let nonce = Encrypt::nonce(cid, 1 - self.sender, u64::from_be_bytes(counter));
+        let tag = *Tag::from_slice(&buf[len + COUNTER_LEN..len + COUNTER_LEN + AEAD_TAG_LEN]);
+        self.cipher.decrypt_in_place_detached(Nonce::from_slice(&nonce), &cid.to_be_bytes(), &mut buf[..len], &tag)
+            .map_err(|_| Error::Decrypt)?;
+        Ok(len)
+    }
+
+    fn features(&self) -> u8 {
+        FEATURE_ENCRYPT
+    }
+}
+
+pub fn features(mw: &Option<Box<dyn Middleware>>) -> u8 {
+    mw.as_ref().map_or(0, |mw| mw.features())
+}
+
+fn check_len(len: usize, cap: usize) -> Result<usize, Error> {
+    if len > cap {
+        Err(Error::Overflow(len))
+    } else {
+        Ok(len)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("payload of {0} bytes does not fit into the buffer")]
+    Overflow(usize),
+    #[error("checksum mismatch")]
+    Corrupt,
+    #[error("authentication failed")]
+    Auth,
+    #[error("replayed or too old packet")]
+    Replay,
+    #[error("decryption failed")]
+    Decrypt,
+    #[error("{0}")]
+    Other(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // through `out` on one end and `inb` on the other, returns the payload
+    // as it was in the tunnel
+    fn round_trip(out: &mut dyn Middleware, inb: &mut dyn Middleware, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0; 2048];
+        buf[..payload.len()].copy_from_slice(payload);
+        let len = out.transform_outbound(7, &mut buf, payload.len()).unwrap();
+        let sent = buf[..len].to_vec();
+        let len = inb.transform_inbound(7, &mut buf, len).unwrap();
+        assert_eq!(&buf[..len], payload);
+        sent
+    }
+
+    fn noise(len: usize) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn compress_compressible() {
+        let payload = b"hello hello hello hello hello hello hello hello".repeat(10);
+        let sent = round_trip(&mut Compress::default(), &mut Compress::default(), &payload);
+        assert_eq!(sent[0], COMPRESSED);
+        assert!(sent.len() < payload.len());
+    }
+
+    #[test]
+    fn compress_incompressible() {
+        let payload = noise(500);
+        let sent = round_trip(&mut Compress::default(), &mut Compress::default(), &payload);
+        assert_eq!(sent[0], STORED);
+        assert_eq!(sent.len(), payload.len() + 1);
+    }
+
+    #[test]
+    fn compress_empty() {
+        assert_eq!(round_trip(&mut Compress::default(), &mut Compress::default(), &[]), [STORED]);
+    }
+
+    #[test]
+    fn compress_unknown_marker() {
+        let mut buf = [0x7f, 1, 2];
+        assert!(Compress::default().transform_inbound(7, &mut buf, 3).is_err());
+    }
+
+    #[test]
+    fn padding() {
+        let mut padding = Padding::new(32);
+        for len in 0..64 {
+            let sent = round_trip(&mut padding, &mut Padding::new(0), &noise(len));
+            assert!((len + 2..=len + 34).contains(&sent.len()));
+        }
+    }
+
+    #[test]
+    fn padding_fills_buffer_at_most() {
+        let mut buf = [0; 12];
+        assert_eq!(Padding::new(1000).transform_outbound(7, &mut buf, 10).unwrap(), 12);
+        assert!(Padding::new(0).transform_outbound(7, &mut buf, 11).is_err());
+    }
+
+    #[test]
+    fn padding_claims_too_much() {
+        let mut buf = [0, 10, 1, 2, 3];
+        assert!(Padding::new(0).transform_inbound(7, &mut buf, 5).is_err());
+    }
+
+    #[test]
+    fn checksum() {
+        for &len in &[0, 1, 100] {
+            assert_eq!(round_trip(&mut Checksum, &mut Checksum, &noise(len)).len(), len + 4);
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut buf = vec![0; 64];
+        buf[..5].copy_from_slice(b"hello");
+        let len = Checksum.transform_outbound(7, &mut buf, 5).unwrap();
+        buf[1] ^= 0x01;
+        assert!(matches!(Checksum.transform_inbound(7, &mut buf, len), Err(Error::Corrupt)));
+        assert!(matches!(Checksum.transform_inbound(7, &mut buf, 3), Err(Error::Corrupt)));
+    }
+
+    #[test]
+    fn hmac() {
+        let sent = round_trip(&mut Authenticate::new(b"key", false), &mut Authenticate::new(b"key", true), b"hello");
+        assert_eq!(&sent[..5], b"hello");
+        assert_eq!(sent.len(), 5 + TAG_LEN);
+        round_trip(&mut Authenticate::new(b"key", true), &mut Authenticate::new(b"key", false), b"hello");
+    }
+
+    #[test]
+    fn hmac_wrong_key() {
+        let mut buf = vec![0; 64];
+        let len = Authenticate::new(b"key", false).transform_outbound(7, &mut buf, 5).unwrap();
+        assert!(matches!(Authenticate::new(b"other", true).transform_inbound(7, &mut buf, len), Err(Error::Auth)));
+    }
+
+    #[test]
+    fn hmac_covers_connection_id() {
+        let mut buf = vec![0; 64];
+        let len = Authenticate::new(b"key", false).transform_outbound(7, &mut buf, 5).unwrap();
+        assert!(matches!(Authenticate::new(b"key", true).transform_inbound(8, &mut buf, len), Err(Error::Auth)));
+    }
+
+    // sent back to the end it came from
+    #[test]
+    fn hmac_reflected() {
+        let mut hmac = Authenticate::new(b"key", false);
+        let mut buf = vec![0; 64];
+        let len = hmac.transform_outbound(7, &mut buf, 5).unwrap();
+        assert!(matches!(hmac.transform_inbound(7, &mut buf, len), Err(Error::Auth)));
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut w = ReplayWindow::new(64);
+        assert!(w.accept(100));
+        assert!(!w.accept(100));
+        // late but inside the window, once
+        assert!(w.accept(98));
+        assert!(!w.accept(98));
+        assert!(w.accept(37));
+        // too far behind
+        assert!(!w.accept(36));
+        assert!(w.accept(101));
+    }
+
+    #[test]
+    fn replay_window_jump() {
+        let mut w = ReplayWindow::new(64);
+        assert!(w.accept(10));
+        assert!(w.accept(1000));
+        // what the jump moved out of the window is gone, not remembered
+        assert!(!w.accept(10));
+        assert!(w.accept(999));
+        // takes over the bit of 1000, which is out of the window now
+        assert!(w.accept(1064));
+        assert!(!w.accept(1000));
+        assert!(w.accept(1001));
+    }
+
+    #[test]
+    fn replay_window_not_multiple_of_64() {
+        let mut w = ReplayWindow::new(100);
+        assert!(w.accept(200));
+        assert!(w.accept(101));
+        assert!(!w.accept(100));
+    }
+
+    #[test]
+    fn replayed_packet() {
+        let (mut out, mut inb) = (Replay::new(64), Replay::new(64));
+        let mut buf = vec![0; 64];
+        let len = out.transform_outbound(7, &mut buf, 5).unwrap();
+        let packet = buf.clone();
+        assert_eq!(inb.transform_inbound(7, &mut buf, len).unwrap(), 5);
+        buf.copy_from_slice(&packet);
+        assert!(matches!(inb.transform_inbound(7, &mut buf, len), Err(Error::Replay)));
+        // every connection has a window of its own
+        buf.copy_from_slice(&packet);
+        assert_eq!(inb.transform_inbound(8, &mut buf, len).unwrap(), 5);
+    }
+
+    // with only encryption, like --encrypt --replay-window
+    #[test]
+    fn replayed_encrypted_packet() {
+        let chain = |server| Chain(vec![Box::new(Replay::new(64)), Box::new(Encrypt::new(b"key", server))]);
+        let (mut out, mut inb) = (chain(false), chain(true));
+        let packet = round_trip(&mut out, &mut inb, b"hello");
+        let mut buf = packet.clone();
+        assert!(matches!(inb.transform_inbound(7, &mut buf, packet.len()), Err(Error::Replay)));
+    }
+
+    #[test]
+    fn encrypt() {
+        let payload = b"hello hello hello";
+        let sent = round_trip(&mut Encrypt::new(b"key", false), &mut Encrypt::new(b"key", true), payload);
+        assert_ne!(&sent[..payload.len()], payload);
+        assert_eq!(sent.len(), payload.len() + COUNTER_LEN + AEAD_TAG_LEN);
+        round_trip(&mut Encrypt::new(b"key", true), &mut Encrypt::new(b"key", false), payload);
+    }
+
+    fn decrypt_error(inb: &mut Encrypt, cid: u16, tamper: impl Fn(&mut [u8])) -> Error {
+        let mut buf = vec![0; 64];
+        buf[..5].copy_from_slice(b"hello");
+        let len = Encrypt::new(b"key", false).transform_outbound(7, &mut buf, 5).unwrap();
+        tamper(&mut buf);
+        inb.transform_inbound(cid, &mut buf, len).unwrap_err()
+    }
+
+    #[test]
+    fn encrypt_wrong_key() {
+        assert!(matches!(decrypt_error(&mut Encrypt::new(b"other", true), 7, |_| {}), Error::Decrypt));
+    }
+
+    #[test]
+    fn encrypt_tampered() {
+        assert!(matches!(decrypt_error(&mut Encrypt::new(b"key", true), 7, |buf| buf[0] ^= 0x01), Error::Decrypt));
+        assert!(matches!(decrypt_error(&mut Encrypt::new(b"key", true), 8, |_| {}), Error::Decrypt));
+    }
+
+    // the nonces of both ends differ, so a packet can't be reflected back
+    #[test]
+    fn encrypt_reflected() {
+        assert!(matches!(decrypt_error(&mut Encrypt::new(b"key", false), 7, |_| {}), Error::Decrypt));
+    }
+
+    #[test]
+    fn chain() {
+        let chain = || Chain(vec![Box::new(Replay::new(64)), Box::new(Compress::default()), Box::new(Encrypt::new(b"key", false))]);
+        let (mut out, mut inb) = (chain(), chain());
+        // the other end of the encryption
+        inb.0[2] = Box::new(Encrypt::new(b"key", true));
+        round_trip(&mut out, &mut inb, &b"hello ".repeat(20));
+        assert_eq!(out.features(), FEATURE_REPLAY | FEATURE_COMPRESS | FEATURE_ENCRYPT);
+    }
+}
\ No newline at end of file
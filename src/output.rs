@@ -1,15 +1,75 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Display, Formatter};
 use std::fmt;
 use std::hash::Hash;
+use std::net::SocketAddr;
 
+use once_cell::sync::OnceCell;
+use serde::Serialize;
 use thiserror::Error;
 
+use crate::common::Format;
+use crate::log;
+
+// Where data packet log lines go, either the text table or one JSON object
+// per packet.
+pub enum DataLog<T> {
+    Table(TableFormat<T>),
+    Json,
+}
+
+impl<T, D> DataLog<T>
+    where T: Column<Data=D> + Copy,
+          D: Record {
+    pub fn new(format: &Format, default: &str) -> Result<Self, ParseError> {
+        match format {
+            Format::Json => Ok(DataLog::Json),
+            f => Ok(DataLog::Table(TableFormat::parse_spec(f.with_default(default))?)),
+        }
+    }
+
+    pub fn write(&self, row: &D) {
+        match self {
+            DataLog::Table(t) => log::data(format_args!("{}", t.bind(row))),
+            // can't fail, there are only strings and numbers in there
+            DataLog::Json => if let Ok(s) = serde_json::to_string(&row.record()) {
+                log::data(format_args!("{}", s));
+            },
+        }
+    }
+}
+
+// One data packet as written by the JSON log. The keys are part of the
+// output format, don't rename them.
+#[derive(Debug, Clone, Serialize)]
+pub struct DataRecord {
+    // RFC 3339 with milliseconds
+    pub timestamp: String,
+    // "into_tunnel" or "from_tunnel"
+    pub direction: &'static str,
+    pub cid: u16,
+    // the tunnel peer on the target end, the client on the entry end
+    pub peer: SocketAddr,
+    // the socket the packet left through or arrived on at this end
+    pub local: Option<SocketAddr>,
+    pub len: usize,
+    pub jitter_ms: Option<f64>,
+    pub rate: Option<f64>,
+    // running count on the connection
+    pub packet: Option<u64>,
+    // running payload total on the connection, both directions
+    pub bytes: Option<u64>,
+}
+
+pub trait Record {
+    fn record(&self) -> DataRecord;
+}
+
 pub struct TableFormat<T> {
-    sizes: RefCell<HashMap<T, usize>>,
+    sizes: RefCell<HashMap<T, Width>>,
     format: Vec<FormatPart<T>>,
 }
 
@@ -19,28 +79,68 @@ impl<T, D> TableFormat<T>
         TableFormat { sizes: Default::default(), format }
     }
 
+    // %x for columns, %10x or %-10x for a fixed width like printf, %% for a
+    // percent sign, and \t, \n and \\ in literals
     pub fn parse_spec(s: &str) -> Result<Self, ParseError> {
         let mut parts = Vec::new();
         let mut partial = String::new();
-        let mut rem = s;
-        while let Some(next_bound) = rem.find('%') {
-            partial.push_str(&rem[..next_bound]);
-            rem = &rem[next_bound + 1..];
-            let ch = rem.chars().next().ok_or(ParseError::Eof)?;
-            if ch == '%' {
-                partial.push(ch);
-            } else {
-                parts.push(FormatPart::Literal(partial.to_string()));
-                partial = String::new();
-                parts.push(FormatPart::Column(T::by_char(ch).ok_or(ParseError::InvalidPart(ch))?));
+        let mut chars = s.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '%' => match chars.next().ok_or(ParseError::Eof)? {
+                    '%' => partial.push('%'),
+                    mut ch => {
+                        let left = ch == '-';
+                        if left {
+                            ch = chars.next().ok_or(ParseError::Eof)?;
+                        }
+                        let mut width = String::new();
+                        while ch.is_ascii_digit() {
+                            width.push(ch);
+                            ch = chars.next().ok_or(ParseError::Eof)?;
+                        }
+                        let fixed = match width.parse() {
+                            Ok(0) => return Err(ParseError::InvalidWidth),
+                            Ok(width) => Some(FixedWidth { width, left }),
+                            Err(_) if left || !width.is_empty() => return Err(ParseError::InvalidWidth),
+                            Err(_) => None,
+                        };
+                        parts.push(FormatPart::Literal(std::mem::take(&mut partial)));
+                        parts.push(FormatPart::Column(T::by_char(ch).ok_or(ParseError::InvalidPart(ch, T::CHARS))?, fixed));
+                    }
+                },
+                '\\' => partial.push(match chars.next().ok_or(ParseError::Eof)? {
+                    't' => '\t',
+                    'n' => '\n',
+                    '\\' => '\\',
+                    ch => return Err(ParseError::InvalidEscape(ch)),
+                }),
+                ch => partial.push(ch),
             }
-            rem = &rem[ch.len_utf8()..];
         }
-        partial.push_str(rem);
-        parts.push(FormatPart::Literal(partial.to_string()));
+        parts.push(FormatPart::Literal(partial));
         Ok(TableFormat::new(parts))
     }
 
+    // widest value so far, or in the last rows with a width window
+    fn width(&self, column: T, len: usize) -> usize {
+        let mut sizes = self.sizes.borrow_mut();
+        let w = sizes.entry(column).or_default();
+        match WIDTH_WINDOW.get() {
+            Some(&rows) => {
+                w.recent.push_back(len);
+                if w.recent.len() > rows {
+                    w.recent.pop_front();
+                }
+                w.recent.iter().copied().max().unwrap_or(len)
+            }
+            None => {
+                w.widest = max(w.widest, len);
+                w.widest
+            }
+        }
+    }
+
     pub fn bind<'a>(&'a self, row: &'a D) -> BoundTable<'a, T> {
         BoundTable { table: self, data: row }
     }
@@ -56,28 +156,28 @@ pub struct BoundTable<'a, T>
     data: &'a T::Data,
 }
 
-impl<T> Display for BoundTable<'_, T>
-    where T: Column + Copy {
+impl<T, D> Display for BoundTable<'_, T>
+    where T: Column<Data=D> + Copy {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         for part in self.table.format.iter() {
             match part {
-                FormatPart::Column(c) if !c.constant_size() => {
+                // cut off when too long
+                FormatPart::Column(c, Some(fixed)) => {
+                    let col: String = part.to_string(self.data).chars().take(fixed.width).collect();
+                    let len = col.chars().count();
+                    let alignment = if fixed.left { Alignment::Left } else { Alignment::Right };
+                    write_padded(f, Colored(col.into(), c.color(self.data)), alignment, fixed.width - len)?;
+                }
+                FormatPart::Column(c, None) if !c.constant_size() => {
                     let col = part.to_string(self.data);
                     let len = col.chars().count();
-                    let col_width = *self.table.sizes.borrow_mut().entry(*c)
-                        .and_modify(|v| *v = max(*v, len))
-                        .or_insert(len);
-
-                    match c.alignment() {
-                        Alignment::Left => {
-                            write!(f, "{}{}", col, " ".repeat(col_width - len))?;
-                        }
-                        Alignment::Right => {
-                            write!(f, "{}{}", " ".repeat(col_width - len), col)?;
-                        }
-                    }
+                    let col_width = self.table.width(*c, len);
+                    write_padded(f, Colored(col, c.color(self.data)), c.alignment(), col_width - len)?;
                 }
-                _ => {
+                FormatPart::Column(c, None) => {
+                    write!(f, "{}", Colored(part.to_string(self.data), c.color(self.data)))?;
+                }
+                FormatPart::Literal(_) => {
                     write!(f, "{}", part.to_string(self.data))?;
                 }
             }
@@ -86,17 +186,101 @@ impl<T> Display for BoundTable<'_, T>
     }
 }
 
+// padding is worked out on the text without escape codes
+fn write_padded(f: &mut Formatter, col: Colored, alignment: Alignment, padding: usize) -> fmt::Result {
+    match alignment {
+        Alignment::Left => write!(f, "{}{}", col, " ".repeat(padding)),
+        Alignment::Right => write!(f, "{}{}", " ".repeat(padding), col),
+    }
+}
+
+#[derive(Default)]
+struct Width {
+    widest: usize,
+    recent: VecDeque<usize>,
+}
+
+static WIDTH_WINDOW: OnceCell<usize> = OnceCell::new();
+
+// Size columns by the last `rows` rows only, so they shrink again after a
+// long value; without this they only ever grow
+pub fn set_width_window(rows: usize) {
+    let _ = WIDTH_WINDOW.set(rows);
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Color {
+    Green,
+    Cyan,
+    Dim,
+}
+
+impl Color {
+    fn escape(self) -> &'static str {
+        match self {
+            Color::Green => "\x1b[32m",
+            Color::Cyan => "\x1b[36m",
+            Color::Dim => "\x1b[2m",
+        }
+    }
+}
+
+static COLOR: OnceCell<bool> = OnceCell::new();
+
+// Whether columns get colored, off unless enabled
+pub fn set_color(enabled: bool) {
+    let _ = COLOR.set(enabled);
+}
+
+struct Colored<'a>(Cow<'a, str>, Option<Color>);
+
+impl Display for Colored<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.1 {
+            Some(color) if COLOR.get() == Some(&true) => write!(f, "{}{}\x1b[0m", color.escape(), self.0),
+            _ => write!(f, "{}", self.0),
+        }
+    }
+}
+
+pub const DEFAULT_TIME_FORMAT: &str = "%H:%M:%S%.3f";
+
+static TIME_FORMAT: OnceCell<String> = OnceCell::new();
+
+// strftime pattern for timestamp columns
+pub fn set_time_format(format: String) {
+    let _ = TIME_FORMAT.set(format);
+}
+
+pub fn time_format() -> &'static str {
+    TIME_FORMAT.get().map_or(DEFAULT_TIME_FORMAT, String::as_str)
+}
+
 #[derive(Error, Debug, Copy, Clone)]
 pub enum ParseError {
-    #[error("invalid format spec %{0}")]
-    InvalidPart(char),
+    #[error("invalid format spec %{0}, expected one of {}", list_chars(.1))]
+    InvalidPart(char, &'static str),
+    #[error("invalid escape sequence \\{0}")]
+    InvalidEscape(char),
+    #[error("column width must be a positive number")]
+    InvalidWidth,
     #[error("unexpected end of format string")]
     Eof,
 }
 
+fn list_chars(chars: &str) -> String {
+    chars.chars().map(|c| format!("%{}", c)).collect::<Vec<_>>().join(" ")
+}
+
 enum FormatPart<T> {
     Literal(String),
-    Column(T),
+    Column(T, Option<FixedWidth>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FixedWidth {
+    width: usize,
+    left: bool,
 }
 
 impl<T, D> FormatPart<T>
@@ -104,7 +288,7 @@ impl<T, D> FormatPart<T>
     fn to_string<'a>(&'a self, row: &'a D) -> Cow<'a, str> {
         match self {
             FormatPart::Literal(l) => l.into(),
-            FormatPart::Column(c) => c.to_string(row),
+            FormatPart::Column(c, _) => c.to_string(row),
         }
     }
 }
@@ -112,17 +296,139 @@ impl<T, D> FormatPart<T>
 pub trait Column: Eq + Hash + Sized {
     type Data;
 
+    // every char by_char knows, for error messages
+    const CHARS: &'static str;
+
     fn by_char(ch: char) -> Option<Self>;
 
+    // a line of help for every char in CHARS
+    fn describe(ch: char) -> Option<&'static str>;
+
     fn to_string<'a>(&'a self, data: &'a Self::Data) -> Cow<'a, str>;
 
     fn constant_size(&self) -> bool { false }
 
     fn alignment(&self) -> Alignment { Alignment::Left }
+
+    fn color(&self, _data: &Self::Data) -> Option<Color> { None }
+}
+
+// The columns a format can use, one per line, for --list-columns.
+pub fn list_columns<C: Column>() -> String {
+    C::CHARS.chars()
+        .filter_map(|ch| C::describe(ch).map(|d| format!("%{}\t{}\n", ch, d)))
+        .collect()
 }
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum Alignment {
     Left,
     Right,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+    enum TestColumn {
+        Id,
+        Len,
+    }
+
+    struct Row {
+        id: u16,
+        len: usize,
+    }
+
+    impl Column for TestColumn {
+        type Data = Row;
+
+        const CHARS: &'static str = "cl";
+
+        fn by_char(ch: char) -> Option<Self> {
+            match ch {
+                'c' => Some(TestColumn::Id),
+                'l' => Some(TestColumn::Len),
+                _ => None,
+            }
+        }
+
+        fn describe(ch: char) -> Option<&'static str> {
+            TestColumn::by_char(ch).map(|_| "")
+        }
+
+        fn to_string<'a>(&'a self, data: &'a Row) -> Cow<'a, str> {
+            match self {
+                TestColumn::Id => data.id.to_string().into(),
+                TestColumn::Len => data.len.to_string().into(),
+            }
+        }
+    }
+
+    fn format(spec: &str, rows: &[Row]) -> Vec<String> {
+        let table = TableFormat::<TestColumn>::parse_spec(spec).unwrap();
+        rows.iter().map(|r| table.format_row(r)).collect()
+    }
+
+    #[test]
+    fn table_from_spec() {
+        assert_eq!(format("id %c len %l", &[Row { id: 1, len: 20 }]), ["id 1 len 20"]);
+    }
+
+    #[test]
+    fn columns_grow() {
+        assert_eq!(format("%c|%l", &[Row { id: 100, len: 1 }, Row { id: 2, len: 1 }]), ["100|1", "2  |1"]);
+    }
+
+    #[test]
+    fn percent_sign() {
+        assert_eq!(format("%l%%", &[Row { id: 0, len: 50 }]), ["50%"]);
+    }
+
+    #[test]
+    fn tab_escape() {
+        assert_eq!(format("%c\\t%l", &[Row { id: 1, len: 20 }]), ["1\t20"]);
+    }
+
+    #[test]
+    fn backslash_and_newline_escapes() {
+        assert_eq!(format("\\\\%c\\n", &[Row { id: 1, len: 0 }]), ["\\1\n"]);
+    }
+
+    #[test]
+    fn invalid_escape() {
+        assert!(matches!(TableFormat::<TestColumn>::parse_spec("%c\\x"), Err(ParseError::InvalidEscape('x'))));
+        assert!(matches!(TableFormat::<TestColumn>::parse_spec("%c\\"), Err(ParseError::Eof)));
+    }
+
+    #[test]
+    fn fixed_width() {
+        assert_eq!(format("%10c|", &[Row { id: 1, len: 0 }]), ["         1|"]);
+    }
+
+    #[test]
+    fn fixed_width_left() {
+        assert_eq!(format("%-5l|", &[Row { id: 0, len: 12 }]), ["12   |"]);
+    }
+
+    #[test]
+    fn fixed_width_truncates() {
+        // and doesn't grow with longer values like an automatic width
+        assert_eq!(format("%3c|", &[Row { id: 12345, len: 0 }, Row { id: 1, len: 0 }]), ["123|", "  1|"]);
+    }
+
+    #[test]
+    fn invalid_width() {
+        assert!(matches!(TableFormat::<TestColumn>::parse_spec("%xc"), Err(ParseError::InvalidPart('x', _))));
+        assert!(matches!(TableFormat::<TestColumn>::parse_spec("%0c"), Err(ParseError::InvalidWidth)));
+        assert!(matches!(TableFormat::<TestColumn>::parse_spec("%-c"), Err(ParseError::InvalidWidth)));
+        assert!(matches!(TableFormat::<TestColumn>::parse_spec("%10"), Err(ParseError::Eof)));
+    }
+
+    #[test]
+    fn unknown_column() {
+        assert!(matches!(TableFormat::<TestColumn>::parse_spec("%c %z"), Err(ParseError::InvalidPart('z', "cl"))));
+        assert!(matches!(TableFormat::<TestColumn>::parse_spec("%c %"), Err(ParseError::Eof)));
+    }
 }
\ No newline at end of file
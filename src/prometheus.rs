@@ -0,0 +1,104 @@
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::stats::{Counters, Stats, TargetStats};
+
+// Serves the shared counters over HTTP at /metrics in the Prometheus text
+// format. Runs on its own task and only reads the counters.
+pub async fn start<T: ToSocketAddrs>(addr: T, stats: Arc<Stats>) -> io::Result<()> {
+    let mut listener = TcpListener::bind(addr).await?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((s, _)) => { tokio::spawn(handle(s, stats.clone())); }
+                Err(e) => error!("failed to accept metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+// one request per connection, the headers are read and ignored
+async fn handle(stream: TcpStream, stats: Arc<Stats>) -> io::Result<()> {
+    let (r, mut w) = tokio::io::split(stream);
+    let mut r = BufReader::new(r);
+    let mut request_line = String::new();
+    r.read_line(&mut request_line).await?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if r.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = format_metrics(&stats.counters(), &stats);
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    w.write_all(response.as_bytes()).await
+}
+
+fn format_metrics(c: &Counters, stats: &Stats) -> String {
+    let mut s = String::new();
+    metric(&mut s, "packets_total", "counter", "Data packets forwarded, in is out of the tunnel", &[
+        ("direction=\"in\"", c.packets_in),
+        ("direction=\"out\"", c.packets_out),
+    ]);
+    metric(&mut s, "bytes_total", "counter", "Payload bytes forwarded, in is out of the tunnel", &[
+        ("direction=\"in\"", c.bytes_in),
+        ("direction=\"out\"", c.bytes_out),
+    ]);
+    metric(&mut s, "connections", "gauge", "Active connections", &[("", c.connections)]);
+    metric(&mut s, "peak_connections", "gauge", "Most connections active at once", &[("", c.peak_connections)]);
+    metric(&mut s, "handshakes_total", "counter", "Completed tunnel handshakes", &[("", c.handshakes)]);
+    metric(&mut s, "tunnel_rtt_microseconds", "gauge", "Round trip time of the last handshake this end started, 0 if it never started one", &[("", c.rtt_micros)]);
+    metric(&mut s, "handshake_failures_total", "counter", "Rejected tunnel handshakes", &[("", c.handshake_rejects)]);
+    metric(&mut s, "dropped_packets_total", "counter", "Packets dropped, by reason", &[
+        ("reason=\"malformed\"", c.middleware_drops),
+        ("reason=\"corrupt\"", c.corrupt_drops),
+        ("reason=\"auth\"", c.auth_drops),
+        ("reason=\"replay\"", c.replay_drops),
+        ("reason=\"decrypt\"", c.decrypt_drops),
+        ("reason=\"truncated\"", c.truncated_drops),
+        ("reason=\"loopback\"", c.loopback_drops),
+        ("reason=\"filtered\"", c.filtered),
+        ("reason=\"quota\"", c.quota_rejects),
+        ("reason=\"unknown_packet\"", c.unknown_packets),
+    ]);
+
+    let targets = stats.target_counters();
+    if !targets.is_empty() {
+        let labels: Vec<_> = targets.iter().map(|(a, _)| format!("target=\"{}\"", a)).collect();
+        metric(&mut s, "target_connections_total", "counter", "Connections opened to a target", &per_target(&labels, &targets, |t| t.connections));
+        metric(&mut s, "target_bytes_total", "counter", "Payload bytes sent to a target", &per_target(&labels, &targets, |t| t.bytes_in));
+        metric(&mut s, "target_received_bytes_total", "counter", "Payload bytes received from a target", &per_target(&labels, &targets, |t| t.bytes_out));
+        metric(&mut s, "target_errors_total", "counter", "Failed connections or sends to a target", &per_target(&labels, &targets, |t| t.errors));
+    }
+    s
+}
+
+fn per_target<'a>(labels: &'a [String], targets: &[(SocketAddr, TargetStats)], f: fn(&TargetStats) -> u64) -> Vec<(&'a str, u64)> {
+    labels.iter().zip(targets).map(|(l, (_, t))| (l.as_str(), f(t))).collect()
+}
+
+fn metric(s: &mut String, name: &str, kind: &str, help: &str, values: &[(&str, u64)]) {
+    writeln!(s, "# HELP udptun_{} {}", name, help).unwrap();
+    writeln!(s, "# TYPE udptun_{} {}", name, kind).unwrap();
+    for (labels, value) in values {
+        if labels.is_empty() {
+            writeln!(s, "udptun_{} {}", name, value).unwrap();
+        } else {
+            writeln!(s, "udptun_{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+}
\ No newline at end of file
@@ -1,127 +1,701 @@
 use std::{fmt, io};
 use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
-use chrono::Duration;
-use rand::prelude::{SliceRandom, ThreadRng};
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use chrono::{DateTime, Duration, Local, SecondsFormat};
+use rand::rngs::SmallRng;
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::Sender;
+use tokio::time;
 
-use crate::{common, output};
-use crate::common::{default_listen_ip, Format, IpMode, respond_connect, setup_tunnel_socket};
-use crate::output::Alignment;
+use crate::{common, control, filter, log, middleware, output, sockopt, stats};
+use crate::config::Reload;
+use crate::common::{Batch, Coalesce, DataDirection, Drain, FairOrder, Handshake, Keepalive, default_listen_ip, Format, IpMode, log_closed, log_expired, log_opened, read_cid, Reconnect, reject, respond_connect, setup_tunnel_socket, TunnelSession};
+use crate::control::{Command, ControlAddr};
+use crate::event::{CloseReason, EventSink, TunnelEvent};
+use crate::filter::{Filter, Packet};
+use crate::middleware::Middleware;
+use crate::output::{Alignment, Color, DataLog, DataRecord};
 use crate::proto::*;
-use crate::server_cache::{Cache, CacheEntry};
-use crate::sourcefmt::SourceFormat;
+use crate::server_cache::Cache;
+use crate::server_task;
+use crate::server_task::{Forward, Report};
+use crate::sourcefmt::{SourceAlloc, SourceFormat};
+use crate::signal::SignalListener;
+use crate::sockopt::{ListenInterface, SocketOptions};
+use crate::stats::{Jitter, Rejection, Stats};
+
+const DEFAULT_FORMAT: &str = "[%d tunnel] client: %c lsock: %a dbuf: %l";
 
 pub struct ServerParams<'a, T, U, V>
     where T: ToSocketAddrs,
           U: ToSocketAddrs,
           V: ToSocketAddrs {
-    pub target: T,
+    pub targets: Vec<T>,
     pub remote: Option<U>,
     pub bufsize: usize,
     pub timeout: Duration,
     pub tunnel_addr: Option<V>,
     pub source_format: Option<SourceFormat>,
+    pub source_alloc: SourceAlloc,
+    pub hash_key: HashKey,
+    pub target_select: TargetSelect,
     pub mode: IpMode,
     pub format: Option<Format<'a>>,
     pub print_data_buffer: bool,
+    pub middleware: Option<Box<dyn Middleware>>,
+    pub filter: Option<Box<dyn Filter>>,
+    pub stats: Arc<Stats>,
+    pub events: Option<Sender<TunnelEvent>>,
+    pub control: Option<ControlAddr>,
+    pub drain_timeout: time::Duration,
+    // how often timed out connections are looked for and removed
+    pub cleanup_interval: time::Duration,
+    pub reload: Option<Reload<'a>>,
+    pub jitter: bool,
+    pub max_conns_per_source: Option<usize>,
+    pub verbosity: u64,
+    pub target_batch: Option<usize>,
+    pub log_connections: bool,
+    // a line for every connection removed for being idle
+    pub log_expiry: bool,
+    pub socket_options: SocketOptions,
+    // only for the tunnel socket, unlike socket_options.device
+    pub listen_interface: Option<ListenInterface>,
+    pub grow_buffer: Option<usize>,
+    // drop packets that filled the whole buffer instead of forwarding them
+    pub strict_mtu: bool,
+    pub bind_retries: usize,
+    pub tunnel_weight: u32,
+    // datagrams read per syscall once a socket is readable
+    pub batch: usize,
+    // for reproducible source addresses
+    pub rng_seed: Option<u64>,
+    pub psk: Option<Vec<u8>>,
+    pub keepalive: Option<time::Duration>,
+    // resends of an unanswered connect, each waiting twice as long
+    pub connect_retries: u32,
+    pub connect_timeout: time::Duration,
+    // pack data packets that are read together into one tunnel datagram
+    pub coalesce: bool,
+    // read every target socket in a task of its own, which sends straight
+    // into the tunnel, instead of in the main loop
+    pub task_per_connection: bool,
+}
+
+impl<T, U, V> ServerParams<'_, T, U, V>
+    where T: ToSocketAddrs,
+          U: ToSocketAddrs,
+          V: ToSocketAddrs {
+    // Sets up everything start_server would short of talking to the remote and
+    // returns the first problem found.
+    pub async fn validate(&self) -> Result<(), common::Error> {
+        self.format.as_ref().map(|f| DataLog::<OutputColumn>::new(f, DEFAULT_FORMAT)).transpose().map_err(common::Error::DataFormat)?;
+        if self.targets.is_empty() {
+            return Err(common::Error::NoTargets);
+        }
+        resolve_targets(&self.targets, self.mode).await.map_err(common::Error::TargetResolve)?;
+        common::open_tunnel(self.tunnel_addr.as_ref(), self.remote.as_ref(), self.mode, &self.socket_options, self.listen_interface.as_ref(), self.psk.as_deref()).await?;
+        Ok(())
+    }
+}
+
+pub fn list_columns() -> String {
+    output::list_columns::<OutputColumn>()
+}
+
+// Runs until the tunnel is drained or SIGINT/SIGTERM arrives.
+pub async fn start_server<T, U, V>(params: ServerParams<'_, T, U, V>) -> Result<(), common::Error>
+    where T: ToSocketAddrs,
+          U: ToSocketAddrs,
+          V: ToSocketAddrs {
+    let mut signal = SignalListener::shutdown();
+    start_server_until(params, signal.recv()).await
 }
 
-pub async fn start_server<T, U, V>(params: ServerParams<'_, T, U, V>)
+// Like start_server, but stops when `shutdown` completes instead of on a signal.
+pub async fn start_server_until<T, U, V>(mut params: ServerParams<'_, T, U, V>, shutdown: impl Future<Output=()>) -> Result<(), common::Error>
     where T: ToSocketAddrs,
           U: ToSocketAddrs,
           V: ToSocketAddrs {
+    tokio::pin!(shutdown);
+    // before anything is set up, a typo shouldn't have to wait for the handshake
+    let mut data_output = params.format.map(|f| DataLog::<OutputColumn>::new(&f, DEFAULT_FORMAT)).transpose().map_err(common::Error::DataFormat)?;
+    // the first connection would have nowhere to go
+    if params.targets.is_empty() {
+        return Err(common::Error::NoTargets);
+    }
     let mut buffer = vec![0; params.bufsize];
-    let mut tunnel_socket = setup_tunnel_socket(params.tunnel_addr, params.remote, params.mode, &mut buffer, TYPE_CLIENT).await.expect("failed to setup tunnel");
+    let mut events = EventSink::new(params.events);
+    // only the end that sent the connect can send it again
+    let connecting = params.tunnel_addr.is_none();
+    let features = middleware::features(&params.middleware) | if params.coalesce { FEATURE_COALESCE } else { 0 };
+    let handshake = Handshake {
+        mode: params.mode,
+        opts: params.socket_options.clone(),
+        interface: params.listen_interface.clone(),
+        remote_type: TYPE_CLIENT,
+        features,
+        psk: params.psk.clone(),
+        retries: params.connect_retries,
+        timeout: params.connect_timeout,
+    };
+    let mut tunnel_socket = setup_tunnel_socket(params.tunnel_addr, params.remote, &handshake, &mut buffer, &mut events).await?;
+    let forward = Arc::new(Forward::new(params.middleware.take(), params.filter.take(), params.stats.clone(), params.verbosity, params.print_data_buffer, params.bufsize));
+    if params.task_per_connection {
+        forward.set_tunnel(&tunnel_socket).map_err(common::Error::TunnelSocketShare)?;
+    }
+    // what the forwarding tasks did, empty without them
+    let (report_tx, mut reports) = mpsc::unbounded_channel();
+    // only when this end connected, a listening end counts the connects it answers
+    if tunnel_socket.version.is_some() {
+        params.stats.handshake_completed();
+    }
+    if let Some(rtt) = tunnel_socket.rtt {
+        params.stats.set_rtt(rtt);
+    }
+    let mut own_addr = tunnel_socket.local_addr().ok();
+    let mut reconnect = Reconnect::default();
+    let mut targets = resolve_targets(&params.targets, params.mode).await.map_err(common::Error::TargetResolve)?;
     let mut cache: Cache = Cache::new(params.timeout);
-    let data_output = params.format.map(|f| output::TableFormat::<OutputColumn>::parse_spec(f.with_default("[%d tunnel] client: %c lsock: %a dbuf: %l")).expect("failed to parse data log format"));
+    let mut control_rx = control::start(params.control).await.map_err(common::Error::ControlSocket)?;
+    let mut drain = Drain::new(params.drain_timeout);
+    let mut cleanup_timer = time::interval(params.cleanup_interval);
+    let mut keepalive = Keepalive::new(params.keepalive);
+    let mut drain_signal = SignalListener::drain();
+    let mut reload_signal = SignalListener::reload();
+    // packets in a row read from target sockets
+    let mut target_streak = 0;
+    let mut poll_order = PollOrder::default();
+    // index of the next address for sequential source allocation
+    let mut source_cursor = 0;
+    let mut target_cursor = 0;
+    let mut rng = common::seeded_rng(params.rng_seed);
+    let mut batched = Batch::new(params.batch);
+    let mut coalesce = Coalesce::new(params.coalesce, params.bufsize - DATA_HEADER_LEN);
 
     loop {
-        match poll_sockets(&tunnel_socket, &cache, &mut buffer[2..]).await {
+        let tunnel_first = params.target_batch.is_some_and(|batch| target_streak >= batch);
+        // whatever was held back for coalescing goes out before waiting
+        if batched.is_empty() {
+            if let Err(e) = coalesce.flush(&mut tunnel_socket).await {
+                error!("failed to send packet: {}", e);
+            }
+        }
+        let queued = batched.pop_into(&mut buffer[DATA_HEADER_LEN..]);
+        let from_batch = queued.is_some();
+        let packet = match queued {
+            Some(packet) => packet,
+            None => tokio::select! {
+                packet = poll_sockets(&tunnel_socket, params.tunnel_weight, &cache, &mut poll_order, &mut buffer[DATA_HEADER_LEN..], tunnel_first, !params.task_per_connection) => packet,
+                Some(req) = control::recv(&mut control_rx) => {
+                    let reply = match req.command {
+                        Command::List => control::format_list(&cache.snapshot()),
+                        Command::Stats => control::format_stats(&params.stats),
+                        Command::Drain => {
+                            drain.start(&params.stats);
+                            format!("draining, {} connections left\n", cache.active_count())
+                        }
+                        Command::Kill(id) => {
+                            let removed = id.parse().ok().and_then(|id| cache.remove(id));
+                            params.stats.set_connections(cache.active_count());
+                            if let Some(closed) = &removed {
+                                events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Killed });
+                                if params.log_connections {
+                                    log_closed(closed, CloseReason::Killed);
+                                }
+                                // or it keeps sending on the connection, which
+                                // gets mixed up with the next one using the ID
+                                let close = forward.close_packet(closed.cid);
+                                if let Err(e) = common::send_close(&mut tunnel_socket, close).await {
+                                    error!("failed to send close: {}", e);
+                                }
+                            }
+                            control::format_kill(&id, removed)
+                        }
+                        // connections here are keyed by the tunnel peer, which can't roam
+                        Command::Rebind(..) => "error: rebinding connections is only supported at the entry end\n".to_string(),
+                        Command::Weight(..) if params.task_per_connection => "error: weights have no effect with a task per connection\n".to_string(),
+                        Command::Weight(id, weight) => {
+                            let found = id.parse().is_ok_and(|id| cache.set_weight(id, weight));
+                            control::format_weight(&id, weight, found)
+                        }
+                    };
+                    let _ = req.reply.send(reply);
+                    continue;
+                }
+                _ = drain_signal.recv() => {
+                    drain.start(&params.stats);
+                    info!("draining, {} connections left", cache.active_count());
+                    continue;
+                }
+                _ = reload_signal.recv() => {
+                    let config = match params.reload.as_ref().map(|r| r()) {
+                        Some(Ok(config)) => config,
+                        Some(Err(e)) => {
+                            warn!("failed to reload config, keeping the current one: {}", e);
+                            continue;
+                        }
+                        None => {
+                            warn!("no config file given, nothing to reload");
+                            continue;
+                        }
+                    };
+                    let mode = params.mode;
+                    if config.source_format.as_ref().is_some_and(|sf| !sf.allowed_in(mode)) {
+                        warn!("failed to reload config, keeping the current one: the source format does not match the selected IP version");
+                        continue;
+                    }
+                    if config.targets.is_empty() {
+                        warn!("failed to reload config, keeping the current one: no targets");
+                        continue;
+                    }
+                    let new_targets = match resolve_targets(&config.targets, params.mode).await {
+                        Ok(t) => t,
+                        Err(e) => {
+                            warn!("failed to reload config, keeping the current one: failed to resolve target: {}", e);
+                            continue;
+                        }
+                    };
+                    let new_output = match (&data_output, &config.format) {
+                        // a format from the config doesn't replace JSON output
+                        (Some(DataLog::Table(_)), Some(f)) => match output::TableFormat::<OutputColumn>::parse_spec(f) {
+                            Ok(t) => Some(DataLog::Table(t)),
+                            Err(e) => {
+                                warn!("failed to reload config, keeping the current one: failed to parse data log format: {}", e);
+                                continue;
+                            }
+                        },
+                        _ => None,
+                    };
+                    // existing connections keep their socket and target
+                    targets = new_targets;
+                    params.source_format = config.source_format;
+                    source_cursor = 0;
+                    target_cursor = 0;
+                    if new_output.is_some() {
+                        data_output = new_output;
+                    }
+                    info!("reloaded config, {} targets", targets.len());
+                    continue;
+                }
+                _ = keepalive.due() => {
+                    // nobody to send to before the handshake
+                    if tunnel_socket.version.is_some() {
+                        if let Err(e) = tunnel_socket.send(&[PACKET_KEEPALIVE]).await {
+                            error!("failed to send keepalive: {}", e);
+                        }
+                    }
+                    keepalive.sent();
+                    continue;
+                }
+                result = reconnect.done() => {
+                    match result {
+                        Ok((session, _)) => {
+                            tunnel_socket = session;
+                            own_addr = tunnel_socket.local_addr().ok();
+                            if params.task_per_connection {
+                                if let Err(e) = forward.set_tunnel(&tunnel_socket) {
+                                    error!("failed to share tunnel socket with the forwarding tasks: {}", e);
+                                }
+                            }
+                            params.stats.handshake_completed();
+                            if let Some(rtt) = tunnel_socket.rtt {
+                                params.stats.set_rtt(rtt);
+                            }
+                        }
+                        Err(e) => error!("failed to connect again: {}", e),
+                    }
+                    continue;
+                }
+                Some(report) = reports.recv() => {
+                    match report {
+                        Report::Sent { id, time, at, len } => {
+                            let entry = match cache.get_by_id_mut(id) {
+                                Some(entry) => entry,
+                                None => continue,
+                            };
+                            keepalive.sent();
+                            entry.stats.record(DataDirection::IntoTunnel, len);
+                            entry.rate.update(at, len);
+                            params.stats.target(entry.target, |t| t.bytes_out += len as u64);
+                            if let Some(data_log) = &data_output {
+                                let info = DataPacketInfo {
+                                    time,
+                                    to_tunnel: true,
+                                    client: id,
+                                    tunnel_socket: entry.socket.local_addr().ok(),
+                                    data_len: len,
+                                    jitter: entry.jitter.map(|j| j.millis()),
+                                    rate: entry.rate.bytes_per_sec(at),
+                                    packet: Some(entry.stats.packets()),
+                                    bytes: Some(entry.stats.bytes()),
+                                };
+                                data_log.write(&info);
+                            }
+                        }
+                        Report::Unreachable(id) => close_unreachable(&mut cache, id, &forward, &mut events, params.log_connections, &mut tunnel_socket).await,
+                        Report::SendError(id, e) => {
+                            error!("failed to send packet: {}", e);
+                            events.emit(TunnelEvent::SendError { cid: id.cid, error: e.to_string() });
+                        }
+                        Report::RecvError(id, e) => {
+                            if let Some(entry) = cache.get_by_id_mut(id) {
+                                params.stats.target(entry.target, |t| t.errors += 1);
+                            }
+                            error!("recv error from {}, ignoring: {}", Direction::IntoTunnel(id), e);
+                        }
+                    }
+                    continue;
+                }
+                timed_out = drain.tick() => {
+                    if timed_out || cache.active_count() == 0 {
+                        info!("drain finished, {} connections left, peak was {}", cache.active_count(), params.stats.peak_connections.load(Ordering::Relaxed));
+                        return Ok(());
+                    }
+                    continue;
+                }
+                _ = cleanup_timer.tick() => {
+                    cache.expire_idle();
+                    if cache.expired_count() > 0 {
+                        if params.verbosity >= 1 {
+                            debug!("cleaning up {} timed out connections", cache.expired_count());
+                        }
+                        for closed in cache.cleanup() {
+                            events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Timeout });
+                            if params.log_connections {
+                                log_closed(&closed, CloseReason::Timeout);
+                            }
+                            if params.log_expiry {
+                                log_expired(&closed);
+                            }
+                        }
+                        params.stats.set_connections(cache.active_count());
+                    }
+                    continue;
+                }
+                _ = &mut shutdown => {
+                    cache.cleanup();
+                    info!("shutting down, {} connections were active, peak was {}", cache.active_count(), params.stats.peak_connections.load(Ordering::Relaxed));
+                    return Ok(());
+                }
+            },
+        };
+        poll_order.fair.serviced(packet.0);
+        if let (_, Ok((size, sender_addr))) = &packet {
+            if common::check_truncated(&mut buffer, DATA_HEADER_LEN, *size, *sender_addr, &mut params.grow_buffer, params.strict_mtu) {
+                params.stats.reject(Rejection::Truncated);
+                continue;
+            }
+        }
+        if !from_batch && packet.1.is_ok() {
+            let socket = match packet.0 {
+                Direction::FromTunnel => Some(&*tunnel_socket),
+                Direction::IntoTunnel(id) => cache.socket(id),
+            };
+            if let Some(socket) = socket {
+                batched.fill(packet.0, socket, buffer.len() - DATA_HEADER_LEN);
+            }
+        }
+        match packet.0 {
+            Direction::IntoTunnel(_) => target_streak += 1,
+            Direction::FromTunnel => target_streak = 0,
+        }
+        match packet {
             (dir, Ok((size, sender_addr))) => {
                 match dir {
                     Direction::FromTunnel => {
-                        let buffer = &mut buffer[2..];
+                        let buffer = &mut buffer[DATA_HEADER_LEN..];
                         if size == 0 { continue; }
+                        if own_addr.is_some_and(|own| common::is_own_addr(own, sender_addr)) {
+                            reject(&params.stats, params.verbosity, sender_addr, Rejection::Loopback, "packet from the tunnel socket itself");
+                            continue;
+                        }
                         match buffer[0] {
+                            PACKET_CONNECT if drain.is_draining() => {
+                                warn!("draining, ignoring connect from {}", sender_addr);
+                            }
                             PACKET_CONNECT => {
-                                respond_connect(&mut tunnel_socket, sender_addr, buffer, TYPE_SERVER).await;
+                                match respond_connect(&mut tunnel_socket, sender_addr, buffer, size, TYPE_SERVER, features, &mut events).await {
+                                    // not the peer's fault, keep serving and wait for it to retry
+                                    Err(common::Error::RemoteConnect(e)) | Err(common::Error::ConnectRespond(e)) => {
+                                        error!("failed to answer connect from {}: {}", sender_addr, e);
+                                    }
+                                    Err(e) => reject(&params.stats, params.verbosity, sender_addr, Rejection::from(&e), e),
+                                    Ok(()) => params.stats.handshake_completed(),
+                                }
+                            }
+                            // only there to keep the path open, it doesn't
+                            // refresh any connection: keepalives go out exactly
+                            // when the tunnel is idle, so if they refreshed
+                            // connections, two ends with --keepalive would keep
+                            // each other's connections from ever timing out
+                            PACKET_KEEPALIVE => {
+                                // the other end is watching whether this one is still there
+                                if size >= 2 && buffer[1] == KEEPALIVE_PROBE {
+                                    if let Err(e) = tunnel_socket.send(&[PACKET_KEEPALIVE]).await {
+                                        error!("failed to answer keepalive: {}", e);
+                                    }
+                                }
+                            }
+                            PACKET_COALESCED if tunnel_socket.features & FEATURE_COALESCE != 0 => {
+                                if !batched.split(Direction::FromTunnel, &buffer[1..size], sender_addr) {
+                                    reject(&params.stats, params.verbosity, sender_addr, Rejection::Malformed, "coalesced packet lengths don't add up");
+                                }
+                            }
+                            PACKET_CLOSE => {
+                                if size < DATA_HEADER_LEN {
+                                    reject(&params.stats, params.verbosity, sender_addr, Rejection::Malformed, "too small for close");
+                                    continue;
+                                }
+                                let id = ConnId { from: sender_addr, cid: read_cid(buffer) };
+                                if let Err(e) = middleware::inbound(&mut forward.middleware.lock().unwrap(), id.cid, &mut buffer[DATA_HEADER_LEN..], size - DATA_HEADER_LEN) {
+                                    reject(&params.stats, params.verbosity, id, Rejection::from(&e), e);
+                                    continue;
+                                }
+                                if let Some(closed) = cache.remove(id) {
+                                    params.stats.set_connections(cache.active_count());
+                                    events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Remote });
+                                    if params.log_connections {
+                                        log_closed(&closed, CloseReason::Remote);
+                                    }
+                                }
                             }
                             PACKET_DATA => {
-                                let buffer = &mut buffer[..size];
-                                if buffer.len() < 2 {
-                                    eprintln!("packet from {} too small for data, ignoring", sender_addr);
+                                if size < DATA_HEADER_LEN {
+                                    reject(&params.stats, params.verbosity, sender_addr, Rejection::Malformed, "too small for data");
                                     continue;
                                 }
-                                let id = ConnId { from: sender_addr, cid: buffer[1] };
-                                let socket = if let Some(CacheEntry { socket, .. }) = cache.get_by_id_mut(id) {
-                                    socket
+                                let id = ConnId { from: sender_addr, cid: read_cid(buffer) };
+                                let len = match middleware::inbound(&mut forward.middleware.lock().unwrap(), id.cid, &mut buffer[DATA_HEADER_LEN..], size - DATA_HEADER_LEN) {
+                                    Ok(len) => len,
+                                    Err(e) => {
+                                        reject(&params.stats, params.verbosity, id, Rejection::from(&e), e);
+                                        continue;
+                                    }
+                                };
+                                let buffer = &mut buffer[DATA_HEADER_LEN..DATA_HEADER_LEN + len];
+                                if !filter::accepts(&forward.filter, &Packet { direction: DataDirection::FromTunnel, cid: id.cid, payload: buffer }) {
+                                    reject(&params.stats, params.verbosity, id, Rejection::Filtered, "payload did not pass the filters");
+                                    continue;
+                                }
+                                let entry = if let Some(entry) = cache.get_by_id_mut(id) {
+                                    entry
                                 } else {
-                                    match create_socket(&params.target, &params.source_format, params.mode).await {
-                                        Ok(s) => &mut cache.insert(id, s).socket,
+                                    if drain.is_draining() {
+                                        warn!("draining, dropping packet for new connection {}", id);
+                                        continue;
+                                    }
+                                    if let Some(max) = params.max_conns_per_source {
+                                        if cache.active_for_peer(id.from) >= max {
+                                            reject(&params.stats, params.verbosity, id, Rejection::Quota, format!("peer already has {} connections", max));
+                                            continue;
+                                        }
+                                    }
+                                    let hash = conn_hash(id, params.hash_key);
+                                    let target = select_target(&targets, params.target_select, &mut target_cursor, hash);
+                                    match create_socket(target, &params.source_format, params.source_alloc, &mut source_cursor, &mut rng, hash, params.mode, &params.socket_options, params.bind_retries, &params.stats).await {
+                                        Ok((s, target)) => {
+                                            for closed in cache.cleanup() {
+                                                events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Timeout });
+                                                if params.log_connections {
+                                                    log_closed(&closed, CloseReason::Timeout);
+                                                }
+                                                if params.log_expiry {
+                                                    log_expired(&closed);
+                                                }
+                                            }
+                                            events.emit(TunnelEvent::ConnectionOpened { cid: id.cid, peer: id.from });
+                                            if params.log_connections {
+                                                log_opened(id.cid, id.from, s.local_addr().ok(), Some(target));
+                                            }
+                                            let task = if params.task_per_connection {
+                                                match sockopt::try_clone(&s).and_then(UdpSocket::from_std) {
+                                                    Ok(recv_socket) => Some(server_task::spawn(id, recv_socket, forward.clone(), params.bufsize, params.grow_buffer, params.strict_mtu, report_tx.clone())),
+                                                    Err(e) => {
+                                                        error!("failed to start forwarding task: {}", e);
+                                                        continue;
+                                                    }
+                                                }
+                                            } else {
+                                                None
+                                            };
+                                            params.stats.set_connections(cache.active_count() + 1);
+                                            let entry = cache.insert(id, s, target);
+                                            entry.task = task;
+                                            entry
+                                        }
                                         Err(e) => {
-                                            eprintln!("failed to open client socket: {}", e);
+                                            error!("failed to open client socket: {}", e);
                                             continue;
                                         }
                                     }
                                 };
-                                if let Some(data_table) = &data_output {
+                                entry.stats.record(DataDirection::FromTunnel, buffer.len());
+                                params.stats.record(DataDirection::FromTunnel, buffer.len());
+                                let now = Instant::now();
+                                entry.rate.update(now, buffer.len());
+                                let target = entry.target;
+                                if params.jitter {
+                                    entry.jitter.get_or_insert_with(Jitter::default).update(now);
+                                }
+                                let jitter = entry.jitter.map(|j| j.millis());
+                                let rate = entry.rate.bytes_per_sec(now);
+                                let packet = entry.stats.packets();
+                                let bytes = entry.stats.bytes();
+                                let socket = &mut entry.socket;
+                                if let Some(data_log) = &data_output {
                                     let info = DataPacketInfo {
+                                        time: Local::now(),
                                         to_tunnel: false,
                                         client: id,
                                         tunnel_socket: socket.local_addr().ok(),
-                                        data_len: buffer.len() - 2,
+                                        data_len: buffer.len(),
+                                        jitter,
+                                        rate,
+                                        packet: Some(packet),
+                                        bytes: Some(bytes),
                                     };
-                                    println!("{}", data_table.bind(&info));
+                                    data_log.write(&info);
                                 }
-                                if let Err(e) = socket.send(&buffer[2..]).await {
-                                    eprintln!("failed to send packet: {}", e);
+                                if params.print_data_buffer {
+                                    common::dump_payload(DataDirection::FromTunnel, id.cid, buffer);
+                                }
+                                match socket.send(buffer).await {
+                                    Ok(_) => params.stats.target(target, |t| t.bytes_in += buffer.len() as u64),
+                                    Err(e) => {
+                                        params.stats.target(target, |t| t.errors += 1);
+                                        error!("failed to send packet: {}", e);
+                                        events.emit(TunnelEvent::SendError { cid: id.cid, error: e.to_string() });
+                                    }
                                 }
                             }
-                            _ => eprintln!("ignoring invalid packet type ${:02X} from {}", buffer[0], sender_addr)
+                            _ => reject(&params.stats, params.verbosity, sender_addr, Rejection::UnknownPacket, format!("${:02X}, first bytes: {}", buffer[0], common::head_bytes(&buffer[..size]))),
                         }
                     }
                     Direction::IntoTunnel(id) => {
-                        buffer[0] = PACKET_DATA;
-                        buffer[1] = id.cid;
-                        if let Some(data_table) = &data_output {
+                        // %l is the payload length in both directions
+                        let payload_len = size;
+                        let len = match forward.prepare(id, &mut buffer, size) {
+                            Some(len) => len,
+                            None => continue,
+                        };
+                        let now = Instant::now();
+                        // looked up once, the data log below needs it too
+                        let mut entry = cache.get_by_id_mut(id);
+                        if let Some(entry) = entry.as_mut() {
+                            entry.stats.record(DataDirection::IntoTunnel, payload_len);
+                            entry.rate.update(now, payload_len);
+                            params.stats.target(entry.target, |t| t.bytes_out += payload_len as u64);
+                        }
+                        if let Some(data_log) = &data_output {
                             let info = DataPacketInfo {
+                                time: Local::now(),
                                 to_tunnel: true,
                                 client: id,
-                                tunnel_socket: cache.get_by_id_mut(id).and_then(|s| s.socket.local_addr().ok()),
-                                data_len: size,
+                                tunnel_socket: entry.as_ref().and_then(|e| e.socket.local_addr().ok()),
+                                data_len: payload_len,
+                                jitter: entry.as_ref().and_then(|e| e.jitter).map(|j| j.millis()),
+                                rate: entry.as_mut().and_then(|e| e.rate.bytes_per_sec(now)),
+                                packet: entry.as_ref().map(|e| e.stats.packets()),
+                                bytes: entry.as_ref().map(|e| e.stats.bytes()),
                             };
-                            println!("{}", data_table.bind(&info));
+                            data_log.write(&info);
                         }
-                        if let Err(e) = tunnel_socket.send(&buffer[..size + 2]).await {
-                            eprintln!("failed to send packet: {}", e);
+                        keepalive.sent();
+                        if let Err(e) = coalesce.send(&mut tunnel_socket, &buffer[..len]).await {
+                            error!("failed to send packet: {}", e);
+                            events.emit(TunnelEvent::SendError { cid: id.cid, error: e.to_string() });
                         }
                     }
                 }
             }
+            // the other end went away, most likely restarted
+            (Direction::FromTunnel, Err(e)) if connecting && common::is_unreachable(&e) => {
+                if reconnect.is_running() {
+                    continue;
+                }
+                match sockopt::peer_addr(&tunnel_socket) {
+                    Ok(peer) => {
+                        warn!("tunnel peer unreachable ({}), connecting again", e);
+                        reconnect.start(vec![peer], 0, &handshake, buffer.len(), events.clone());
+                    }
+                    Err(e) => error!("failed to connect again: {}", e),
+                }
+            }
+            // the target isn't there (anymore), so the connection is over
+            (Direction::IntoTunnel(id), Err(e)) if common::is_unreachable(&e) => {
+                close_unreachable(&mut cache, id, &forward, &mut events, params.log_connections, &mut tunnel_socket).await;
+            }
             (dir, Err(e)) => {
-                eprintln!("recv error from {}, ignoring: {}", dir, e);
+                if let Direction::IntoTunnel(id) = dir {
+                    if let Some(entry) = cache.get_by_id_mut(id) {
+                        params.stats.target(entry.target, |t| t.errors += 1);
+                    }
+                }
+                error!("recv error from {}, ignoring: {}", dir, e);
             }
         }
     }
 }
 
-async fn poll_sockets(tunnel_socket: &UdpSocket, cache: &Cache, buf: &mut [u8]) -> (Direction, io::Result<(usize, SocketAddr)>) {
-    let mut all = Vec::with_capacity(cache.len_max() + 1);
-    all.push((Direction::FromTunnel, tunnel_socket));
-    all.extend(cache.iter().map(|e| (Direction::IntoTunnel(e.id), &e.socket)));
-    all.shuffle(&mut ThreadRng::default());
+// The sockets to poll with their weights and the round-robin credit they
+// built up, kept across packets. The sockets are only set again when the
+// cache changes, which keeps the credit of those still there.
+#[derive(Default)]
+struct PollOrder {
+    generation: Option<u64>,
+    fair: FairOrder<Direction>,
+}
+
+impl PollOrder {
+    fn update(&mut self, cache: &Cache, tunnel_weight: u32) {
+        if self.generation == Some(cache.generation()) {
+            return;
+        }
+        let tunnel = std::iter::once((Direction::FromTunnel, tunnel_weight));
+        self.fair.set(tunnel.chain(cache.poll_entries().map(|(id, weight)| (Direction::IntoTunnel(id), weight))));
+        self.generation = Some(cache.generation());
+    }
+}
+
+// Sockets take turns by weight, unless `tunnel_first` is set, which gives the
+// tunnel socket priority over all target sockets. Without `targets` only the
+// tunnel socket is polled, the target sockets have tasks of their own then.
+#[allow(clippy::too_many_arguments)]
+async fn poll_sockets(tunnel_socket: &UdpSocket, tunnel_weight: u32, cache: &Cache, order: &mut PollOrder, buf: &mut [u8], tunnel_first: bool, targets: bool) -> (Direction, io::Result<(usize, SocketAddr)>) {
+    order.update(cache, tunnel_weight);
+    order.fair.sort();
 
-    let (d, r) = common::poll_sockets(&all, buf).await;
-    (*d, r)
+    let fair = &order.fair;
+    // polled first and left out of the rest
+    let tunnel = if tunnel_first { Some(Direction::FromTunnel) } else { None };
+    common::poll_sockets(|| tunnel.into_iter().chain(fair.keys().filter(move |&d| tunnel != Some(d))).filter_map(|d| match d {
+        Direction::FromTunnel => Some((d, tunnel_socket)),
+        Direction::IntoTunnel(id) if targets => cache.socket(id).map(|sock| (d, sock)),
+        Direction::IntoTunnel(_) => None,
+    }), buf).await
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct ConnId {
     from: SocketAddr,
-    cid: u8,
+    cid: u16,
+}
+
+impl ConnId {
+    pub fn peer(&self) -> SocketAddr { self.from }
+
+    pub fn cid(&self) -> u16 { self.cid }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -130,6 +704,17 @@ enum Direction {
     IntoTunnel(ConnId),
 }
 
+impl FromStr for ConnId {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pos = s.find('@').ok_or(())?;
+        let cid = s[..pos].parse().map_err(|_| ())?;
+        let from = s[pos + 1..].parse().map_err(|_| ())?;
+        Ok(ConnId { from, cid })
+    }
+}
+
 impl Display for ConnId {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(f, "{}@{}", self.cid, self.from)
@@ -145,23 +730,228 @@ impl Display for Direction {
     }
 }
 
-async fn create_socket(target: impl ToSocketAddrs, sf: &Option<SourceFormat>, mode: IpMode) -> io::Result<UdpSocket> {
-    let a = sf.map(|sf| sf.get_addr(ThreadRng::default())).unwrap_or_else(|| default_listen_ip(mode));
-    println!("creating socket on {}", a);
-    let socket = UdpSocket::bind(a).await?;
-    socket.connect(target).await?;
-    Ok(socket)
+// The target isn't there (anymore), so the connection is over, and the other
+// end is told so.
+async fn close_unreachable(cache: &mut Cache, id: ConnId, forward: &Forward, events: &mut EventSink, log_connections: bool, tunnel_socket: &mut TunnelSession) {
+    let stats = &forward.stats;
+    if let Some(closed) = cache.remove(id) {
+        if let Some(target) = closed.target {
+            stats.target(target, |t| t.errors += 1);
+        }
+        stats.set_connections(cache.active_count());
+        events.emit(TunnelEvent::ConnectionClosed { cid: closed.cid, peer: closed.peer, reason: CloseReason::Refused });
+        if log_connections {
+            log_closed(&closed, CloseReason::Refused);
+        }
+    }
+    let close = forward.close_packet(id.cid);
+    if let Err(e) = common::send_close(tunnel_socket, close).await {
+        error!("failed to send close: {}", e);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn create_socket(target: SocketAddr, sf: &Option<SourceFormat>, alloc: SourceAlloc, cursor: &mut u64, rng: &mut SmallRng, hash: u64, mode: IpMode, opts: &SocketOptions, retries: usize, stats: &Stats) -> io::Result<(UdpSocket, SocketAddr)> {
+    let socket = match (sf, alloc) {
+        (Some(sf), SourceAlloc::Sticky) => bind_sticky(sf, hash, opts).await?,
+        (Some(sf), SourceAlloc::Sequential) => bind_sequential(sf, cursor, retries, opts).await?,
+        (Some(sf), _) => bind_random(sf, rng, retries, opts).await?,
+        (None, _) => {
+            let a = default_listen_ip(mode);
+            if log::verbose(2) {
+                debug!("creating socket on {}", a);
+            }
+            sockopt::bind(a, opts).await?
+        }
+    };
+    if let Err(e) = socket.connect(target).await {
+        stats.target(target, |t| t.errors += 1);
+        return Err(e);
+    }
+    stats.target(target, |t| t.connections += 1);
+    Ok((socket, target))
+}
+
+async fn resolve_targets<T: ToSocketAddrs>(targets: &[T], mode: IpMode) -> io::Result<Vec<SocketAddr>> {
+    let mut resolved = Vec::with_capacity(targets.len());
+    for target in targets {
+        let addr = lookup_host(target).await?
+            .find(|a| mode.allows(a))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no target address for the selected IP mode"))?;
+        resolved.push(addr);
+    }
+    Ok(resolved)
+}
+
+fn select_target(targets: &[SocketAddr], select: TargetSelect, cursor: &mut usize, hash: u64) -> SocketAddr {
+    match select {
+        TargetSelect::Hash => hash_target(targets, hash),
+        TargetSelect::RoundRobin => {
+            let target = targets[*cursor % targets.len()];
+            *cursor = cursor.wrapping_add(1);
+            target
+        }
+    }
+}
+
+// rendezvous hashing, so adding or removing a target only moves the
+// connections that were mapped to that target
+fn hash_target(targets: &[SocketAddr], hash: u64) -> SocketAddr {
+    *targets.iter()
+        .max_by_key(|t| {
+            let mut hasher = DefaultHasher::new();
+            hash.hash(&mut hasher);
+            t.hash(&mut hasher);
+            hasher.finish()
+        })
+        .expect("no targets")
+}
+
+// draws a fresh address whenever the previous one is already taken
+async fn bind_random(sf: &SourceFormat, rng: &mut SmallRng, retries: usize, opts: &SocketOptions) -> io::Result<UdpSocket> {
+    let mut attempt = 0;
+    loop {
+        let a = sf.get_addr(&mut *rng);
+        if log::verbose(2) {
+            debug!("creating socket on {}", a);
+        }
+        match sockopt::bind(a, opts).await {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempt < retries => attempt += 1,
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                error!("giving up on binding a source address after {} attempts", attempt + 1);
+                return Err(e);
+            }
+            r => return r,
+        }
+    }
+}
+
+// takes the address at `cursor` and moves it on, skipping addresses that are
+// already taken
+async fn bind_sequential(sf: &SourceFormat, cursor: &mut u64, retries: usize, opts: &SocketOptions) -> io::Result<UdpSocket> {
+    let size = sf.size();
+    if size == 0 {
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "empty source format"));
+    }
+    let mut attempt = 0;
+    loop {
+        let a = sf.nth_addr(*cursor % size);
+        *cursor = (*cursor % size + 1) % size;
+        if log::verbose(2) {
+            debug!("creating socket on {}", a);
+        }
+        match sockopt::bind(a, opts).await {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempt < retries => attempt += 1,
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                error!("giving up on binding a source address after {} attempts", attempt + 1);
+                return Err(e);
+            }
+            r => return r,
+        }
+    }
+}
+
+const STICKY_CANDIDATES: u64 = 16;
+
+async fn bind_sticky(sf: &SourceFormat, hash: u64, opts: &SocketOptions) -> io::Result<UdpSocket> {
+    let size = sf.size();
+    if size == 0 {
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "empty source format"));
+    }
+    let start = hash % size;
+    let mut last_err = None;
+    for i in 0..STICKY_CANDIDATES.min(size) {
+        let a = sf.nth_addr((start + i) % size);
+        if log::verbose(2) {
+            debug!("creating socket on {}", a);
+        }
+        match sockopt::bind(a, opts).await {
+            Ok(s) => return Ok(s),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => last_err = Some(e),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_err.unwrap())
+}
+
+fn conn_hash(id: ConnId, key: HashKey) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match key {
+        HashKey::Connection => id.hash(&mut hasher),
+        HashKey::Peer => id.from.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HashKey {
+    Connection,
+    Peer,
+}
+
+impl FromStr for HashKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connection" => Ok(HashKey::Connection),
+            "peer" => Ok(HashKey::Peer),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TargetSelect {
+    // a client sticks to one target, see HashKey
+    Hash,
+    RoundRobin,
+}
+
+impl FromStr for TargetSelect {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(TargetSelect::Hash),
+            "round-robin" => Ok(TargetSelect::RoundRobin),
+            _ => Err(()),
+        }
+    }
 }
 
 struct DataPacketInfo {
+    time: DateTime<Local>,
     to_tunnel: bool,
     client: ConnId,
     tunnel_socket: Option<SocketAddr>,
     data_len: usize,
+    jitter: Option<f64>,
+    rate: Option<f64>,
+    packet: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl output::Record for DataPacketInfo {
+    fn record(&self) -> DataRecord {
+        DataRecord {
+            timestamp: self.time.to_rfc3339_opts(SecondsFormat::Millis, false),
+            direction: if self.to_tunnel { "into_tunnel" } else { "from_tunnel" },
+            cid: self.client.cid,
+            peer: self.client.from,
+            local: self.tunnel_socket,
+            len: self.data_len,
+            jitter_ms: self.jitter,
+            rate: self.rate,
+            packet: self.packet,
+            bytes: self.bytes,
+        }
+    }
 }
 
 #[derive(Hash, Eq, PartialEq, Copy, Clone)]
 enum OutputColumn {
+    Timestamp,
     Direction,
     RevDirection,
     Client,
@@ -169,13 +959,20 @@ enum OutputColumn {
     Peer,
     TunnelSocket,
     DataLen,
+    Jitter,
+    Rate,
+    PacketCount,
+    Bytes,
 }
 
 impl output::Column for OutputColumn {
     type Data = DataPacketInfo;
 
+    const CHARS: &'static str = "TdDcipaljrnB";
+
     fn by_char(ch: char) -> Option<Self> {
         match ch {
+            'T' => Some(OutputColumn::Timestamp),
             'd' => Some(OutputColumn::Direction),
             'D' => Some(OutputColumn::RevDirection),
             'c' => Some(OutputColumn::Client),
@@ -183,12 +980,35 @@ impl output::Column for OutputColumn {
             'p' => Some(OutputColumn::Peer),
             'a' => Some(OutputColumn::TunnelSocket),
             'l' => Some(OutputColumn::DataLen),
+            'j' => Some(OutputColumn::Jitter),
+            'r' => Some(OutputColumn::Rate),
+            'n' => Some(OutputColumn::PacketCount),
+            'B' => Some(OutputColumn::Bytes),
+            _ => None,
+        }
+    }
+
+    fn describe(ch: char) -> Option<&'static str> {
+        match ch {
+            'T' => Some("time the packet was handled, see --timestamp-format"),
+            'd' => Some("direction, => into the tunnel and <= out of it"),
+            'D' => Some("direction seen from the other end, the reverse of %d"),
+            'c' => Some("connection, as ID@peer"),
+            'i' => Some("connection ID"),
+            'p' => Some("tunnel peer address the connection came from"),
+            'a' => Some("local address of the connection's target socket"),
+            'l' => Some("payload length in bytes"),
+            'j' => Some("jitter of the connection in milliseconds, needs --jitter"),
+            'r' => Some("data rate of the connection"),
+            'n' => Some("packets transferred on the connection so far"),
+            'B' => Some("bytes transferred on the connection so far"),
             _ => None,
         }
     }
 
     fn to_string<'a>(&'a self, data: &'a Self::Data) -> Cow<'a, str> {
         match self {
+            OutputColumn::Timestamp => data.time.format(output::time_format()).to_string().into(),
             OutputColumn::Direction => if data.to_tunnel { "=>" } else { "<=" }.into(),
             OutputColumn::RevDirection => if data.to_tunnel { "<=" } else { "=>" }.into(),
             OutputColumn::Client => format!("{}", data.client).into(),
@@ -196,17 +1016,79 @@ impl output::Column for OutputColumn {
             OutputColumn::Peer => format!("{}", data.client.from).into(),
             OutputColumn::TunnelSocket => if let Some(s) = data.tunnel_socket { format!("{}", s).into() } else { "???".into() },
             OutputColumn::DataLen => format!("{}", data.data_len).into(),
+            OutputColumn::Jitter => if let Some(j) = data.jitter { format!("{:.2}ms", j).into() } else { "-".into() },
+            OutputColumn::Rate => if let Some(r) = data.rate { stats::format_rate(r).into() } else { "-".into() },
+            OutputColumn::PacketCount => if let Some(n) = data.packet { format!("{}", n).into() } else { "-".into() },
+            OutputColumn::Bytes => if let Some(n) = data.bytes { stats::format_bytes(n).into() } else { "-".into() },
         }
     }
 
     fn constant_size(&self) -> bool {
-        *self == OutputColumn::Direction
+        matches!(self, OutputColumn::Timestamp | OutputColumn::Direction)
+    }
+
+    fn color(&self, data: &Self::Data) -> Option<Color> {
+        match self {
+            OutputColumn::Timestamp => Some(Color::Dim),
+            OutputColumn::Direction | OutputColumn::RevDirection => Some(if data.to_tunnel { Color::Green } else { Color::Cyan }),
+            _ => None,
+        }
     }
 
     fn alignment(&self) -> Alignment {
         match self {
-            OutputColumn::ClientId | OutputColumn::DataLen => Alignment::Right,
+            OutputColumn::ClientId | OutputColumn::DataLen | OutputColumn::Jitter | OutputColumn::Rate | OutputColumn::PacketCount | OutputColumn::Bytes => Alignment::Right,
             _ => Alignment::Left
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn targets(n: u16) -> Vec<SocketAddr> {
+        (0..n).map(|i| SocketAddr::from(([10, 0, 0, 1], 1000 + i))).collect()
+    }
+
+    #[test]
+    fn hash_spreads_over_targets() {
+        let targets = targets(4);
+        let mut counts = HashMap::new();
+        for hash in 0..1000 {
+            *counts.entry(hash_target(&targets, hash)).or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        assert!(counts.values().all(|&n| n > 150), "{:?}", counts);
+    }
+
+    #[test]
+    fn removing_target_only_moves_its_connections() {
+        let (all, fewer) = (targets(4), targets(3));
+        for hash in 0..1000 {
+            let before = hash_target(&all, hash);
+            if before != all[3] {
+                assert_eq!(hash_target(&fewer, hash), before);
+            }
+        }
+    }
+
+    #[test]
+    fn adding_target_only_moves_connections_to_it() {
+        let (all, more) = (targets(4), targets(5));
+        for hash in 0..1000 {
+            let after = hash_target(&more, hash);
+            assert!(after == more[4] || after == hash_target(&all, hash));
+        }
+    }
+
+    #[test]
+    fn peer_hash_ignores_connection_id() {
+        let a: ConnId = "1@127.0.0.1:1000".parse().unwrap();
+        let b: ConnId = "2@127.0.0.1:1000".parse().unwrap();
+        assert_eq!(conn_hash(a, HashKey::Peer), conn_hash(b, HashKey::Peer));
+        assert_ne!(conn_hash(a, HashKey::Connection), conn_hash(b, HashKey::Connection));
+    }
 }
\ No newline at end of file
@@ -1,25 +1,55 @@
 use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 
 use chrono::{DateTime, Duration, Local};
 use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
 
 use crate::server::ConnId;
+use crate::stats::{ConnectionInfo, ConnStats, Jitter, Rate};
 
 pub struct Cache {
     timeout: Duration,
     by_id: HashMap<ConnId, CacheEntryOuter>,
+    by_peer: HashMap<SocketAddr, HashSet<ConnId>>,
     expired: RefCell<HashSet<ConnId>>,
+    // changes whenever connections are added or removed or their weight
+    // changes, so the poll order only needs rebuilding then
+    generation: u64,
 }
 
 struct CacheEntryOuter {
+    created: DateTime<Local>,
     last_access: Cell<DateTime<Local>>,
     data: CacheEntry,
 }
 
+impl CacheEntryOuter {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            cid: self.data.id.cid(),
+            peer: self.data.id.peer(),
+            local: self.data.socket.local_addr().ok(),
+            target: Some(self.data.target),
+            created: self.created,
+            last_access: self.last_access.get(),
+            stats: self.data.stats,
+            jitter: self.data.jitter.map(|j| j.millis()),
+        }
+    }
+}
+
 pub struct CacheEntry {
     pub id: ConnId,
     pub socket: UdpSocket,
+    pub target: SocketAddr,
+    pub stats: ConnStats,
+    pub jitter: Option<Jitter>,
+    pub rate: Rate,
+    pub weight: u32,
+    // the task reading the socket stops once this is dropped
+    pub task: Option<oneshot::Sender<()>>,
 }
 
 impl Cache {
@@ -27,16 +57,20 @@ impl Cache {
         Cache {
             timeout,
             by_id: Default::default(),
+            by_peer: Default::default(),
             expired: Default::default(),
+            generation: 0,
         }
     }
 
-    pub fn insert(&mut self, id: ConnId, socket: UdpSocket) -> &mut CacheEntry {
+    pub fn insert(&mut self, id: ConnId, socket: UdpSocket, target: SocketAddr) -> &mut CacheEntry {
         self.cleanup();
         let now = Local::now();
-        let data = CacheEntry { id, socket };
-        let entry = CacheEntryOuter { last_access: Cell::new(now), data };
+        let data = CacheEntry { id, socket, target, stats: Default::default(), jitter: None, rate: Rate::default(), weight: 1, task: None };
+        let entry = CacheEntryOuter { created: now, last_access: Cell::new(now), data };
         self.by_id.insert(id, entry);
+        self.by_peer.entry(id.peer()).or_default().insert(id);
+        self.generation += 1;
         &mut self.by_id.get_mut(&id).unwrap().data
     }
 
@@ -44,16 +78,6 @@ impl Cache {
         Cache::prepare_entry_mut(self.by_id.get_mut(&id)?, self.timeout, &self.expired)
     }
 
-    fn prepare_entry<'a>(&self, e: &'a CacheEntryOuter) -> Option<&'a CacheEntry> {
-        let now = Local::now();
-        if now.signed_duration_since(e.last_access.get()) > self.timeout {
-            self.expired.borrow_mut().insert(e.data.id);
-            return None;
-        }
-        e.last_access.set(now);
-        Some(&e.data)
-    }
-
     fn prepare_entry_mut<'a>(e: &'a mut CacheEntryOuter, timeout: Duration, expired: &RefCell<HashSet<ConnId>>) -> Option<&'a mut CacheEntry> {
         let now = Local::now();
         if now.signed_duration_since(e.last_access.get()) > timeout {
@@ -64,18 +88,120 @@ impl Cache {
         Some(&mut e.data)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item=&CacheEntry> {
-        self.by_id.values().filter_map(move |v| self.prepare_entry(v))
+    // every connection with its weight, including ones that timed out but
+    // weren't cleaned up yet
+    pub fn poll_entries(&self) -> impl Iterator<Item=(ConnId, u32)> + '_ {
+        self.by_id.values().map(|e| (e.data.id, e.data.weight))
+    }
+
+    // doesn't count as activity on the connection
+    pub fn socket(&self, id: ConnId) -> Option<&UdpSocket> {
+        self.by_id.get(&id).map(|e| &e.data.socket)
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // doesn't count as activity on the connection
+    pub fn set_weight(&mut self, id: ConnId, weight: u32) -> bool {
+        match self.by_id.get_mut(&id) {
+            Some(e) => {
+                e.data.weight = weight;
+                self.generation += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: ConnId) -> Option<ConnectionInfo> {
+        let entry = self.by_id.remove(&id)?;
+        self.forget_peer(id);
+        self.generation += 1;
+        Some(entry.info())
     }
 
-    pub fn len_max(&self) -> usize {
-        self.by_id.len()
+    fn forget_peer(&mut self, id: ConnId) {
+        if let Some(ids) = self.by_peer.get_mut(&id.peer()) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.by_peer.remove(&id.peer());
+            }
+        }
+    }
+
+    // connections from this peer that haven't timed out yet
+    pub fn active_for_peer(&self, peer: SocketAddr) -> usize {
+        let now = Local::now();
+        self.by_peer.get(&peer).map_or(0, |ids| {
+            ids.iter()
+                .filter_map(|id| self.by_id.get(id))
+                .filter(|e| now.signed_duration_since(e.last_access.get()) <= self.timeout)
+                .count()
+        })
+    }
+
+    pub fn active_count(&self) -> usize {
+        let now = Local::now();
+        self.by_id.values()
+            .filter(|e| now.signed_duration_since(e.last_access.get()) <= self.timeout)
+            .count()
+    }
+
+    pub fn snapshot(&self) -> Vec<ConnectionInfo> {
+        let now = Local::now();
+        self.by_id.values()
+            .filter(|e| now.signed_duration_since(e.last_access.get()) <= self.timeout)
+            .map(CacheEntryOuter::info)
+            .collect()
     }
 
-    pub fn cleanup(&mut self) {
-        let vec = self.expired.get_mut();
-        for x in vec.drain() {
-            self.by_id.remove(&x);
+    // marks every connection that timed out, so the next cleanup removes it
+    // even if nothing touched it since
+    pub fn expire_idle(&mut self) {
+        let now = Local::now();
+        let timeout = self.timeout;
+        let expired = self.expired.get_mut();
+        expired.extend(self.by_id.values()
+            .filter(|e| now.signed_duration_since(e.last_access.get()) > timeout)
+            .map(|e| e.data.id));
+    }
+
+    // connections waiting to be removed by the next cleanup
+    pub fn expired_count(&self) -> usize {
+        self.expired.borrow().len()
+    }
+
+    pub fn cleanup(&mut self) -> Vec<ConnectionInfo> {
+        let expired: Vec<_> = self.expired.get_mut().drain().collect();
+        let mut removed = Vec::new();
+        for x in expired {
+            if let Some(entry) = self.by_id.remove(&x) {
+                removed.push(entry.info());
+                self.forget_peer(x);
+                self.generation += 1;
+            }
         }
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn close_frees_id() {
+        let mut cache = Cache::new(Duration::seconds(60));
+        let id: ConnId = "5@127.0.0.1:1000".parse().unwrap();
+        let target = SocketAddr::from(([127, 0, 0, 1], 2000));
+        cache.insert(id, UdpSocket::bind("127.0.0.1:0").await.unwrap(), target);
+        assert_eq!(cache.active_for_peer(id.peer()), 1);
+        assert_eq!(cache.remove(id).unwrap().cid, 5);
+        assert_eq!(cache.active_for_peer(id.peer()), 0);
+        assert!(cache.get_by_id_mut(id).is_none());
+        cache.insert(id, UdpSocket::bind("127.0.0.1:0").await.unwrap(), target);
+        assert!(cache.get_by_id_mut(id).is_some());
     }
 }
\ No newline at end of file
@@ -0,0 +1,132 @@
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use crate::{common, filter, log, middleware};
+use crate::common::{DataDirection, reject};
+use crate::filter::{Filter, Packet};
+use crate::middleware::Middleware;
+use crate::proto::*;
+use crate::server::ConnId;
+use crate::stats::{Rejection, Stats};
+
+// What forwarding into the tunnel needs, shared between the main loop and the
+// tasks that read target sockets with --task-per-connection.
+pub struct Forward {
+    pub middleware: Mutex<Option<Box<dyn Middleware>>>,
+    pub filter: Option<Box<dyn Filter>>,
+    pub stats: Arc<Stats>,
+    pub verbosity: u64,
+    pub print_data_buffer: bool,
+    bufsize: usize,
+    // a second handle to the tunnel socket, set only when there are tasks
+    tunnel: RwLock<Option<std::net::UdpSocket>>,
+}
+
+impl Forward {
+    pub fn new(middleware: Option<Box<dyn Middleware>>, filter: Option<Box<dyn Filter>>, stats: Arc<Stats>, verbosity: u64, print_data_buffer: bool, bufsize: usize) -> Self {
+        Forward { middleware: Mutex::new(middleware), filter, stats, verbosity, print_data_buffer, bufsize, tunnel: RwLock::new(None) }
+    }
+
+    pub fn close_packet(&self, cid: u16) -> io::Result<Vec<u8>> {
+        common::close_packet(&mut self.middleware.lock().unwrap(), cid, self.bufsize)
+    }
+
+    // Called again whenever the main loop gets a new tunnel socket.
+    pub fn set_tunnel(&self, socket: &UdpSocket) -> io::Result<()> {
+        *self.tunnel.write().unwrap() = Some(crate::sockopt::try_clone(socket)?);
+        Ok(())
+    }
+
+    // Runs a payload read from a target socket through the filters and the
+    // middleware and puts the data header in front. Returns the length of
+    // the datagram for the tunnel, or None if it was dropped.
+    pub fn prepare(&self, id: ConnId, buffer: &mut [u8], size: usize) -> Option<usize> {
+        if !filter::accepts(&self.filter, &Packet { direction: DataDirection::IntoTunnel, cid: id.cid(), payload: &buffer[DATA_HEADER_LEN..size + DATA_HEADER_LEN] }) {
+            reject(&self.stats, self.verbosity, id, Rejection::Filtered, "payload did not pass the filters");
+            return None;
+        }
+        self.stats.record(DataDirection::IntoTunnel, size);
+        // before the middleware turns it into something unreadable
+        if self.print_data_buffer {
+            common::dump_payload(DataDirection::IntoTunnel, id.cid(), &buffer[DATA_HEADER_LEN..size + DATA_HEADER_LEN]);
+        }
+        let size = match middleware::outbound(&mut self.middleware.lock().unwrap(), id.cid(), &mut buffer[DATA_HEADER_LEN..], size) {
+            Ok(size) => size,
+            Err(e) => {
+                self.stats.reject(Rejection::from(&e));
+                log::in_connection(id.cid(), id.peer(), || warn!("middleware dropped packet for {}: {}", id, e));
+                return None;
+            }
+        };
+        buffer[0] = PACKET_DATA;
+        buffer[1..DATA_HEADER_LEN].copy_from_slice(&id.cid().to_be_bytes());
+        Some(size + DATA_HEADER_LEN)
+    }
+
+    // Never waits: with a full send buffer the packet is dropped, like it
+    // would be anywhere else on the way.
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match &*self.tunnel.read().unwrap() {
+            Some(socket) => socket.send(buf),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "no tunnel socket")),
+        }
+    }
+}
+
+// What a task did, for the main loop to put into the cache. The main loop
+// stays the only one touching it.
+pub enum Report {
+    Sent { id: ConnId, time: DateTime<Local>, at: Instant, len: usize },
+    Unreachable(ConnId),
+    SendError(ConnId, io::Error),
+    RecvError(ConnId, io::Error),
+}
+
+// Forwards everything arriving on `socket` into the tunnel until the returned
+// sender is dropped, which happens together with the connection's cache entry.
+pub fn spawn(id: ConnId, mut socket: UdpSocket, forward: Arc<Forward>, bufsize: usize, mut grow_buffer: Option<usize>, strict_mtu: bool, reports: UnboundedSender<Report>) -> oneshot::Sender<()> {
+    let (stop_tx, mut stop) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let mut buffer = vec![0; bufsize];
+        loop {
+            let r = tokio::select! {
+                r = socket.recv_from(&mut buffer[DATA_HEADER_LEN..]) => r,
+                _ = &mut stop => return,
+            };
+            let report = match r {
+                Ok((size, sender_addr)) => {
+                    if common::check_truncated(&mut buffer, DATA_HEADER_LEN, size, sender_addr, &mut grow_buffer, strict_mtu) {
+                        forward.stats.reject(Rejection::Truncated);
+                        continue;
+                    }
+                    let time = Local::now();
+                    let at = Instant::now();
+                    let len = match forward.prepare(id, &mut buffer, size) {
+                        Some(len) => len,
+                        None => continue,
+                    };
+                    match forward.send(&buffer[..len]) {
+                        Ok(_) => Report::Sent { id, time, at, len: size },
+                        Err(e) => Report::SendError(id, e),
+                    }
+                }
+                // the target isn't there (anymore), so the connection is over
+                Err(e) if common::is_unreachable(&e) => {
+                    let _ = reports.send(Report::Unreachable(id));
+                    return;
+                }
+                Err(e) => Report::RecvError(id, e),
+            };
+            if reports.send(report).is_err() {
+                return;
+            }
+        }
+    });
+    stop_tx
+}
\ No newline at end of file
@@ -0,0 +1,89 @@
+use std::future;
+#[cfg(unix)]
+use std::task::Poll;
+
+#[cfg(unix)]
+use tokio::future::poll_fn;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+// Signals that don't exist on the current platform just never arrive.
+pub struct SignalListener {
+    #[cfg(unix)]
+    inner: Vec<Signal>,
+    #[cfg(not(unix))]
+    ctrl_c: bool,
+}
+
+impl SignalListener {
+    #[cfg(unix)]
+    fn new(kinds: &[SignalKind]) -> Self {
+        let inner = kinds.iter()
+            .filter_map(|&kind| signal(kind)
+                .map_err(|e| error!("failed to listen for signal: {}", e))
+                .ok())
+            .collect();
+        SignalListener { inner }
+    }
+
+    // SIGUSR2
+    #[cfg(unix)]
+    pub fn drain() -> Self {
+        SignalListener::new(&[SignalKind::user_defined2()])
+    }
+
+    #[cfg(not(unix))]
+    pub fn drain() -> Self {
+        SignalListener { ctrl_c: false }
+    }
+
+    // SIGHUP
+    #[cfg(unix)]
+    pub fn reload() -> Self {
+        SignalListener::new(&[SignalKind::hangup()])
+    }
+
+    #[cfg(not(unix))]
+    pub fn reload() -> Self {
+        SignalListener { ctrl_c: false }
+    }
+
+    // SIGINT or SIGTERM
+    #[cfg(unix)]
+    pub fn shutdown() -> Self {
+        SignalListener::new(&[SignalKind::interrupt(), SignalKind::terminate()])
+    }
+
+    #[cfg(not(unix))]
+    pub fn shutdown() -> Self {
+        SignalListener { ctrl_c: true }
+    }
+
+    #[cfg(unix)]
+    pub async fn recv(&mut self) {
+        let inner = &mut self.inner;
+        // a closed stream doesn't count as a signal
+        let received = poll_fn(|cx| {
+            let mut open = false;
+            for s in inner.iter_mut() {
+                match s.poll_recv(cx) {
+                    Poll::Ready(Some(())) => return Poll::Ready(true),
+                    Poll::Ready(None) => {}
+                    Poll::Pending => open = true,
+                }
+            }
+            if open { Poll::Pending } else { Poll::Ready(false) }
+        }).await;
+        if !received {
+            future::pending().await
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub async fn recv(&mut self) {
+        if self.ctrl_c && tokio::signal::ctrl_c().await.is_ok() {
+            return;
+        }
+        future::pending().await
+    }
+}
\ No newline at end of file
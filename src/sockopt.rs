@@ -0,0 +1,198 @@
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::ffi::CString;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+
+// Options applied to every UDP socket the tunnel opens. Options that don't
+// exist on the current platform make binding fail instead of being silently
+// ignored.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SocketOptions {
+    pub recv_buffer: Option<usize>,
+    pub send_buffer: Option<usize>,
+    pub dscp: Option<u8>,
+    pub device: Option<String>,
+    pub only_v6: Option<bool>,
+}
+
+// What the tunnel socket listens on besides its port: one of the local
+// addresses, or a network interface by name (SO_BINDTODEVICE, Linux only).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ListenInterface {
+    Addr(IpAddr),
+    Device(String),
+}
+
+impl FromStr for ListenInterface {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name is empty"));
+        }
+        Ok(s.parse().map(ListenInterface::Addr).unwrap_or_else(|_| ListenInterface::Device(s.to_string())))
+    }
+}
+
+// Like bind, but only on the given interface. With an address only the port
+// of addr is kept.
+pub async fn bind_on<T: ToSocketAddrs>(addr: T, interface: &ListenInterface, opts: &SocketOptions) -> io::Result<UdpSocket> {
+    match interface {
+        ListenInterface::Addr(ip) => {
+            let port = lookup_host(addr).await?.next().map_or(0, |a| a.port());
+            bind(SocketAddr::new(*ip, port), opts).await
+        }
+        ListenInterface::Device(name) => {
+            let opts = SocketOptions { device: Some(name.clone()), ..opts.clone() };
+            bind(addr, &opts).await
+        }
+    }
+}
+
+pub async fn bind<T: ToSocketAddrs>(addr: T, opts: &SocketOptions) -> io::Result<UdpSocket> {
+    let mut last_err = None;
+    for addr in lookup_host(addr).await? {
+        match bind_addr(addr, opts) {
+            Ok(s) => return Ok(s),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address")))
+}
+
+fn bind_addr(addr: SocketAddr, opts: &SocketOptions) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(size) = opts.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = opts.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let (Some(only_v6), true) = (opts.only_v6, addr.is_ipv6()) {
+        // some systems can't turn it off, the tunnel socket warns about that
+        if let Err(e) = socket.set_only_v6(only_v6) {
+            if only_v6 {
+                return Err(e);
+            }
+        }
+    }
+    if let Some(dscp) = opts.dscp {
+        set_dscp(&socket, addr, dscp)?;
+    }
+    if let Some(device) = &opts.device {
+        bind_device(&socket, device)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Reads as many datagrams as are already waiting, up to one per buffer, with
+// a single recvmmsg call. Doesn't wait, so nothing waiting gives no datagrams.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn recv_batch(socket: &UdpSocket, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+    let mut iovecs: Vec<libc::iovec> = bufs.iter_mut()
+        .map(|b| libc::iovec { iov_base: b.as_mut_ptr() as *mut libc::c_void, iov_len: b.len() })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = iovecs.iter_mut().zip(addrs.iter_mut()).map(|(iov, addr)| {
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        hdr.msg_iov = iov;
+        hdr.msg_iovlen = 1;
+        libc::mmsghdr { msg_hdr: hdr, msg_len: 0 }
+    }).collect();
+    let n = unsafe { libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), msgs.len() as _, libc::MSG_DONTWAIT as _, std::ptr::null_mut()) };
+    if n < 0 {
+        let e = io::Error::last_os_error();
+        return if e.kind() == io::ErrorKind::WouldBlock { Ok(Vec::new()) } else { Err(e) };
+    }
+    Ok(msgs[..n as usize].iter().zip(&addrs)
+        .filter_map(|(m, a)| sockaddr_to_std(a).map(|addr| (m.msg_len as usize, addr)))
+        .collect())
+}
+
+// Without recvmmsg every datagram is received the normal way.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn recv_batch(_socket: &UdpSocket, _bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    Ok(Vec::new())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sockaddr_to_std(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let a = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(a.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let a = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(a.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(a.sin6_port), a.sin6_flowinfo, a.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
+
+pub fn peer_addr(socket: &UdpSocket) -> io::Result<SocketAddr> {
+    SockRef::from(socket).peer_addr()?.as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "peer is not an IP address"))
+}
+
+// A second handle to the same socket. The socket stays non-blocking, so the
+// standard library one can be sent on from any task without waiting.
+pub fn try_clone(socket: &UdpSocket) -> io::Result<std::net::UdpSocket> {
+    Ok(SockRef::from(socket).try_clone()?.into())
+}
+
+// Whether an IPv6 socket refuses IPv4 traffic. IPv4 sockets never take IPv6
+// traffic, so they don't count.
+pub fn is_v6_only(socket: &UdpSocket) -> io::Result<bool> {
+    if socket.local_addr()?.is_ipv4() {
+        return Ok(false);
+    }
+    SockRef::from(socket).only_v6()
+}
+
+#[cfg(not(windows))]
+fn set_dscp(socket: &Socket, addr: SocketAddr, dscp: u8) -> io::Result<()> {
+    if addr.is_ipv6() {
+        return Err(unsupported("setting DSCP on IPv6 sockets"));
+    }
+    socket.set_tos(u32::from(dscp) << 2)
+}
+
+// Windows accepts IP_TOS but ignores it
+#[cfg(windows)]
+fn set_dscp(_socket: &Socket, _addr: SocketAddr, _dscp: u8) -> io::Result<()> {
+    Err(unsupported("setting DSCP"))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    let name = CString::new(device).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "device name contains a NUL byte"))?;
+    socket.bind_device(Some(name.as_bytes())).map_err(|e| match e.raw_os_error() {
+        Some(libc::ENODEV) => io::Error::new(io::ErrorKind::NotFound, format!("no network interface named {}", device)),
+        _ => e,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(unsupported("binding to a device"))
+}
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::other(format!("{} is not supported on this platform", what))
+}
\ No newline at end of file
@@ -1,22 +1,91 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
-use std::ops::Add;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::str::FromStr;
 
 use itertools::Itertools;
+use num_traits::{Bounded, FromPrimitive, ToPrimitive};
 use rand::{Rng, RngCore};
 use rand::distributions::uniform::SampleUniform;
+use rand::seq::SliceRandom;
+use thiserror::Error;
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+use crate::common::IpMode;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SourceFormat {
     V4(SourceFormatV4),
     V6(SourceFormatV6),
+    // (weight, format), picked proportionally to the weight
+    Multi(Vec<(u32, SourceFormat)>),
 }
 
 impl SourceFormat {
-    pub fn get_addr(&self, rand: impl RngCore) -> SocketAddr {
+    pub fn get_addr(&self, mut rand: impl RngCore) -> SocketAddr {
         match self {
             SourceFormat::V4(f) => SocketAddr::V4(f.get_addr(rand)),
-            SourceFormat::V6(_) => unimplemented!(),
+            SourceFormat::V6(f) => SocketAddr::V6(f.get_addr(rand)),
+            SourceFormat::Multi(v) => {
+                let (_, f) = v.choose_weighted(&mut rand, |(w, _)| *w).expect("no source format to choose from");
+                f.get_addr(rand)
+            }
+        }
+    }
+
+    pub fn allowed_in(&self, mode: IpMode) -> bool {
+        match self {
+            SourceFormat::V4(_) => mode != IpMode::V6Only,
+            SourceFormat::V6(_) => mode != IpMode::V4Only,
+            SourceFormat::Multi(v) => v.iter().all(|(_, f)| f.allowed_in(mode)),
+        }
+    }
+
+    // number of distinct addresses, weights are not taken into account here;
+    // saturates like the size of a large IPv6 format does
+    pub fn size(&self) -> u64 {
+        match self {
+            SourceFormat::V4(f) => f.size(),
+            SourceFormat::V6(f) => f.size(),
+            SourceFormat::Multi(v) => v.iter().map(|(_, f)| f.size()).fold(0, u64::saturating_add),
+        }
+    }
+
+    pub fn nth_addr(&self, n: u64) -> SocketAddr {
+        match self {
+            SourceFormat::V4(f) => SocketAddr::V4(f.nth_addr(n)),
+            SourceFormat::V6(f) => SocketAddr::V6(f.nth_addr(n)),
+            SourceFormat::Multi(v) => {
+                let mut n = n % self.size();
+                for (_, f) in v {
+                    if n < f.size() {
+                        return f.nth_addr(n);
+                    }
+                    n -= f.size();
+                }
+                // n is below the sum of the sizes, or below u64::MAX when the
+                // sum saturated, so one of the formats always covers it
+                unreachable!("address index {} past the end of the source format", n)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SourceAlloc {
+    Random,
+    // derived from a hash of the connection so it stays the same for a client
+    Sticky,
+    // walks through all addresses in order and starts over at the end
+    Sequential,
+}
+
+impl FromStr for SourceAlloc {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(SourceAlloc::Random),
+            "sticky" => Ok(SourceAlloc::Sticky),
+            "sequential" => Ok(SourceAlloc::Sequential),
+            _ => Err(()),
         }
     }
 }
@@ -36,64 +105,246 @@ impl SourceFormatV4 {
         let port = self.port.get_random(&mut rand);
         SocketAddrV4::new(Ipv4Addr::new(u1, u2, u3, u4), port)
     }
+
+    pub fn size(&self) -> u64 {
+        self.ip.0.size() * self.ip.1.size() * self.ip.2.size() * self.ip.3.size() * self.port.size()
+    }
+
+    // index into the addresses covered by this format, the port varies fastest
+    pub fn nth_addr(&self, n: u64) -> SocketAddrV4 {
+        let mut n = n % self.size();
+        let port = self.port.nth(n % self.port.size());
+        n /= self.port.size();
+        let u4 = self.ip.3.nth(n % self.ip.3.size());
+        n /= self.ip.3.size();
+        let u3 = self.ip.2.nth(n % self.ip.2.size());
+        n /= self.ip.2.size();
+        let u2 = self.ip.1.nth(n % self.ip.1.size());
+        n /= self.ip.1.size();
+        let u1 = self.ip.0.nth(n);
+        SocketAddrV4::new(Ipv4Addr::new(u1, u2, u3, u4), port)
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct SourceFormatV6 {
-    // yeah not doing ipv6 range parsing lol
-    ip: Ipv6Addr,
+    ip: [Range<u16>; 8],
     port: Range<u16>,
 }
 
+impl SourceFormatV6 {
+    pub fn get_addr(&self, mut rand: impl RngCore) -> SocketAddrV6 {
+        let mut segments = [0; 8];
+        for (seg, range) in segments.iter_mut().zip(self.ip.iter()) {
+            *seg = range.get_random(&mut rand);
+        }
+        let port = self.port.get_random(&mut rand);
+        SocketAddrV6::new(Ipv6Addr::from(segments), port, 0, 0)
+    }
+
+    // saturates, a wide enough range has more addresses than fit in a u64
+    pub fn size(&self) -> u64 {
+        self.ip.iter().fold(self.port.size(), |acc, r| acc.saturating_mul(r.size()))
+    }
+
+    // index into the addresses covered by this format, the port varies fastest
+    pub fn nth_addr(&self, n: u64) -> SocketAddrV6 {
+        let mut n = n % self.size();
+        let port = self.port.nth(n % self.port.size());
+        n /= self.port.size();
+        let mut segments = [0; 8];
+        for (seg, range) in segments.iter_mut().zip(self.ip.iter()).rev() {
+            *seg = range.nth(n % range.size());
+            n /= range.size();
+        }
+        SocketAddrV6::new(Ipv6Addr::from(segments), port, 0, 0)
+    }
+}
+
 impl FromStr for SourceFormat {
-    type Err = ();
+    type Err = SourceFormatError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.parse() {
-            Ok(v) => Ok(SourceFormat::V4(v)),
-            Err(_) => match s.parse() {
-                Ok(v) => Ok(SourceFormat::V6(v)),
-                Err(e) => Err(e)
-            }
+        if s.contains(',') || s.contains('@') {
+            let v = s.split(',').map(parse_weighted).collect::<Result<_, _>>()?;
+            Ok(SourceFormat::Multi(v))
+        } else {
+            parse_single(s)
         }
     }
 }
 
+// only IPv6 formats start with a bracket, so that decides which error to report
+fn parse_single(s: &str) -> Result<SourceFormat, SourceFormatError> {
+    if s.starts_with('[') {
+        s.parse().map(SourceFormat::V6)
+    } else {
+        s.parse().map(SourceFormat::V4)
+    }
+}
+
+fn parse_weighted(s: &str) -> Result<(u32, SourceFormat), SourceFormatError> {
+    let (spec, weight) = match s.rfind('@') {
+        Some(pos) => {
+            let w = &s[pos + 1..];
+            match w.parse() {
+                Ok(weight) if weight > 0 => (&s[..pos], weight),
+                _ => return Err(SourceFormatError::Weight(w.to_string())),
+            }
+        }
+        None => (s, 1),
+    };
+    Ok((weight, parse_single(spec)?))
+}
+
 impl FromStr for SourceFormatV4 {
-    type Err = ();
+    type Err = SourceFormatError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<_> = s.splitn(2, ':').collect();
-        if let [addr, port] = *parts {
-            let addr_parts: Vec<_> = addr.splitn(4, '.').map(parse_range).try_collect().map_err(|_| ())?;
-            if let [u1, u2, u3, u4] = *addr_parts {
-                let port = parse_range(port).map_err(|_| ())?;
-                Ok(SourceFormatV4 { ip: (u1, u2, u3, u4), port })
-            } else { Err(()) }
-        } else { Err(()) }
+        let pos = s.rfind(':').ok_or(SourceFormatError::MissingPort)?;
+        let (addr, port) = (&s[..pos], &s[pos + 1..]);
+        let port = parse_range(port, SourceFormatError::Port)?;
+        if addr.contains('/') {
+            return Ok(SourceFormatV4 { ip: parse_cidr(addr)?, port });
+        }
+        let addr_parts: Vec<_> = split_octets(addr).into_iter().map(|p| parse_range(p, SourceFormatError::Octet)).try_collect()?;
+        if let [u1, u2, u3, u4] = *addr_parts {
+            Ok(SourceFormatV4 { ip: (u1, u2, u3, u4), port })
+        } else {
+            Err(SourceFormatError::ComponentCount { expected: 4, found: addr_parts.len() })
+        }
+    }
+}
+
+// '.' separates the octets, except in the '..=' of an inclusive range
+fn split_octets(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < s.len() {
+        if s[i..].starts_with("..=") {
+            i += 3;
+        } else {
+            if s.as_bytes()[i] == b'.' {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            i += 1;
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// one range per octet
+type OctetRanges = (Range<u8>, Range<u8>, Range<u8>, Range<u8>);
+
+// a.b.c.d/prefix as the equivalent per-octet ranges
+fn parse_cidr(s: &str) -> Result<OctetRanges, SourceFormatError> {
+    let pos = s.find('/').unwrap();
+    let base: Ipv4Addr = s[..pos].parse().map_err(|_| SourceFormatError::Cidr(s.to_string()))?;
+    let prefix: u32 = s[pos + 1..].parse().ok().filter(|&p| p <= 32).ok_or_else(|| SourceFormatError::Cidr(s.to_string()))?;
+    let host_mask = u32::MAX.checked_shr(prefix).unwrap_or(0);
+    if u32::from(base) & host_mask != 0 {
+        return Err(SourceFormatError::Misaligned(s.to_string()));
     }
+    let octets = base.octets();
+    let mask = host_mask.to_be_bytes();
+    let range = |i: usize| match mask[i] {
+        0 => Range::Single(octets[i]),
+        m => Range::Inclusive { start: octets[i], end: octets[i] | m },
+    };
+    Ok((range(0), range(1), range(2), range(3)))
 }
 
+// [group:group:...]:port, where every group and the port can be a range and
+// '::' stands for as many zero groups as needed to make up eight
 impl FromStr for SourceFormatV6 {
-    type Err = ();
+    type Err = SourceFormatError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        unimplemented!()
+        let s = s.strip_prefix('[').ok_or(SourceFormatError::MissingBracket)?;
+        let pos = s.find("]:").ok_or(SourceFormatError::MissingPort)?;
+        let (addr, port) = (&s[..pos], &s[pos + 2..]);
+        let port = parse_range(port, SourceFormatError::Port)?;
+
+        let parse_groups = |s: &str| -> Result<Vec<Range<u16>>, SourceFormatError> {
+            if s.is_empty() { return Ok(Vec::new()); }
+            s.split(':').map(|g| parse_range_with(g, |g| u16::from_str_radix(g, 16), SourceFormatError::Group)).try_collect()
+        };
+        let groups = match addr.find("::") {
+            Some(pos) => {
+                let head = parse_groups(&addr[..pos])?;
+                let tail = parse_groups(&addr[pos + 2..])?;
+                if head.len() + tail.len() > 7 {
+                    return Err(SourceFormatError::ComponentCount { expected: 8, found: head.len() + tail.len() + 1 });
+                }
+                let zeros = 8 - head.len() - tail.len();
+                head.into_iter().chain(std::iter::repeat_n(Range::Single(0), zeros)).chain(tail).collect()
+            }
+            None => parse_groups(addr)?,
+        };
+        if groups.len() != 8 {
+            return Err(SourceFormatError::ComponentCount { expected: 8, found: groups.len() });
+        }
+        let mut ip = [Range::Single(0); 8];
+        ip.copy_from_slice(&groups);
+        Ok(SourceFormatV6 { ip, port })
+    }
+}
+
+fn parse_range<T: FromStr + Copy + PartialOrd + Bounded>(s: &str, invalid: fn(String) -> SourceFormatError) -> Result<Range<T>, SourceFormatError> {
+    parse_range_with(s, str::parse, invalid)
+}
+
+// `invalid` says which part of the format a value that doesn't parse belongs
+// to. '*' is every value the part can take.
+fn parse_range_with<T: Copy + PartialOrd + Bounded, E>(s: &str, parse: impl Fn(&str) -> Result<T, E>, invalid: fn(String) -> SourceFormatError) -> Result<Range<T>, SourceFormatError> {
+    if s == "*" {
+        return Ok(Range::Inclusive { start: T::min_value(), end: T::max_value() });
+    }
+    let parse = |part: &str| parse(part).map_err(|_| invalid(s.to_string()));
+    if let Some(pos) = s.find("..=") {
+        let (start, end) = (parse(&s[..pos])?, parse(&s[pos + 3..])?);
+        if start > end { return Err(SourceFormatError::Reversed(s.to_string())); }
+        return Ok(Range::Inclusive { start, end });
+    }
+    let parts: Vec<_> = s.split('-').collect();
+    match *parts {
+        [a] => Ok(Range::Single(parse(a)?)),
+        [a, b] => {
+            let (start, end) = (parse(a)?, parse(b)?);
+            if start >= end { return Err(SourceFormatError::Reversed(s.to_string())); }
+            Ok(Range::Exclusive { start, end })
+        }
+        _ => Err(SourceFormatError::RangeParts(s.to_string())),
     }
 }
 
-fn parse_range<T: FromStr + Copy>(s: &str) -> Result<Range<T>, <T as FromStr>::Err> {
-    let parts = s.splitn(2, '-')
-        .map(|s| s.parse())
-        .fold_results(Vec::new(), |mut acc, a| {
-            acc.push(a);
-            acc
-        })?;
-    if let [a, b] = *parts {
-        Ok(Range::Exclusive { start: a, end: b })
-    } else if let [a] = *parts {
-        Ok(Range::Single(a))
-    } else { panic!("haha too lazy to handle properly") }
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum SourceFormatError {
+    #[error("invalid IPv4 octet or octet range '{0}'")]
+    Octet(String),
+    #[error("invalid IPv6 group or group range '{0}'")]
+    Group(String),
+    #[error("invalid port or port range '{0}'")]
+    Port(String),
+    #[error("expected {expected} address components, found {found}")]
+    ComponentCount { expected: usize, found: usize },
+    #[error("range '{0}' is empty or reversed; the end of 'a-b' is exclusive, use 'a..=b' to include it")]
+    Reversed(String),
+    #[error("range '{0}' has more than a start and an end")]
+    RangeParts(String),
+    #[error("invalid CIDR block '{0}', expected a.b.c.d/PREFIX with a prefix of at most 32")]
+    Cidr(String),
+    #[error("CIDR block '{0}' has host bits set in its base address")]
+    Misaligned(String),
+    #[error("missing port, expected ADDRESS:PORT")]
+    MissingPort,
+    #[error("IPv6 formats must be given as [ADDRESS]:PORT")]
+    MissingBracket,
+    #[error("invalid weight '{0}', expected a positive integer")]
+    Weight(String),
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -103,14 +354,174 @@ pub enum Range<T> {
         start: T,
         end: T,
     },
+    // a..=b, so the last value of a type can be included
+    Inclusive {
+        start: T,
+        end: T,
+    },
 }
 
 impl<T> Range<T>
-    where T: Copy + SampleUniform {
+    where T: Copy + SampleUniform + PartialOrd {
     pub fn get_random(&self, mut rand: impl RngCore) -> T {
         match *self {
             Range::Single(s) => s,
-            Range::Exclusive { start, end } => rand.gen_range(start, end),
+            Range::Exclusive { start, end } => rand.gen_range(start..end),
+            Range::Inclusive { start, end } => rand.gen_range(start..=end),
+        }
+    }
+}
+
+impl<T> Range<T>
+    where T: Copy + ToPrimitive + FromPrimitive {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Range::Single(_) => 1,
+            Range::Exclusive { start, end } => end.to_u64().unwrap() - start.to_u64().unwrap(),
+            Range::Inclusive { start, end } => end.to_u64().unwrap() - start.to_u64().unwrap() + 1,
+        }
+    }
+
+    pub fn nth(&self, n: u64) -> T {
+        match *self {
+            Range::Single(s) => s,
+            Range::Exclusive { start, .. } | Range::Inclusive { start, .. } => T::from_u64(start.to_u64().unwrap() + n).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::common::seeded_rng;
+
+    use super::*;
+
+    fn octet(s: &str) -> Result<Range<u8>, SourceFormatError> {
+        parse_range(s, SourceFormatError::Octet)
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(octet("1-254"), Ok(Range::Exclusive { start: 1, end: 254 }));
+        assert_eq!(octet("7"), Ok(Range::Single(7)));
+    }
+
+    #[test]
+    fn empty_range() {
+        assert_eq!(octet("5-5"), Err(SourceFormatError::Reversed("5-5".to_string())));
+    }
+
+    #[test]
+    fn reversed_range() {
+        assert_eq!(octet("10-3"), Err(SourceFormatError::Reversed("10-3".to_string())));
+        assert_eq!(octet("10..=3"), Err(SourceFormatError::Reversed("10..=3".to_string())));
+        assert!("200-100.1.1.1:53".parse::<SourceFormat>().is_err());
+    }
+
+    #[test]
+    fn inclusive_range() {
+        assert_eq!(octet("5..=5"), Ok(Range::Inclusive { start: 5, end: 5 }));
+        assert_eq!(octet("0..=255").unwrap().size(), 256);
+    }
+
+    #[test]
+    fn random_in_range() {
+        let mut rng = seeded_rng(Some(0));
+        let exclusive = Range::Exclusive { start: 10u8, end: 13 };
+        let values: HashSet<_> = (0..1000).map(|_| exclusive.get_random(&mut rng)).collect();
+        assert_eq!(values, [10, 11, 12].iter().copied().collect());
+        let inclusive = Range::Inclusive { start: 254u8, end: 255 };
+        let values: HashSet<_> = (0..1000).map(|_| inclusive.get_random(&mut rng)).collect();
+        assert_eq!(values, [254, 255].iter().copied().collect());
+        assert_eq!(Range::Single(7u16).get_random(&mut rng), 7);
+    }
+
+    #[test]
+    fn random_addr_in_format() {
+        let mut rng = seeded_rng(Some(0));
+        let format: SourceFormatV4 = "10.0.1-3.5:2000-2002".parse().unwrap();
+        for _ in 0..1000 {
+            let addr = format.get_addr(&mut rng);
+            let [a, b, c, d] = addr.ip().octets();
+            assert_eq!((a, b, d), (10, 0, 5));
+            assert!((1..3).contains(&c) && (2000..2002).contains(&addr.port()));
         }
     }
+
+    fn v4(s: &str) -> Result<SourceFormatV4, SourceFormatError> {
+        s.parse()
+    }
+
+    #[test]
+    fn cidr() {
+        assert_eq!(v4("10.0.0.0/16:30000-40000"), v4("10.0.0..=255.0..=255:30000-40000"));
+        assert_eq!(v4("10.0.16.0/20:53"), v4("10.0.16..=31.0..=255:53"));
+        assert_eq!(v4("0.0.0.0/0:53").unwrap().size(), 1 << 32);
+    }
+
+    #[test]
+    fn cidr_single_host() {
+        assert_eq!(v4("192.168.1.7/32:53"), v4("192.168.1.7:53"));
+    }
+
+    #[test]
+    fn cidr_misaligned() {
+        assert_eq!(v4("10.0.0.1/16:53"), Err(SourceFormatError::Misaligned("10.0.0.1/16".to_string())));
+    }
+
+    #[test]
+    fn cidr_invalid() {
+        assert_eq!(v4("10.0.0.0/33:53"), Err(SourceFormatError::Cidr("10.0.0.0/33".to_string())));
+        assert_eq!(v4("10.0.0/8:53"), Err(SourceFormatError::Cidr("10.0.0/8".to_string())));
+    }
+
+    #[test]
+    fn inclusive_octet() {
+        let format: SourceFormatV4 = "10.0.16..=31.1:53".parse().unwrap();
+        assert_eq!(format.ip.2, Range::Inclusive { start: 16, end: 31 });
+        assert_eq!(format.ip.3, Range::Single(1));
+        assert_eq!(format.size(), 16);
+    }
+
+    #[test]
+    fn wildcard_octets() {
+        let any = Range::Inclusive { start: 0, end: 255 };
+        let one = Range::Single(1);
+        assert_eq!(v4("*.1.1.1:53").unwrap().ip, (any, one, one, one));
+        assert_eq!(v4("1.*.1.1:53").unwrap().ip, (one, any, one, one));
+        assert_eq!(v4("1.1.*.1:53").unwrap().ip, (one, one, any, one));
+        assert_eq!(v4("1.1.1.*:53").unwrap().ip, (one, one, one, any));
+    }
+
+    #[test]
+    fn wildcard_port() {
+        let format = v4("10.0.*.*:*").unwrap();
+        assert_eq!(format.port, Range::Inclusive { start: 0, end: 65535 });
+        assert_eq!(format.size(), 256 * 256 * 65536);
+        let format: SourceFormatV6 = "[fd00::*]:*".parse().unwrap();
+        assert_eq!(format.ip[7], Range::Inclusive { start: 0, end: 0xffff });
+    }
+
+    #[test]
+    fn multi_size_saturates() {
+        let format: SourceFormat = "[*:*:*:*::]:*,[fd00::1]:53".parse().unwrap();
+        assert_eq!(format.size(), u64::MAX);
+        assert!(matches!(format.nth_addr(u64::MAX - 1), SocketAddr::V6(_)));
+    }
+
+    #[test]
+    fn multi_nth_addr() {
+        let format: SourceFormat = "10.0.0.1:1-3,10.0.0.2:1-3@5".parse().unwrap();
+        assert_eq!(format.size(), 4);
+        let addrs: Vec<_> = (0..5).map(|n| format.nth_addr(n).to_string()).collect();
+        assert_eq!(addrs, ["10.0.0.1:1", "10.0.0.1:2", "10.0.0.2:1", "10.0.0.2:2", "10.0.0.1:1"]);
+    }
+
+    #[test]
+    fn range_parts() {
+        assert_eq!(octet("1-2-3"), Err(SourceFormatError::RangeParts("1-2-3".to_string())));
+        assert_eq!(octet("1-x"), Err(SourceFormatError::Octet("1-x".to_string())));
+    }
 }
\ No newline at end of file
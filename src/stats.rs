@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use crate::{common, middleware};
+use crate::common::DataDirection;
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub packets_in: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub packets_out: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub connections: AtomicU64,
+    pub peak_connections: AtomicU64,
+    pub middleware_drops: AtomicU64,
+    pub corrupt_drops: AtomicU64,
+    pub auth_drops: AtomicU64,
+    pub replay_drops: AtomicU64,
+    pub decrypt_drops: AtomicU64,
+    pub filtered: AtomicU64,
+    pub quota_rejects: AtomicU64,
+    pub handshake_rejects: AtomicU64,
+    pub unknown_packets: AtomicU64,
+    pub truncated_drops: AtomicU64,
+    pub loopback_drops: AtomicU64,
+    pub handshakes: AtomicU64,
+    // of the last handshake this end started, 0 until there was one
+    pub rtt_micros: AtomicU64,
+    pub draining: AtomicBool,
+    pub targets: Mutex<HashMap<SocketAddr, TargetStats>>,
+}
+
+impl Stats {
+    pub fn record(&self, dir: DataDirection, len: usize) {
+        match dir {
+            DataDirection::FromTunnel => {
+                self.packets_in.fetch_add(1, Ordering::Relaxed);
+                self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            DataDirection::IntoTunnel => {
+                self.packets_out.fetch_add(1, Ordering::Relaxed);
+                self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn reject(&self, reason: Rejection) {
+        match reason {
+            Rejection::Malformed => &self.middleware_drops,
+            Rejection::Corrupt => &self.corrupt_drops,
+            Rejection::Auth => &self.auth_drops,
+            Rejection::Replay => &self.replay_drops,
+            Rejection::Decrypt => &self.decrypt_drops,
+            Rejection::Filtered => &self.filtered,
+            Rejection::Quota => &self.quota_rejects,
+            Rejection::Handshake => &self.handshake_rejects,
+            Rejection::UnknownPacket => &self.unknown_packets,
+            Rejection::Truncated => &self.truncated_drops,
+            Rejection::Loopback => &self.loopback_drops,
+        }.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_completed(&self) {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_rtt(&self, rtt: Duration) {
+        self.rtt_micros.store(rtt.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    pub fn set_connections(&self, n: usize) {
+        self.connections.store(n as u64, Ordering::Relaxed);
+        self.peak_connections.fetch_max(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn target(&self, target: SocketAddr, f: impl FnOnce(&mut TargetStats)) {
+        f(self.targets.lock().unwrap().entry(target).or_default())
+    }
+
+    pub fn target_counters(&self) -> Vec<(SocketAddr, TargetStats)> {
+        let mut v: Vec<_> = self.targets.lock().unwrap().iter().map(|(a, t)| (*a, *t)).collect();
+        v.sort_by_key(|(a, _)| *a);
+        v
+    }
+
+    pub fn counters(&self) -> Counters {
+        Counters {
+            packets_in: self.packets_in.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            packets_out: self.packets_out.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            peak_connections: self.peak_connections.load(Ordering::Relaxed),
+            middleware_drops: self.middleware_drops.load(Ordering::Relaxed),
+            corrupt_drops: self.corrupt_drops.load(Ordering::Relaxed),
+            auth_drops: self.auth_drops.load(Ordering::Relaxed),
+            replay_drops: self.replay_drops.load(Ordering::Relaxed),
+            decrypt_drops: self.decrypt_drops.load(Ordering::Relaxed),
+            filtered: self.filtered.load(Ordering::Relaxed),
+            quota_rejects: self.quota_rejects.load(Ordering::Relaxed),
+            handshake_rejects: self.handshake_rejects.load(Ordering::Relaxed),
+            unknown_packets: self.unknown_packets.load(Ordering::Relaxed),
+            truncated_drops: self.truncated_drops.load(Ordering::Relaxed),
+            loopback_drops: self.loopback_drops.load(Ordering::Relaxed),
+            handshakes: self.handshakes.load(Ordering::Relaxed),
+            rtt_micros: self.rtt_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// point-in-time copy of the shared counters, for exporting
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct Counters {
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+    pub connections: u64,
+    pub peak_connections: u64,
+    pub middleware_drops: u64,
+    pub corrupt_drops: u64,
+    pub auth_drops: u64,
+    pub replay_drops: u64,
+    pub decrypt_drops: u64,
+    pub filtered: u64,
+    pub quota_rejects: u64,
+    pub handshake_rejects: u64,
+    pub unknown_packets: u64,
+    pub truncated_drops: u64,
+    pub loopback_drops: u64,
+    pub handshakes: u64,
+    pub rtt_micros: u64,
+}
+
+// Why a packet or handshake from the tunnel was dropped
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Rejection {
+    Malformed,
+    Corrupt,
+    Auth,
+    Replay,
+    Decrypt,
+    Filtered,
+    Quota,
+    Handshake,
+    UnknownPacket,
+    // filled the whole buffer with --strict-mtu, in either direction
+    Truncated,
+    // came from the tunnel socket itself
+    Loopback,
+}
+
+impl From<&middleware::Error> for Rejection {
+    fn from(e: &middleware::Error) -> Self {
+        match e {
+            middleware::Error::Corrupt => Rejection::Corrupt,
+            middleware::Error::Auth => Rejection::Auth,
+            middleware::Error::Replay => Rejection::Replay,
+            middleware::Error::Decrypt => Rejection::Decrypt,
+            middleware::Error::Overflow(_) | middleware::Error::Other(_) => Rejection::Malformed,
+        }
+    }
+}
+
+impl From<&common::Error> for Rejection {
+    fn from(e: &common::Error) -> Self {
+        match e {
+            common::Error::AuthFailed | common::Error::AckAuthFailed => Rejection::Auth,
+            _ => Rejection::Handshake,
+        }
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            Rejection::Malformed => "malformed",
+            Rejection::Corrupt => "checksum mismatch",
+            Rejection::Auth => "authentication failed",
+            Rejection::Replay => "replayed",
+            Rejection::Decrypt => "decryption failed",
+            Rejection::Filtered => "filtered",
+            Rejection::Quota => "connection limit reached",
+            Rejection::Handshake => "handshake mismatch",
+            Rejection::UnknownPacket => "unknown packet type",
+            Rejection::Truncated => "truncated",
+            Rejection::Loopback => "sent by this end itself",
+        };
+        f.write_str(s)
+    }
+}
+
+// Totals per backend target, keyed by the resolved address. Same direction
+// naming as ConnStats, so "in" is what was forwarded to the target.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct TargetStats {
+    pub connections: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub errors: u64,
+}
+
+// "in" is traffic coming out of the tunnel, "out" is traffic going into it
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ConnStats {
+    pub packets_in: u64,
+    pub bytes_in: u64,
+    pub packets_out: u64,
+    pub bytes_out: u64,
+}
+
+impl ConnStats {
+    // in both directions, so the first packet of a connection is number 1
+    pub fn packets(&self) -> u64 {
+        self.packets_in + self.packets_out
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes_in + self.bytes_out
+    }
+
+    pub fn record(&mut self, dir: DataDirection, len: usize) {
+        match dir {
+            DataDirection::FromTunnel => {
+                self.packets_in += 1;
+                self.bytes_in += len as u64;
+            }
+            DataDirection::IntoTunnel => {
+                self.packets_out += 1;
+                self.bytes_out += len as u64;
+            }
+        }
+    }
+}
+
+// RFC 3550 style interarrival jitter. Without sender timestamps the transit
+// time difference is approximated by the change between consecutive
+// inter-arrival gaps.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Jitter {
+    last_arrival: Option<Instant>,
+    last_gap: Option<Duration>,
+    estimate: f64,
+}
+
+impl Jitter {
+    pub fn update(&mut self, now: Instant) {
+        if let Some(last) = self.last_arrival {
+            let gap = now.duration_since(last);
+            if let Some(prev) = self.last_gap {
+                let d = (gap.as_secs_f64() - prev.as_secs_f64()).abs();
+                self.estimate += (d - self.estimate) / 16.0;
+            }
+            self.last_gap = Some(gap);
+        }
+        self.last_arrival = Some(now);
+    }
+
+    pub fn millis(&self) -> f64 {
+        self.estimate * 1000.0
+    }
+}
+
+// Bytes per second over the last second, in both directions. Sliding window
+// approximated from the current and the previous one-second bucket.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Rate {
+    first: Option<Instant>,
+    bucket_start: Option<Instant>,
+    current: u64,
+    previous: u64,
+}
+
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+impl Rate {
+    pub fn update(&mut self, now: Instant, len: usize) {
+        self.roll(now);
+        self.first.get_or_insert(now);
+        self.current += len as u64;
+    }
+
+    fn roll(&mut self, now: Instant) {
+        let start = *self.bucket_start.get_or_insert(now);
+        let elapsed = now.saturating_duration_since(start);
+        if elapsed >= 2 * RATE_WINDOW {
+            self.previous = 0;
+            self.current = 0;
+            self.bucket_start = Some(now);
+        } else if elapsed >= RATE_WINDOW {
+            self.previous = self.current;
+            self.current = 0;
+            self.bucket_start = Some(start + RATE_WINDOW);
+        }
+    }
+
+    // None until a full window has passed since the first packet
+    pub fn bytes_per_sec(&mut self, now: Instant) -> Option<f64> {
+        if now.saturating_duration_since(self.first?) < RATE_WINDOW {
+            return None;
+        }
+        self.roll(now);
+        let in_bucket = now.saturating_duration_since(self.bucket_start?).as_secs_f64() / RATE_WINDOW.as_secs_f64();
+        Some((self.previous as f64 * (1.0 - in_bucket).max(0.0) + self.current as f64) / RATE_WINDOW.as_secs_f64())
+    }
+}
+
+// like "1.2 MiB/s"
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    scaled(bytes_per_sec, &["B/s", "KiB/s", "MiB/s", "GiB/s"])
+}
+
+// like "42.7 MiB"
+pub fn format_bytes(bytes: u64) -> String {
+    scaled(bytes as f64, &["B", "KiB", "MiB", "GiB", "TiB"])
+}
+
+fn scaled(mut value: f64, units: &[&str]) -> String {
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, units[unit])
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+// owned summary of a cache entry, for listing connections
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub cid: u16,
+    pub peer: SocketAddr,
+    pub local: Option<SocketAddr>,
+    pub target: Option<SocketAddr>,
+    pub created: DateTime<Local>,
+    pub last_access: DateTime<Local>,
+    pub stats: ConnStats,
+    pub jitter: Option<f64>,
+}
+
+impl Display for ConnectionInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.cid, self.peer)
+    }
+}
\ No newline at end of file
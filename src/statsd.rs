@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::time;
+
+use crate::common::{default_listen_ip, IpMode};
+use crate::stats::{Counters, Stats, TargetStats};
+
+// Periodically pushes the shared counters to a StatsD server. Counters are
+// sent as the difference to the previous push, so the server can sum them up.
+pub async fn start<T: ToSocketAddrs>(addr: T, prefix: String, interval: Duration, stats: Arc<Stats>) -> io::Result<()> {
+    let addr = lookup_host(addr).await?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no address for statsd server"))?;
+    let mode = if addr.is_ipv4() { IpMode::V4Only } else { IpMode::V6Only };
+    let mut socket = UdpSocket::bind(default_listen_ip(mode)).await?;
+    socket.connect(addr).await?;
+    let prefix = if prefix.is_empty() { prefix } else { format!("{}.", prefix) };
+
+    tokio::spawn(async move {
+        let mut ticker = time::interval(interval);
+        let mut last = Counters::default();
+        let mut last_targets = HashMap::new();
+        loop {
+            ticker.tick().await;
+            let current = stats.counters();
+            let mut msg = format_metrics(&prefix, &current, &last);
+            for (target, t) in stats.target_counters() {
+                let prev = last_targets.insert(target, t).unwrap_or_default();
+                msg.push('\n');
+                msg.push_str(&format_target_metrics(&prefix, target, &t, &prev));
+            }
+            if let Err(e) = socket.send(msg.as_bytes()).await {
+                error!("failed to send metrics to statsd: {}", e);
+            }
+            last = current;
+        }
+    });
+
+    Ok(())
+}
+
+fn format_metrics(prefix: &str, current: &Counters, last: &Counters) -> String {
+    let counters = [
+        ("packets_in", current.packets_in - last.packets_in),
+        ("bytes_in", current.bytes_in - last.bytes_in),
+        ("packets_out", current.packets_out - last.packets_out),
+        ("bytes_out", current.bytes_out - last.bytes_out),
+        ("middleware_drops", current.middleware_drops - last.middleware_drops),
+        ("corrupt_drops", current.corrupt_drops - last.corrupt_drops),
+        ("auth_drops", current.auth_drops - last.auth_drops),
+        ("replay_drops", current.replay_drops - last.replay_drops),
+        ("decrypt_drops", current.decrypt_drops - last.decrypt_drops),
+        ("truncated_drops", current.truncated_drops - last.truncated_drops),
+        ("loopback_drops", current.loopback_drops - last.loopback_drops),
+        ("filtered", current.filtered - last.filtered),
+        ("quota_rejects", current.quota_rejects - last.quota_rejects),
+        ("handshake_rejects", current.handshake_rejects - last.handshake_rejects),
+        ("unknown_packets", current.unknown_packets - last.unknown_packets),
+        ("handshakes", current.handshakes - last.handshakes),
+    ];
+    let mut s = String::new();
+    for (name, value) in counters.iter() {
+        writeln!(s, "{}{}:{}|c", prefix, name, value).unwrap();
+    }
+    writeln!(s, "{}connections:{}|g", prefix, current.connections).unwrap();
+    writeln!(s, "{}peak_connections:{}|g", prefix, current.peak_connections).unwrap();
+    write!(s, "{}tunnel_rtt_us:{}|g", prefix, current.rtt_micros).unwrap();
+    s
+}
+
+fn format_target_metrics(prefix: &str, target: SocketAddr, current: &TargetStats, last: &TargetStats) -> String {
+    // '.' and ':' are separators in the StatsD protocol
+    let name = target.to_string().replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    let counters = [
+        ("connections", current.connections - last.connections),
+        ("bytes_in", current.bytes_in - last.bytes_in),
+        ("bytes_out", current.bytes_out - last.bytes_out),
+        ("errors", current.errors - last.errors),
+    ];
+    let mut s = String::new();
+    for (i, (metric, value)) in counters.iter().enumerate() {
+        if i > 0 {
+            s.push('\n');
+        }
+        write!(s, "{}target.{}.{}:{}|c", prefix, name, metric, value).unwrap();
+    }
+    s
+}
\ No newline at end of file
@@ -0,0 +1,109 @@
+use std::fmt::Arguments;
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+
+use chrono::{SecondsFormat, Utc};
+
+#[cfg(unix)]
+const LOCAL_SOCKET: &str = "/dev/log";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Facility(u8);
+
+impl FromStr for Facility {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = match s {
+            "kern" => 0,
+            "user" => 1,
+            "mail" => 2,
+            "daemon" => 3,
+            "auth" => 4,
+            "syslog" => 5,
+            "lpr" => 6,
+            "news" => 7,
+            "uucp" => 8,
+            "cron" => 9,
+            "authpriv" => 10,
+            "ftp" => 11,
+            "local0" => 16,
+            "local1" => 17,
+            "local2" => 18,
+            "local3" => 19,
+            "local4" => 20,
+            "local5" => 21,
+            "local6" => 22,
+            "local7" => 23,
+            _ => return Err(()),
+        };
+        Ok(Facility(code))
+    }
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Local(UnixDatagram),
+}
+
+// Sends RFC 5424 formatted messages, either to the local syslog socket or to
+// a remote server over UDP.
+pub struct Syslog {
+    transport: Transport,
+    facility: Facility,
+    app_name: String,
+}
+
+impl Syslog {
+    // `target` is either "local" or the address of a remote syslog server.
+    pub fn connect(target: &str, facility: Facility, app_name: &str) -> io::Result<Self> {
+        let transport = match target {
+            #[cfg(unix)]
+            "local" => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(LOCAL_SOCKET)?;
+                Transport::Local(socket)
+            }
+            #[cfg(not(unix))]
+            "local" => return Err(io::Error::new(io::ErrorKind::Other, "no local syslog on this platform")),
+            _ => {
+                let addr = target.to_socket_addrs()?.next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address"))?;
+                let bind: std::net::SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(addr)?;
+                Transport::Udp(socket)
+            }
+        };
+        Ok(Syslog { transport, facility, app_name: app_name.to_string() })
+    }
+
+    pub fn send(&self, severity: Severity, msg: Arguments) -> io::Result<()> {
+        // no hostname lookup, the nil value lets the server fill it in
+        let line = format!(
+            "<{}>1 {} - {} {} - - {}",
+            self.facility.0 * 8 + severity as u8,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.app_name,
+            std::process::id(),
+            msg,
+        );
+        match &self.transport {
+            Transport::Udp(s) => s.send(line.as_bytes()),
+            #[cfg(unix)]
+            Transport::Local(s) => s.send(line.as_bytes()),
+        }.map(|_| ())
+    }
+}
\ No newline at end of file
@@ -0,0 +1,445 @@
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+
+use udptun::{ClientParams, HashKey, IdAlloc, IpMode, ServerParams, TargetSelect};
+use udptun::control::ControlAddr;
+use udptun::event::TunnelEvent;
+use udptun::middleware::{Authenticate, Compress, Middleware};
+use udptun::proto::*;
+use udptun::sockopt::SocketOptions;
+use udptun::sourcefmt::SourceAlloc;
+use udptun::stats::Stats;
+
+// One end of the tunnel, running on a thread of its own until dropped.
+struct End {
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl End {
+    // `run` gets a future to stop at, the params are built on the thread so
+    // they don't need to be Send
+    fn spawn<F>(run: impl FnOnce(Stop) -> F + Send + 'static) -> Self
+        where F: Future<Output=Result<(), udptun::Error>> {
+        let (stop, stopped) = oneshot::channel();
+        let thread = thread::spawn(move || {
+            let mut rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(run(Stop(stopped))).unwrap();
+        });
+        End { stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        let _ = self.stop.take().unwrap().send(());
+        // don't hide the assertion that failed behind a second panic
+        if !thread::panicking() {
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+}
+
+struct Stop(oneshot::Receiver<()>);
+
+impl Stop {
+    async fn wait(self) {
+        let _ = self.0.await;
+    }
+}
+
+fn socket() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    socket
+}
+
+fn server_params(target: SocketAddr) -> ServerParams<'static, SocketAddr, SocketAddr, SocketAddr> {
+    ServerParams {
+        targets: vec![target],
+        remote: None,
+        bufsize: 2048,
+        timeout: chrono::Duration::seconds(60),
+        tunnel_addr: None,
+        source_format: None,
+        source_alloc: SourceAlloc::Random,
+        hash_key: HashKey::Connection,
+        target_select: TargetSelect::Hash,
+        mode: IpMode::V4Only,
+        format: None,
+        print_data_buffer: false,
+        middleware: None,
+        filter: None,
+        stats: Arc::new(Stats::default()),
+        events: None,
+        control: None,
+        drain_timeout: Duration::from_secs(1),
+        cleanup_interval: Duration::from_secs(60),
+        reload: None,
+        jitter: false,
+        max_conns_per_source: None,
+        verbosity: 0,
+        target_batch: None,
+        log_connections: false,
+        log_expiry: false,
+        socket_options: SocketOptions::default(),
+        listen_interface: None,
+        grow_buffer: None,
+        strict_mtu: false,
+        bind_retries: 0,
+        tunnel_weight: 1,
+        batch: 1,
+        rng_seed: None,
+        psk: None,
+        keepalive: None,
+        connect_retries: 0,
+        connect_timeout: Duration::from_secs(1),
+        coalesce: false,
+        task_per_connection: false,
+    }
+}
+
+fn client_params(entry: SocketAddr) -> ClientParams<'static, SocketAddr, SocketAddr, SocketAddr> {
+    ClientParams {
+        entry: vec![entry],
+        remote: None,
+        timeout: chrono::Duration::seconds(60),
+        bufsize: 2048,
+        id_alloc: IdAlloc::Lowest,
+        tunnel_addr: None,
+        mode: IpMode::V4Only,
+        format: None,
+        print_data_buffer: false,
+        middleware: None,
+        filter: None,
+        stats: Arc::new(Stats::default()),
+        events: None,
+        control: None,
+        drain_timeout: Duration::from_secs(1),
+        max_connections: None,
+        cleanup_interval: Duration::from_secs(60),
+        reload: None,
+        jitter: false,
+        verbosity: 0,
+        log_connections: false,
+        log_expiry: false,
+        socket_options: SocketOptions::default(),
+        listen_interface: None,
+        grow_buffer: None,
+        strict_mtu: false,
+        tunnel_weight: 1,
+        batch: 1,
+        rng_seed: None,
+        state_file: None,
+        psk: None,
+        keepalive: None,
+        connect_retries: 3,
+        connect_timeout: Duration::from_millis(200),
+        coalesce: false,
+        fallback_remotes: Vec::new(),
+        failover_timeout: None,
+    }
+}
+
+// an address nothing listens on right now
+fn free_addr() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+// A server listening for the tunnel and a client connecting to it, with
+// their params changed by `server` and `client`. Returns once they are
+// connected, with the client's entry address, with the target end at `target`.
+fn tunnel(target: SocketAddr, server: impl FnOnce(&mut ServerParams<'static, SocketAddr, SocketAddr, SocketAddr>) + Send + 'static, client: impl FnOnce(&mut ClientParams<'static, SocketAddr, SocketAddr, SocketAddr>) + Send + 'static) -> (End, End, SocketAddr) {
+    let (tunnel_addr, entry) = (free_addr(), free_addr());
+    let (events, mut connected) = mpsc::channel(16);
+    let server = End::spawn(move |stop| {
+        let mut params = server_params(target);
+        params.tunnel_addr = Some(tunnel_addr);
+        server(&mut params);
+        udptun::start_server_until(params, stop.wait())
+    });
+    let client = End::spawn(move |stop| {
+        let mut params = client_params(entry);
+        params.remote = Some(tunnel_addr);
+        params.events = Some(events);
+        client(&mut params);
+        udptun::start_client_until(params, stop.wait())
+    });
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let event = rt.block_on(async { tokio::time::timeout(Duration::from_secs(2), connected.recv()).await }).unwrap();
+    assert!(matches!(event, Some(TunnelEvent::HandshakeCompleted { .. })));
+    (server, client, entry)
+}
+
+// Answers the connect of a server that was told to connect to `peer`, so
+// `peer` plays the client end of the tunnel, agreeing to the features the
+// server asks for.
+fn accept_server(peer: &UdpSocket) {
+    let mut buf = [0; 64];
+    let (_, server_addr) = peer.recv_from(&mut buf).unwrap();
+    assert_eq!(buf[0], PACKET_CONNECT);
+    peer.send_to(&[PACKET_CONN_ACK, TYPE_CLIENT, PROTO_VERSION, buf[1]], server_addr).unwrap();
+    peer.connect(server_addr).unwrap();
+}
+
+// Sends `command` to the control socket at `addr` and returns the reply.
+fn control(addr: SocketAddr, command: &str) -> String {
+    // it only listens once the tunnel is up
+    let mut stream = (0..20).find_map(|_| TcpStream::connect(addr).map_err(|_| thread::sleep(Duration::from_millis(50))).ok()).unwrap();
+    writeln!(stream, "{}", command).unwrap();
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply).unwrap();
+    reply
+}
+
+// Waits up to 2s for `cond`.
+fn eventually(cond: impl Fn() -> bool) -> bool {
+    (0..40).any(|_| cond() || { thread::sleep(Duration::from_millis(50)); false })
+}
+
+const KEY: &[u8] = b"secret";
+
+// A packet of type `kind` with the payload signed with `key`, like --hmac does
+// at the client end.
+fn signed(key: &[u8], kind: u8, cid: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = data_packet(cid, payload);
+    packet[0] = kind;
+    packet.resize(packet.len() + 64, 0);
+    let len = Authenticate::new(key, false).transform_outbound(cid, &mut packet[DATA_HEADER_LEN..], payload.len()).unwrap();
+    packet.truncate(DATA_HEADER_LEN + len);
+    packet
+}
+
+fn data_packet(cid: u16, payload: &[u8]) -> Vec<u8> {
+    let [hi, lo] = cid.to_be_bytes();
+    let mut packet = vec![PACKET_DATA, hi, lo];
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn server_counters(task_per_connection: bool) {
+    let (peer, target) = (socket(), socket());
+    let (peer_addr, target_addr) = (peer.local_addr().unwrap(), target.local_addr().unwrap());
+    let stats = Arc::new(Stats::default());
+    let server_stats = stats.clone();
+    let _server = End::spawn(move |stop| {
+        let mut params = server_params(target_addr);
+        params.remote = Some(peer_addr);
+        params.stats = server_stats;
+        params.task_per_connection = task_per_connection;
+        udptun::start_server_until(params, stop.wait())
+    });
+    accept_server(&peer);
+
+    let mut buf = [0; 2048];
+    for &len in &[10, 20, 30] {
+        peer.send(&data_packet(0, &vec![1; len])).unwrap();
+    }
+    let mut conn_addr = None;
+    for &len in &[10, 20, 30] {
+        let (size, addr) = target.recv_from(&mut buf).unwrap();
+        assert_eq!(size, len);
+        conn_addr = Some(addr);
+    }
+    for &len in &[5, 7] {
+        target.send_to(&vec![2; len], conn_addr.unwrap()).unwrap();
+    }
+    for &len in &[5, 7] {
+        let size = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &data_packet(0, &vec![2; len])[..]);
+    }
+
+    let c = stats.counters();
+    assert_eq!((c.packets_in, c.bytes_in), (3, 60));
+    assert_eq!((c.packets_out, c.bytes_out), (2, 12));
+}
+
+#[test]
+fn counters() {
+    server_counters(false);
+}
+
+#[test]
+fn counters_with_tasks() {
+    server_counters(true);
+}
+
+// The server runs with the peer as remote and has one connection open with
+// the returned socket of the server on the target end.
+fn open_connection(peer: &UdpSocket, target: &UdpSocket) -> SocketAddr {
+    accept_server(peer);
+    peer.send(&data_packet(0, b"hello")).unwrap();
+    let mut buf = [0; 64];
+    let (size, conn_addr) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..size], b"hello");
+    conn_addr
+}
+
+#[test]
+fn target_gone_closes_connection() {
+    let (peer, target) = (socket(), socket());
+    let (peer_addr, target_addr) = (peer.local_addr().unwrap(), target.local_addr().unwrap());
+    let stats = Arc::new(Stats::default());
+    let server_stats = stats.clone();
+    let _server = End::spawn(move |stop| {
+        let mut params = server_params(target_addr);
+        params.remote = Some(peer_addr);
+        params.stats = server_stats;
+        udptun::start_server_until(params, stop.wait())
+    });
+    open_connection(&peer, &target);
+    drop(target);
+
+    // the port unreachable for this resets the connection's socket
+    peer.send(&data_packet(0, b"anyone?")).unwrap();
+    let mut buf = [0; 64];
+    let size = peer.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..size], &[PACKET_CLOSE, 0, 0]);
+    assert_eq!(stats.counters().connections, 0);
+    // and it's not reported again and again
+    peer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert!(peer.recv(&mut buf).is_err());
+}
+
+#[test]
+fn remote_gone_connects_again() {
+    let (peer, target) = (socket(), socket());
+    let (peer_addr, target_addr) = (peer.local_addr().unwrap(), target.local_addr().unwrap());
+    let _server = End::spawn(move |stop| {
+        let mut params = server_params(target_addr);
+        params.remote = Some(peer_addr);
+        params.connect_retries = 3;
+        params.connect_timeout = Duration::from_millis(200);
+        udptun::start_server_until(params, stop.wait())
+    });
+    let conn_addr = open_connection(&peer, &target);
+    drop(peer);
+
+    // the port unreachable for this resets the tunnel socket, the first
+    // connect after it is refused as well
+    target.send_to(b"anyone?", conn_addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let peer = UdpSocket::bind(peer_addr).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    accept_server(&peer);
+    // the server swaps in the new socket only after the answer arrived
+    thread::sleep(Duration::from_millis(100));
+
+    // the connection survived it
+    target.send_to(b"back", conn_addr).unwrap();
+    let mut buf = [0; 64];
+    let size = peer.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..size], &data_packet(0, b"back")[..]);
+}
+
+#[test]
+fn compressed_round_trip() {
+    let (app, target) = (socket(), socket());
+    let compress = |middleware: &mut Option<Box<dyn Middleware>>| *middleware = Some(Box::new(Compress::default()));
+    let (_server, _client, entry) = tunnel(target.local_addr().unwrap(), move |p| compress(&mut p.middleware), move |p| compress(&mut p.middleware));
+    let compressible = b"hello hello hello hello hello hello hello hello".repeat(10);
+    let incompressible: Vec<u8> = (0..500).map(|_| rand::random()).collect();
+    let mut buf = [0; 2048];
+    for payload in &[&compressible, &incompressible] {
+        app.send_to(payload, entry).unwrap();
+        let (size, conn_addr) = target.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &payload[..]);
+        target.send_to(payload, conn_addr).unwrap();
+        let size = app.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &payload[..]);
+    }
+}
+
+
+#[test]
+fn kill_closes_other_end() {
+    let (app, target) = (socket(), socket());
+    let control_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let stats = Arc::new(Stats::default());
+    let server_stats = stats.clone();
+    // the close is signed like the data
+    let (_server, _client, entry) = tunnel(target.local_addr().unwrap(), move |p| {
+        p.stats = server_stats;
+        p.middleware = Some(Box::new(Authenticate::new(KEY, true)));
+    }, move |p| {
+        p.control = Some(ControlAddr::Tcp(control_addr));
+        p.middleware = Some(Box::new(Authenticate::new(KEY, false)));
+    });
+    let mut buf = [0; 64];
+    app.send_to(b"first", entry).unwrap();
+    let (_, first) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(stats.counters().connections, 1);
+
+    assert!(control(control_addr, "kill 0").starts_with("killed 0"));
+    assert!(eventually(|| stats.counters().connections == 0));
+    // the ID is handed out again, which is a new connection at the target end
+    let app = socket();
+    app.send_to(b"second", entry).unwrap();
+    let (size, second) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..size], b"second");
+    assert_ne!(first, second);
+}
+
+
+#[test]
+fn forged_close_dropped() {
+    let (peer, target) = (socket(), socket());
+    let (peer_addr, target_addr) = (peer.local_addr().unwrap(), target.local_addr().unwrap());
+    let stats = Arc::new(Stats::default());
+    let server_stats = stats.clone();
+    let _server = End::spawn(move |stop| {
+        let mut params = server_params(target_addr);
+        params.remote = Some(peer_addr);
+        params.stats = server_stats;
+        params.middleware = Some(Box::new(Authenticate::new(KEY, true)));
+        udptun::start_server_until(params, stop.wait())
+    });
+    accept_server(&peer);
+    peer.send(&signed(KEY, PACKET_DATA, 0, b"hello")).unwrap();
+    let mut buf = [0; 64];
+    let size = target.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..size], b"hello");
+
+    peer.send(&[PACKET_CLOSE, 0, 0]).unwrap();
+    peer.send(&signed(b"not the key", PACKET_CLOSE, 0, b"")).unwrap();
+    assert!(eventually(|| stats.counters().auth_drops == 1));
+    let c = stats.counters();
+    assert_eq!((c.middleware_drops, c.connections), (1, 1));
+    peer.send(&signed(KEY, PACKET_CLOSE, 0, b"")).unwrap();
+    assert!(eventually(|| stats.counters().connections == 0));
+}
+
+
+#[test]
+fn eviction_keeps_count() {
+    let (apps, target) = ([socket(), socket(), socket()], socket());
+    let stats = Arc::new(Stats::default());
+    let client_stats = stats.clone();
+    let (_server, _client, entry) = tunnel(target.local_addr().unwrap(), |_| {}, move |p| {
+        p.stats = client_stats;
+        p.max_connections = Some(2);
+    });
+    let mut buf = [0; 64];
+    for app in &apps {
+        app.send_to(b"hello", entry).unwrap();
+        target.recv_from(&mut buf).unwrap();
+    }
+    let c = stats.counters();
+    assert_eq!((c.connections, c.peak_connections), (2, 2));
+}
+
+
+#[test]
+fn no_targets() {
+    let mut params = server_params(free_addr());
+    params.targets.clear();
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let r = rt.block_on(udptun::start_server_until(params, async {}));
+    assert!(matches!(r, Err(udptun::Error::NoTargets)));
+}
\ No newline at end of file